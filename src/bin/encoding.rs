@@ -0,0 +1,95 @@
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::process;
+
+// Checks the messages in a recorded trace (see RAFT_TRACE in transport.rs)
+// against the MessagePack encoding network.rs can send them in: each must
+// decode back to exactly the JSON it was encoded from. Prints, per message
+// type, how big the messages are in each encoding.
+//
+//   encoding <trace.jsonl>...
+//
+// Exits 1 if any message does not survive the round trip.
+
+#[derive(Default)]
+struct Sizes {
+    messages: u64,
+    json: u64,
+    msgpack: u64,
+    mismatches: u64,
+}
+
+fn usage() -> ! {
+    eprintln!("usage: encoding <trace.jsonl>...");
+    process::exit(2);
+}
+
+fn main() {
+    let paths: Vec<String> = env::args().skip(1).collect();
+    if paths.is_empty() {
+        usage();
+    }
+    let mut sizes: BTreeMap<String, Sizes> = BTreeMap::new();
+    for path in &paths {
+        let trace = fs::read_to_string(path).unwrap_or_else(|error| {
+            eprintln!("cannot read {}: {}", path, error);
+            process::exit(2);
+        });
+        // Every message sent between traced nodes is in the trace twice;
+        // counting only the sending side covers each once.
+        for line in trace.lines().filter(|l| !l.trim().is_empty()) {
+            let entry: Value = serde_json::from_str(line).unwrap();
+            if entry["direction"] != "send" {
+                continue;
+            }
+            let message = &entry["message"];
+            let json = serde_json::to_string(message).unwrap();
+            let binary = rmp_serde::to_vec_named(message).unwrap();
+            let r#type = message["body"]["type"].as_str().unwrap_or("?");
+            let s = sizes.entry(r#type.to_string()).or_default();
+            s.messages += 1;
+            s.json += json.len() as u64;
+            s.msgpack += binary.len() as u64;
+            match rmp_serde::from_slice::<Value>(&binary) {
+                Ok(decoded) if decoded == *message => {}
+                decoded => {
+                    s.mismatches += 1;
+                    println!("{} decodes as {:?}", json, decoded);
+                }
+            }
+        }
+    }
+
+    println!(
+        "{:<16} {:>9} {:>12} {:>12} {:>7} {:>10}",
+        "type", "messages", "json bytes", "msgpack", "ratio", "mismatches"
+    );
+    let mut total = Sizes::default();
+    for (r#type, s) in &sizes {
+        println!(
+            "{:<16} {:>9} {:>12} {:>12} {:>7.2} {:>10}",
+            r#type,
+            s.messages,
+            s.json,
+            s.msgpack,
+            s.msgpack as f64 / s.json.max(1) as f64,
+            s.mismatches
+        );
+        total.messages += s.messages;
+        total.json += s.json;
+        total.msgpack += s.msgpack;
+        total.mismatches += s.mismatches;
+    }
+    println!(
+        "{:<16} {:>9} {:>12} {:>12} {:>7.2} {:>10}",
+        "total",
+        total.messages,
+        total.json,
+        total.msgpack,
+        total.msgpack as f64 / total.json.max(1) as f64,
+        total.mismatches
+    );
+    process::exit((total.mismatches > 0) as i32);
+}
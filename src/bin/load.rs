@@ -0,0 +1,687 @@
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::net::TcpStream;
+use std::process::{self, Child, ChildStdin, Command, Stdio};
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Puts a cluster under load from concurrent clients speaking the same client
+// protocol as Maelstrom's, then prints throughput and latency percentiles per
+// operation.
+//
+//   load [<flag> <value>]... -- <command> [args...]
+//   load [<flag> <value>]... --peers <peers.json>
+//
+// e.g. `load --workload counter -- target/debug/raft pn-counter`. Given a
+// command, it starts --nodes copies of it and stands in for Maelstrom: it
+// routes messages between them over their stdin and stdout and answers for
+// the seq-kv, lin-kv and lww-kv services itself. Given a peer map (see
+// network.rs), it talks to a cluster already running on the network, each
+// client over its own TCP connection to one node.
+//
+//   --workload      list-append (default): `txn` of reads and appends;
+//                   rw-register: `txn` of reads, writes and `cas`;
+//                   broadcast: `broadcast` and `read`;
+//                   counter: `add` and `read`;
+//                   register: `write` and `read`
+//   --nodes         nodes to start, 3
+//   --concurrency   clients, each with one request in flight at a time, 5
+//   --rate          requests per second across all clients, 0 for as fast as
+//                   they get replies, 100
+//   --time-limit    seconds to keep sending, 10
+//   --keys          distinct keys, 100
+//   --distribution  how keys are picked: uniform (default) or zipfian
+//   --timeout-ms    how long a client waits for a reply, 5000
+//   --history       file to record the history in, one JSON event per line
+//
+// The history is in the shape Jepsen checkers read: an `invoke` event when a
+// client sends a request, then `ok`, `fail` if the node answered with an error
+// that means the operation did not happen, or `info` if it may have happened
+// and the client cannot tell, such as when no reply came in time. A client
+// that sees `info` carries on as a new process, since its old one may still
+// be in flight.
+
+const FLAGS: &[&str] = &[
+    "workload",
+    "nodes",
+    "concurrency",
+    "rate",
+    "time-limit",
+    "keys",
+    "distribution",
+    "timeout-ms",
+    "history",
+];
+const SERVICES: &[&str] = &["seq-kv", "lin-kv", "lww-kv"];
+// Error codes after which the operation may still have taken effect: timeout
+// and crash. Every other code means it definitely did not.
+const INDEFINITE: &[i64] = &[0, 13];
+const KEY_DOES_NOT_EXIST: i64 = 20;
+const PRECONDITION_FAILED: i64 = 22;
+// Zipf's exponent: key k of n is picked with odds proportional to 1/k^s.
+const ZIPF_EXPONENT: f64 = 1.0;
+// Values written to rw-registers are drawn from this range, so `cas` has a
+// fair chance of finding the value it expects.
+const REGISTER_VALUES: u64 = 5;
+
+fn usage() -> ! {
+    eprintln!("usage: load [<flag> <value>]... -- <command> [args...]");
+    eprintln!("       load [<flag> <value>]... --peers <peers.json>");
+    eprintln!("flags: --{}", FLAGS.join(" --"));
+    process::exit(2);
+}
+
+fn fail(message: String) -> ! {
+    eprintln!("load: {}", message);
+    usage();
+}
+
+struct Options {
+    values: HashMap<String, String>,
+}
+
+impl Options {
+    fn get<T: std::str::FromStr>(&self, flag: &str, default: T) -> T {
+        match self.values.get(flag) {
+            Some(value) => value
+                .parse()
+                .unwrap_or_else(|_| fail(format!("bad value `{}` for --{}", value, flag))),
+            None => default,
+        }
+    }
+}
+
+// xorshift64, as in rga.rs's convergence test; good enough to pick keys and ops.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        Rng(seed.wrapping_mul(0x9E3779B97F4A7C15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+
+    fn unit(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+enum Keys {
+    Uniform(u64),
+    // Cumulative odds of each key, ending at 1.
+    Zipfian(Vec<f64>),
+}
+
+impl Keys {
+    fn new(distribution: &str, n: u64) -> Keys {
+        match distribution {
+            "uniform" => Keys::Uniform(n),
+            "zipfian" => {
+                let weights: Vec<f64> = (1..=n)
+                    .map(|k| 1.0 / (k as f64).powf(ZIPF_EXPONENT))
+                    .collect();
+                let total: f64 = weights.iter().sum();
+                let mut sum = 0.0;
+                let cdf = weights
+                    .iter()
+                    .map(|w| {
+                        sum += w / total;
+                        sum
+                    })
+                    .collect();
+                Keys::Zipfian(cdf)
+            }
+            _ => fail(format!("unknown distribution `{}`", distribution)),
+        }
+    }
+
+    fn pick(&self, rng: &mut Rng) -> i64 {
+        match self {
+            Keys::Uniform(n) => rng.below(*n) as i64,
+            Keys::Zipfian(cdf) => {
+                let u = rng.unit();
+                cdf.partition_point(|c| *c < u).min(cdf.len() - 1) as i64
+            }
+        }
+    }
+}
+
+// Appended and broadcast values are unique across the run, so a checker can
+// tell every write apart.
+static NEXT_VALUE: AtomicI64 = AtomicI64::new(1);
+
+fn unique() -> i64 {
+    NEXT_VALUE.fetch_add(1, Ordering::SeqCst)
+}
+
+// The next request of the workload: its operation name, its body without a
+// msg_id, and the value the history records it was invoked with.
+fn operation(workload: &str, keys: &Keys, rng: &mut Rng) -> (&'static str, Value, Value) {
+    match workload {
+        "list-append" | "rw-register" => {
+            let txn: Vec<Value> = (0..1 + rng.below(4))
+                .map(|_| {
+                    let key = keys.pick(rng);
+                    match (workload, rng.below(3)) {
+                        (_, 0) => json!(["r", key, null]),
+                        ("list-append", _) => json!(["append", key, unique()]),
+                        (_, 1) => json!(["w", key, rng.below(REGISTER_VALUES)]),
+                        _ => json!([
+                            "cas",
+                            key,
+                            [rng.below(REGISTER_VALUES), rng.below(REGISTER_VALUES)]
+                        ]),
+                    }
+                })
+                .collect();
+            ("txn", json!({"type": "txn", "txn": txn}), json!(txn))
+        }
+        "broadcast" if rng.below(3) > 0 => {
+            let message = unique();
+            let body = json!({"type": "broadcast", "message": message});
+            ("broadcast", body, json!(message))
+        }
+        "counter" if rng.below(2) > 0 => {
+            let delta = 1 + rng.below(5);
+            ("add", json!({"type": "add", "delta": delta}), json!(delta))
+        }
+        "register" if rng.below(2) > 0 => {
+            let value = unique();
+            let body = json!({"type": "write", "value": value});
+            ("write", body, json!(value))
+        }
+        "broadcast" | "counter" | "register" => ("read", json!({"type": "read"}), Value::Null),
+        _ => fail(format!("unknown workload `{}`", workload)),
+    }
+}
+
+// What an `ok` reply says the operation returned.
+fn result(f: &str, invoked: &Value, reply: &Value) -> Value {
+    match f {
+        "txn" => reply["txn"].clone(),
+        "read" if reply.get("messages").is_some() => reply["messages"].clone(),
+        "read" => reply["value"].clone(),
+        _ => invoked.clone(),
+    }
+}
+
+// Requests awaiting a reply, by client and msg_id.
+type Pending = Arc<Mutex<HashMap<(String, i64), mpsc::Sender<Value>>>>;
+
+// Hands a reply to the client waiting on it. Replies nobody waits for any
+// more, because the client gave up on them, are dropped.
+fn deliver(pending: &Pending, message: Value) {
+    let dest = message["dest"].as_str().unwrap_or_default().to_string();
+    if let Some(reply_to) = message["body"]["in_reply_to"].as_i64() {
+        if let Some(waiting) = pending.lock().unwrap().remove(&(dest, reply_to)) {
+            let _ = waiting.send(message);
+        }
+    }
+}
+
+// A node's stdin, closed at the end of the run so it shuts down.
+type Stdin = Arc<Mutex<Option<ChildStdin>>>;
+
+// How a client's requests reach its node.
+enum Link {
+    Stdin(Stdin),
+    Tcp(TcpStream),
+}
+
+impl Link {
+    fn send(&mut self, message: &Value) {
+        let line = format!("{}\n", message);
+        let _ = match self {
+            Link::Stdin(stdin) => match stdin.lock().unwrap().as_mut() {
+                Some(stdin) => stdin.write_all(line.as_bytes()),
+                None => Ok(()),
+            },
+            Link::Tcp(stream) => stream.write_all(line.as_bytes()),
+        };
+    }
+}
+
+struct Client {
+    id: String,
+    node: String,
+    link: Link,
+    next_msg_id: i64,
+    pending: Pending,
+    timeout: Duration,
+}
+
+impl Client {
+    // Sends a request and waits for its reply, or None if it does not come in
+    // time.
+    fn call(&mut self, mut body: Value) -> Option<Value> {
+        self.next_msg_id += 1;
+        let key = (self.id.clone(), self.next_msg_id);
+        body["msg_id"] = json!(self.next_msg_id);
+        let (tx, rx) = mpsc::channel();
+        self.pending.lock().unwrap().insert(key.clone(), tx);
+        self.link
+            .send(&json!({"src": self.id, "dest": self.node, "body": body}));
+        let reply = rx.recv_timeout(self.timeout).ok();
+        self.pending.lock().unwrap().remove(&key);
+        reply.map(|message| message["body"].clone())
+    }
+}
+
+// The cluster the clients talk to, and how to reach each of its nodes.
+enum Cluster {
+    // Nodes we started, by id, with their stdin.
+    Local(Vec<(String, Stdin)>, Vec<Child>),
+    // Nodes on the network, by id, with their address.
+    Network(Vec<(String, String)>),
+}
+
+impl Cluster {
+    fn nodes(&self) -> Vec<String> {
+        match self {
+            Cluster::Local(nodes, _) => nodes.iter().map(|(id, _)| id.clone()).collect(),
+            Cluster::Network(nodes) => nodes.iter().map(|(id, _)| id.clone()).collect(),
+        }
+    }
+
+    fn client(&self, id: String, node: usize, pending: &Pending, timeout: Duration) -> Client {
+        let (node, link) = match self {
+            Cluster::Local(nodes, _) => (nodes[node].0.clone(), Link::Stdin(nodes[node].1.clone())),
+            Cluster::Network(nodes) => {
+                let (node, addr) = &nodes[node];
+                let stream = TcpStream::connect(addr).unwrap_or_else(|error| {
+                    fail(format!("cannot reach {} at {}: {}", node, addr, error))
+                });
+                let reader = stream.try_clone().unwrap();
+                let pending = pending.clone();
+                thread::spawn(move || {
+                    for line in BufReader::new(reader).lines() {
+                        match line.map(|line| serde_json::from_str(&line)) {
+                            Ok(Ok(message)) => deliver(&pending, message),
+                            Ok(Err(_)) => continue,
+                            Err(_) => break,
+                        }
+                    }
+                });
+                (node.clone(), Link::Tcp(stream))
+            }
+        };
+        Client {
+            id,
+            node,
+            link,
+            next_msg_id: 0,
+            pending: pending.clone(),
+            timeout,
+        }
+    }
+
+    // Closes every node's stdin and waits for them to drain and exit.
+    fn stop(self) {
+        if let Cluster::Local(nodes, children) = self {
+            for (_, stdin) in &nodes {
+                stdin.lock().unwrap().take();
+            }
+            for mut child in children {
+                let _ = child.wait();
+            }
+        }
+    }
+}
+
+// Maelstrom's key-value services, shared by every node as they are there.
+fn service(store: &Mutex<HashMap<(String, String), Value>>, message: &Value) -> Value {
+    let body = &message["body"];
+    let key = (message["dest"].to_string(), body["key"].to_string());
+    let mut store = store.lock().unwrap();
+    let mut reply = json!({"in_reply_to": body["msg_id"]});
+    let error = |code: i64, text: &str| json!({"type": "error", "code": code, "text": text});
+    let outcome = match (body["type"].as_str().unwrap_or_default(), store.get(&key)) {
+        ("read", Some(value)) => json!({"type": "read_ok", "value": value}),
+        ("read", None) => error(KEY_DOES_NOT_EXIST, "key does not exist"),
+        ("write", _) => {
+            store.insert(key, body["value"].clone());
+            json!({"type": "write_ok"})
+        }
+        ("cas", Some(value)) if *value != body["from"] => {
+            error(PRECONDITION_FAILED, "current value does not match")
+        }
+        ("cas", None) if body["create_if_not_exists"] != true => {
+            error(KEY_DOES_NOT_EXIST, "key does not exist")
+        }
+        ("cas", _) => {
+            store.insert(key, body["to"].clone());
+            json!({"type": "cas_ok"})
+        }
+        _ => error(10, "unsupported request"),
+    };
+    for (field, value) in outcome.as_object().unwrap() {
+        reply[field] = value.clone();
+    }
+    json!({"src": message["dest"], "dest": message["src"], "body": reply})
+}
+
+// Starts the nodes and routes everything they send: to another node, to a
+// service, or to the client waiting on it.
+fn start(command: &[String], count: usize, pending: &Pending) -> Cluster {
+    let mut children = Vec::new();
+    let mut nodes = Vec::new();
+    let mut outputs = Vec::new();
+    for i in 0..count {
+        let mut child = Command::new(&command[0]);
+        child
+            .args(&command[1..])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit());
+        // The nodes share our stderr; keep it to what is worth reading.
+        if env::var_os("RAFT_LOG").is_none() {
+            child.env("RAFT_LOG", "warn");
+        }
+        let mut child = child
+            .spawn()
+            .unwrap_or_else(|error| fail(format!("cannot run {}: {}", command[0], error)));
+        nodes.push((format!("n{}", i), Arc::new(Mutex::new(child.stdin.take()))));
+        outputs.push(child.stdout.take().unwrap());
+        children.push(child);
+    }
+    let routes: Arc<HashMap<String, Stdin>> = Arc::new(nodes.iter().cloned().collect());
+    let store = Arc::new(Mutex::new(HashMap::new()));
+    for output in outputs {
+        let (routes, store, pending) = (routes.clone(), store.clone(), pending.clone());
+        thread::spawn(move || {
+            for line in BufReader::new(output).lines() {
+                let message: Value = match line.map(|line| serde_json::from_str(&line)) {
+                    Ok(Ok(message)) => message,
+                    Ok(Err(_)) => continue,
+                    Err(_) => break,
+                };
+                let dest = message["dest"].as_str().unwrap_or_default();
+                let (message, dest) = match SERVICES.contains(&dest) {
+                    true => {
+                        let reply = service(&store, &message);
+                        let dest = reply["dest"].as_str().unwrap_or_default().to_string();
+                        (reply, dest)
+                    }
+                    false => (message.clone(), dest.to_string()),
+                };
+                match routes.get(&dest) {
+                    Some(stdin) => Link::Stdin(stdin.clone()).send(&message),
+                    None => deliver(&pending, message),
+                }
+            }
+        });
+    }
+    Cluster::Local(nodes, children)
+}
+
+fn connect(path: &str) -> Cluster {
+    let peers = fs::read_to_string(path)
+        .unwrap_or_else(|error| fail(format!("cannot read {}: {}", path, error)));
+    let peers: BTreeMap<String, String> = serde_json::from_str(&peers)
+        .unwrap_or_else(|error| fail(format!("bad peer map {}: {}", path, error)));
+    Cluster::Network(peers.into_iter().collect())
+}
+
+// Spaces requests evenly across all clients to hold the configured rate.
+struct Schedule {
+    next: Mutex<Instant>,
+    interval: Option<Duration>,
+}
+
+impl Schedule {
+    fn wait(&self) {
+        if let Some(interval) = self.interval {
+            let at = {
+                let mut next = self.next.lock().unwrap();
+                let at = (*next).max(Instant::now());
+                *next = at + interval;
+                at
+            };
+            thread::sleep(at.saturating_duration_since(Instant::now()));
+        }
+    }
+}
+
+#[derive(Default)]
+struct Stats {
+    ok: u64,
+    fail: u64,
+    info: u64,
+    // Of ok and fail completions, in ms.
+    latencies: Vec<f64>,
+}
+
+struct History {
+    start: Instant,
+    file: Option<Mutex<BufWriter<File>>>,
+}
+
+impl History {
+    fn record(&self, process: u64, r#type: &str, f: &str, value: &Value, node: &str) {
+        if let Some(file) = &self.file {
+            let event = json!({
+                "process": process,
+                "type": r#type,
+                "f": f,
+                "value": value,
+                "time": self.start.elapsed().as_nanos() as u64,
+                "node": node,
+            });
+            writeln!(file.lock().unwrap(), "{}", event).unwrap();
+        }
+    }
+}
+
+fn percentile(sorted: &[f64], q: f64) -> f64 {
+    match sorted.len() {
+        0 => 0.0,
+        n => sorted[((n as f64 * q).ceil() as usize).clamp(1, n) - 1],
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let mut values = HashMap::new();
+    let mut command = None;
+    let mut peers = None;
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--" => {
+                command = Some(args[i + 1..].to_vec());
+                break;
+            }
+            "--peers" => peers = args.get(i + 1).cloned(),
+            arg => match arg.strip_prefix("--").filter(|flag| FLAGS.contains(flag)) {
+                Some(flag) => {
+                    let value = args.get(i + 1).unwrap_or_else(|| usage());
+                    values.insert(flag.to_string(), value.clone());
+                }
+                None => fail(format!("unknown argument `{}`", arg)),
+            },
+        }
+        i += 2;
+    }
+    let options = Options { values };
+    let workload: String = options.get("workload", "list-append".to_string());
+    let concurrency: usize = options.get("concurrency", 5).max(1);
+    let rate: f64 = options.get("rate", 100.0);
+    let time_limit = Duration::from_secs_f64(options.get("time-limit", 10.0));
+    let timeout = Duration::from_millis(options.get("timeout-ms", 5000));
+    let keys = Keys::new(
+        &options.get("distribution", "uniform".to_string()),
+        options.get("keys", 100).max(1),
+    );
+    // Fail on a bad workload now rather than in every client.
+    operation(&workload, &keys, &mut Rng::new(0));
+
+    let pending: Pending = Arc::default();
+    let cluster = match (command, peers) {
+        (Some(command), None) if !command.is_empty() => {
+            start(&command, options.get("nodes", 3).max(1), &pending)
+        }
+        (None, Some(path)) => connect(&path),
+        _ => usage(),
+    };
+    let nodes = cluster.nodes();
+
+    // Nodes we started need their ids, and broadcast nodes need neighbours;
+    // every node is given all the others.
+    let local = matches!(cluster, Cluster::Local(..));
+    for (i, node) in nodes
+        .iter()
+        .enumerate()
+        .filter(|_| local || workload == "broadcast")
+    {
+        let mut setup = cluster.client("c0".to_string(), i, &pending, timeout);
+        if local {
+            let init = json!({"type": "init", "node_id": node, "node_ids": nodes});
+            setup
+                .call(init)
+                .unwrap_or_else(|| fail(format!("{} did not answer init", node)));
+        }
+        if workload == "broadcast" {
+            let topology: BTreeMap<&String, Vec<&String>> = nodes
+                .iter()
+                .map(|n| (n, nodes.iter().filter(|m| *m != n).collect()))
+                .collect();
+            setup
+                .call(json!({"type": "topology", "topology": topology}))
+                .unwrap_or_else(|| fail(format!("{} did not answer topology", node)));
+        }
+    }
+
+    let history = Arc::new(History {
+        start: Instant::now(),
+        file: options.values.get("history").map(|path| {
+            let file = File::create(path)
+                .unwrap_or_else(|error| fail(format!("cannot write {}: {}", path, error)));
+            Mutex::new(BufWriter::new(file))
+        }),
+    });
+    let schedule = Arc::new(Schedule {
+        next: Mutex::new(Instant::now()),
+        interval: (rate > 0.0).then(|| Duration::from_secs_f64(1.0 / rate)),
+    });
+    let stats: Arc<Mutex<BTreeMap<&'static str, Stats>>> = Arc::default();
+    let keys = Arc::new(keys);
+    let seed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_nanos() as u64;
+    let start = Instant::now();
+    let clients: Vec<_> = (0..concurrency)
+        .map(|i| {
+            let mut client =
+                cluster.client(format!("c{}", i + 1), i % nodes.len(), &pending, timeout);
+            let (workload, keys, history, schedule, stats) = (
+                workload.clone(),
+                keys.clone(),
+                history.clone(),
+                schedule.clone(),
+                stats.clone(),
+            );
+            thread::spawn(move || {
+                let mut rng = Rng::new(seed.wrapping_add(i as u64));
+                let mut process = i as u64;
+                loop {
+                    schedule.wait();
+                    if start.elapsed() >= time_limit {
+                        break;
+                    }
+                    let (f, body, value) = operation(&workload, &keys, &mut rng);
+                    history.record(process, "invoke", f, &value, &client.node);
+                    let sent = Instant::now();
+                    let reply = client.call(body);
+                    let latency = sent.elapsed().as_secs_f64() * 1000.0;
+                    let mut stats = stats.lock().unwrap();
+                    let stats = stats.entry(f).or_default();
+                    match reply {
+                        Some(reply) if reply["type"] != "error" => {
+                            stats.ok += 1;
+                            stats.latencies.push(latency);
+                            let result = result(f, &value, &reply);
+                            history.record(process, "ok", f, &result, &client.node);
+                        }
+                        Some(reply)
+                            if !INDEFINITE.contains(&reply["code"].as_i64().unwrap_or(0)) =>
+                        {
+                            stats.fail += 1;
+                            stats.latencies.push(latency);
+                            history.record(process, "fail", f, &value, &client.node);
+                        }
+                        _ => {
+                            stats.info += 1;
+                            history.record(process, "info", f, &value, &client.node);
+                            process += concurrency as u64;
+                        }
+                    }
+                }
+            })
+        })
+        .collect();
+    for client in clients {
+        client.join().unwrap();
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+    if let Some(file) = &history.file {
+        file.lock().unwrap().flush().unwrap();
+    }
+    cluster.stop();
+
+    println!(
+        "{:<10} {:>8} {:>8} {:>8} {:>9} {:>9} {:>9} {:>9}",
+        "f", "ok", "fail", "info", "p50 ms", "p95 ms", "p99 ms", "max ms"
+    );
+    let mut total = Stats::default();
+    for (f, s) in stats.lock().unwrap().iter_mut() {
+        s.latencies.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        println!(
+            "{:<10} {:>8} {:>8} {:>8} {:>9.2} {:>9.2} {:>9.2} {:>9.2}",
+            f,
+            s.ok,
+            s.fail,
+            s.info,
+            percentile(&s.latencies, 0.5),
+            percentile(&s.latencies, 0.95),
+            percentile(&s.latencies, 0.99),
+            s.latencies.last().copied().unwrap_or(0.0)
+        );
+        total.ok += s.ok;
+        total.fail += s.fail;
+        total.info += s.info;
+        total.latencies.extend(&s.latencies);
+    }
+    total.latencies.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    println!(
+        "{:<10} {:>8} {:>8} {:>8} {:>9.2} {:>9.2} {:>9.2} {:>9.2}",
+        "total",
+        total.ok,
+        total.fail,
+        total.info,
+        percentile(&total.latencies, 0.5),
+        percentile(&total.latencies, 0.95),
+        percentile(&total.latencies, 0.99),
+        total.latencies.last().copied().unwrap_or(0.0)
+    );
+    println!(
+        "{:.1} ok/s, {:.1} requests/s over {:.1}s",
+        total.ok as f64 / elapsed,
+        (total.ok + total.fail + total.info) as f64 / elapsed,
+        elapsed
+    );
+}
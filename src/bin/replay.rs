@@ -0,0 +1,179 @@
+use serde_json::Value;
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::process::{self, Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+// Feeds the messages one node received in a recorded trace (see RAFT_TRACE in
+// transport.rs) back into a fresh copy of that node, and checks it sends the
+// same messages it sent when the trace was recorded.
+//
+//   replay [--step] <trace.jsonl> <node id> <command> [args...]
+//
+// e.g. `replay trace.jsonl n1 target/debug/raft txn --backend 2pc`. With
+// --step it waits for Enter before each message, printing what goes in and
+// comes out.
+//
+// Messages sent on timers and anything derived from the clock differ between
+// runs, so they are left out of the comparison: the types in TIMED, the txn
+// probe's reads, retries (a resend of a msg_id already sent), the `clock`
+// field, generated ids and our own msg_ids, which timer sends shift. What one
+// message sets off is compared regardless of order, and every mismatch is
+// reported rather than stopping at the first.
+
+// How long to wait for the node to send what it sent after a given message.
+const SETTLE: Duration = Duration::from_millis(500);
+// Types only ever sent on a timer: failure-detector heartbeats, CRDT gossip,
+// sequencer epochs and 2PC recovery.
+const TIMED: &[&str] = &["heartbeat", "replicate", "batch", "status"];
+// The lin-kv key the txn workload probes.
+const PROBE: &str = "probe";
+
+fn usage() -> ! {
+    eprintln!("usage: replay [--step] <trace.jsonl> <node id> <command> [args...]");
+    process::exit(2);
+}
+
+// Whether `message` was sent on a timer rather than in answer to what came
+// in. `sent` holds the (dest, msg_id) of every message seen so far.
+fn timed(message: &Value, sent: &mut HashSet<(String, i64)>) -> bool {
+    let body = &message["body"];
+    if TIMED.iter().any(|t| body["type"] == *t) || body["key"] == PROBE {
+        return true;
+    }
+    match (message["dest"].as_str(), body["msg_id"].as_i64()) {
+        (Some(dest), Some(msg_id)) => !sent.insert((dest.to_string(), msg_id)),
+        _ => false,
+    }
+}
+
+// The message without the fields that differ from run to run.
+fn comparable(message: &Value) -> Value {
+    let mut message = message.clone();
+    if let Some(body) = message["body"].as_object_mut() {
+        body.remove("clock");
+        body.remove("msg_id");
+        if body["type"] == "generate_ok" {
+            body.remove("id");
+        }
+    }
+    message
+}
+
+fn main() {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let step = args.first().map(|a| a == "--step").unwrap_or(false);
+    if step {
+        args.remove(0);
+    }
+    if args.len() < 3 {
+        usage();
+    }
+    let trace = fs::read_to_string(&args[0]).unwrap_or_else(|error| {
+        eprintln!("cannot read {}: {}", args[0], error);
+        process::exit(2);
+    });
+    let node = &args[1];
+
+    // The trace as a sequence of steps: one received message, then whatever
+    // the node sent before it received the next one.
+    let mut steps: Vec<(Value, Vec<Value>)> = Vec::new();
+    let mut sent = HashSet::new();
+    for line in trace.lines().filter(|l| !l.trim().is_empty()) {
+        let entry: Value = serde_json::from_str(line).unwrap();
+        if entry["node"] != node.as_str() {
+            continue;
+        }
+        match (entry["direction"].as_str(), steps.last_mut()) {
+            (Some("recv"), _) => steps.push((entry["message"].clone(), Vec::new())),
+            (Some("send"), Some((_, step))) if !timed(&entry["message"], &mut sent) => {
+                step.push(entry["message"].clone())
+            }
+            _ => {}
+        }
+    }
+    eprintln!("Replaying {} messages into {}", steps.len(), node);
+
+    // The replayed node must not append to the trace it is replaying.
+    let mut child = Command::new(&args[2])
+        .args(&args[3..])
+        .env_remove("RAFT_TRACE")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap_or_else(|error| {
+            eprintln!("cannot run {}: {}", args[2], error);
+            process::exit(2);
+        });
+    let mut stdin = child.stdin.take().unwrap();
+    let stdout = child.stdout.take().unwrap();
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut sent = HashSet::new();
+        for line in BufReader::new(stdout).lines() {
+            let message = match line {
+                Ok(line) => serde_json::from_str(&line).unwrap_or(Value::from(line)),
+                Err(_) => break,
+            };
+            if timed(&message, &mut sent) {
+                continue;
+            }
+            if tx.send(message).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut mismatches = 0;
+    let terminal = io::stdin();
+    for (i, (input, expected)) in steps.iter().enumerate() {
+        if step {
+            println!("[{}] > {}", i, input);
+            print!("Enter to send ");
+            io::stdout().flush().unwrap();
+            terminal.lock().read_line(&mut String::new()).unwrap();
+        }
+        writeln!(stdin, "{}", input).unwrap();
+        // With nothing to wait for, only take what has already come out.
+        let mut actual: Vec<Value> = rx.try_iter().collect();
+        while actual.len() < expected.len() {
+            match rx.recv_timeout(SETTLE) {
+                Ok(message) => actual.push(message),
+                Err(_) => break,
+            }
+        }
+        // Tasks the step set off may send in any order, so each message
+        // may match any recorded one not yet matched.
+        let mut unmatched: Vec<&Value> = expected.iter().collect();
+        for message in &actual {
+            if step {
+                println!("[{}] < {}", i, message);
+            }
+            match unmatched
+                .iter()
+                .position(|want| comparable(want) == comparable(message))
+            {
+                Some(j) => {
+                    unmatched.remove(j);
+                }
+                None => {
+                    mismatches += 1;
+                    println!("step {}: sent unrecorded {}", i, message);
+                }
+            }
+        }
+        for want in unmatched {
+            mismatches += 1;
+            println!("step {}: never sent recorded {}", i, want);
+        }
+    }
+    drop(stdin);
+    let _ = child.wait();
+    println!("{} steps, {} mismatches", steps.len(), mismatches);
+    process::exit((mismatches > 0) as i32);
+}
@@ -0,0 +1,250 @@
+use serde_json::Value;
+use std::collections::{BTreeSet, HashMap};
+use std::env;
+use std::fmt::Write;
+use std::fs;
+use std::process;
+
+// Renders a recorded trace (see RAFT_TRACE in transport.rs) as an HTML page
+// holding an SVG space-time diagram: one vertical lane per node or client,
+// time running down, and an arrow per message from where it was sent to where
+// it was received.
+//
+//   visualize <trace.jsonl> [out.html] [--scale <px per ms>]
+//
+// A request (a message with a msg_id and no in_reply_to) and its reply share
+// a colour; a request that never got one is drawn thick and red. Messages
+// that expect no reply are grey. Hovering an arrow shows the full message.
+
+const LANE_WIDTH: f64 = 180.0;
+const MARGIN: f64 = 60.0;
+const MAX_HEIGHT: f64 = 20000.0;
+// Clients and Maelstrom services are not traced, so messages to or from them
+// have only one end recorded; the other end is drawn this far away in time.
+const UNTRACED_MS: f64 = 2.0;
+
+struct Arrow {
+    src: String,
+    dest: String,
+    sent: Option<f64>,
+    received: Option<f64>,
+    message: Value,
+}
+
+fn usage() -> ! {
+    eprintln!("usage: visualize <trace.jsonl> [out.html] [--scale <px per ms>]");
+    process::exit(2);
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// Identifies a message on both ends of the wire, ignoring anything the network
+// adds outside the body.
+fn key(message: &Value) -> String {
+    format!("{} {} {}", message["src"], message["dest"], message["body"])
+}
+
+fn main() {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let mut scale = None;
+    if let Some(i) = args.iter().position(|a| a == "--scale") {
+        scale = args.get(i + 1).and_then(|s| s.parse::<f64>().ok());
+        if scale.is_none() {
+            usage();
+        }
+        args.drain(i..i + 2);
+    }
+    let input = args.first().unwrap_or_else(|| usage());
+    let output = args
+        .get(1)
+        .cloned()
+        .unwrap_or_else(|| format!("{}.html", input.trim_end_matches(".jsonl")));
+    let trace = fs::read_to_string(input).unwrap_or_else(|error| {
+        eprintln!("cannot read {}: {}", input, error);
+        process::exit(2);
+    });
+
+    // Pair each send with its receive. The same message may be sent more than
+    // once (retries), so unmatched ends queue up per key.
+    let mut arrows: Vec<Arrow> = Vec::new();
+    let mut in_flight: HashMap<String, Vec<usize>> = HashMap::new();
+    let mut pending_receives: HashMap<String, Vec<usize>> = HashMap::new();
+    for line in trace.lines().filter(|l| !l.trim().is_empty()) {
+        let entry: Value = serde_json::from_str(line).unwrap();
+        let message = &entry["message"];
+        let at = entry["timestamp"].as_f64().unwrap_or(0.0) / 1000.0;
+        let k = key(message);
+        let sending = entry["direction"] == "send";
+        let (waiting, queue) = match sending {
+            true => (&mut pending_receives, &mut in_flight),
+            _ => (&mut in_flight, &mut pending_receives),
+        };
+        match waiting.get_mut(&k).filter(|w| !w.is_empty()) {
+            Some(w) => {
+                let arrow = &mut arrows[w.remove(0)];
+                match sending {
+                    true => arrow.sent = Some(at),
+                    _ => arrow.received = Some(at),
+                }
+            }
+            None => {
+                queue.entry(k).or_default().push(arrows.len());
+                arrows.push(Arrow {
+                    src: message["src"].as_str().unwrap_or("?").to_string(),
+                    dest: message["dest"].as_str().unwrap_or("?").to_string(),
+                    sent: Some(at).filter(|_| sending),
+                    received: Some(at).filter(|_| !sending),
+                    message: message.clone(),
+                });
+            }
+        }
+    }
+    if arrows.is_empty() {
+        eprintln!("{} holds no messages", input);
+        process::exit(1);
+    }
+
+    // Nodes first, then clients and services, each in name order.
+    let names: BTreeSet<&str> = arrows
+        .iter()
+        .flat_map(|a| [a.src.as_str(), a.dest.as_str()])
+        .collect();
+    let mut lanes: Vec<&str> = names
+        .iter()
+        .copied()
+        .filter(|n| n.starts_with('n'))
+        .collect();
+    lanes.extend(names.iter().copied().filter(|n| !n.starts_with('n')));
+    let lane = |name: &str| lanes.iter().position(|l| *l == name).unwrap() as f64;
+
+    // Requests by (requester, msg_id), and the replies to them.
+    let mut requests: HashMap<(String, i64), usize> = HashMap::new();
+    for (i, a) in arrows.iter().enumerate() {
+        let body = &a.message["body"];
+        if let (Some(msg_id), None) = (body["msg_id"].as_i64(), body["in_reply_to"].as_i64()) {
+            requests.insert((a.src.clone(), msg_id), i);
+        }
+    }
+    let mut answered: HashMap<usize, usize> = HashMap::new();
+    for (i, a) in arrows.iter().enumerate() {
+        if let Some(reply_to) = a.message["body"]["in_reply_to"].as_i64() {
+            if let Some(request) = requests.get(&(a.dest.clone(), reply_to)) {
+                answered.insert(i, *request);
+                answered.insert(*request, *request);
+            }
+        }
+    }
+
+    let start = arrows
+        .iter()
+        .flat_map(|a| [a.sent, a.received])
+        .flatten()
+        .fold(f64::MAX, f64::min);
+    let end = arrows
+        .iter()
+        .flat_map(|a| [a.sent, a.received])
+        .flatten()
+        .fold(f64::MIN, f64::max);
+    let span = (end - start).max(1.0);
+    let scale = scale.unwrap_or((MAX_HEIGHT / span).min(4.0));
+    let width = MARGIN * 2.0 + LANE_WIDTH * (lanes.len() - 1) as f64;
+    let height = MARGIN * 2.0 + span * scale;
+    let x = |name: &str| MARGIN + lane(name) * LANE_WIDTH;
+    let y = |at: f64| MARGIN + (at - start) * scale;
+
+    let mut svg = String::new();
+    writeln!(
+        svg,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{:.0}" height="{:.0}" font-family="monospace" font-size="12">"#,
+        width, height
+    )
+    .unwrap();
+    writeln!(
+        svg,
+        r#"<defs><marker id="head" viewBox="0 0 10 10" refX="10" refY="5" markerWidth="6" markerHeight="6" orient="auto-start-reverse"><path d="M0,0 L10,5 L0,10 z" fill="context-stroke"/></marker></defs>"#
+    )
+    .unwrap();
+    for name in &lanes {
+        writeln!(
+            svg,
+            r##"<text x="{0:.1}" y="{1:.1}" text-anchor="middle">{2}</text><line x1="{0:.1}" y1="{3:.1}" x2="{0:.1}" y2="{4:.1}" stroke="#ccc"/>"##,
+            x(name),
+            MARGIN - 20.0,
+            escape(name),
+            MARGIN - 10.0,
+            height - MARGIN + 10.0
+        )
+        .unwrap();
+    }
+    let mut unanswered = 0;
+    for (i, a) in arrows.iter().enumerate() {
+        let sent = a.sent.unwrap_or_else(|| a.received.unwrap() - UNTRACED_MS);
+        let received = a.received.unwrap_or(sent + UNTRACED_MS);
+        let is_request = requests.get(&(
+            a.src.clone(),
+            a.message["body"]["msg_id"].as_i64().unwrap_or(-1),
+        )) == Some(&i);
+        let (colour, stroke) = match answered.get(&i) {
+            Some(request) => {
+                let hue = (*request as u64).wrapping_mul(0x9e3779b97f4a7c15) % 360;
+                (format!("hsl({}, 70%, 45%)", hue), 1.5)
+            }
+            None if is_request => {
+                unanswered += 1;
+                ("#d00".to_string(), 3.0)
+            }
+            None => ("#999".to_string(), 1.0),
+        };
+        let title = match (answered.get(&i), is_request) {
+            (None, true) => format!("UNANSWERED {}", a.message),
+            _ => a.message.to_string(),
+        };
+        writeln!(
+            svg,
+            r#"<line x1="{:.1}" y1="{:.1}" x2="{:.1}" y2="{:.1}" stroke="{}" stroke-width="{}" marker-end="url(#head)"><title>{}</title></line>"#,
+            x(&a.src),
+            y(sent),
+            x(&a.dest),
+            y(received),
+            colour,
+            stroke,
+            escape(&title)
+        )
+        .unwrap();
+        let label = a.message["body"]["type"].as_str().unwrap_or("");
+        writeln!(
+            svg,
+            r#"<text x="{:.1}" y="{:.1}" fill="{}" font-size="10">{}</text>"#,
+            (x(&a.src) + x(&a.dest)) / 2.0 + 4.0,
+            (y(sent) + y(received)) / 2.0 - 2.0,
+            colour,
+            escape(label)
+        )
+        .unwrap();
+    }
+    svg.push_str("</svg>\n");
+
+    let html = format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{0}</title></head>\n<body>\n<p>{0}: {1} messages over {2:.1} ms, {3} unanswered requests</p>\n{4}</body></html>\n",
+        escape(input),
+        arrows.len(),
+        span,
+        unanswered,
+        svg
+    );
+    fs::write(&output, html).unwrap_or_else(|error| {
+        eprintln!("cannot write {}: {}", output, error);
+        process::exit(2);
+    });
+    println!(
+        "Wrote {} ({} messages, {} unanswered requests)",
+        output,
+        arrows.len(),
+        unanswered
+    );
+}
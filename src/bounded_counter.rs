@@ -0,0 +1,419 @@
+use crate::{config, transport};
+use serde::{Deserialize, Serialize};
+use std::cmp::{max, min};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::time::{sleep, Duration};
+use tracing::info;
+
+struct Node {
+    id: String,
+    neighbours: Vec<String>,
+    next_msg_id: i64,
+    counter: ThreadCounter,
+}
+
+type ThreadCounter = Arc<Mutex<BoundedCounter>>;
+const PRECONDITION_FAILED: i64 = 22;
+
+// Escrow counter that never drops below zero. Each node may only decrement by
+// the rights it holds: its own increments plus rights transferred to it, minus
+// rights it gave away and what it has already decremented. Every map only grows
+// and is merged entry-wise with `max`.
+#[derive(Serialize, Deserialize, Default)]
+struct BoundedCounter {
+    increments: HashMap<String, i64>,
+    decrements: HashMap<String, i64>,
+    transfers: HashMap<String, HashMap<String, i64>>,
+}
+
+fn merge_max(ours: &mut HashMap<String, i64>, theirs: &HashMap<String, i64>) {
+    for (k, v) in theirs {
+        let current = ours.entry(k.to_string()).or_insert(0);
+        *current = max(*current, *v);
+    }
+}
+
+impl BoundedCounter {
+    fn transferred(&self, from: &str, to: &str) -> i64 {
+        match self.transfers.get(from) {
+            Some(t) => *t.get(to).unwrap_or(&0),
+            None => 0,
+        }
+    }
+
+    fn rights(&self, id: &str) -> i64 {
+        let received: i64 = self
+            .transfers
+            .iter()
+            .filter(|(from, _)| *from != id)
+            .map(|(_, t)| *t.get(id).unwrap_or(&0))
+            .sum();
+        let given: i64 = match self.transfers.get(id) {
+            Some(t) => t.iter().filter(|(to, _)| *to != id).map(|(_, v)| v).sum(),
+            None => 0,
+        };
+        self.increments.get(id).unwrap_or(&0) + received
+            - given
+            - self.decrements.get(id).unwrap_or(&0)
+    }
+
+    fn increment(&mut self, id: &str, delta: i64) {
+        *self.increments.entry(id.to_string()).or_insert(0) += delta;
+    }
+
+    fn decrement(&mut self, id: &str, delta: i64) -> bool {
+        if self.rights(id) < delta {
+            return false;
+        }
+        *self.decrements.entry(id.to_string()).or_insert(0) += delta;
+        true
+    }
+
+    // Hands over up to `amount` of our rights and returns the total ever
+    // transferred from `from` to `to`.
+    fn transfer(&mut self, from: &str, to: &str, amount: i64) -> i64 {
+        let amount = min(amount, self.rights(from));
+        let total = self
+            .transfers
+            .entry(from.to_string())
+            .or_default()
+            .entry(to.to_string())
+            .or_insert(0);
+        if amount > 0 {
+            *total += amount;
+        }
+        *total
+    }
+
+    fn merge(&mut self, other: &BoundedCounter) {
+        merge_max(&mut self.increments, &other.increments);
+        merge_max(&mut self.decrements, &other.decrements);
+        for (from, t) in &other.transfers {
+            merge_max(self.transfers.entry(from.to_string()).or_default(), t);
+        }
+    }
+
+    fn value(&self) -> i64 {
+        self.increments.values().sum::<i64>() - self.decrements.values().sum::<i64>()
+    }
+}
+
+async fn replicate(dest: String, src: String, counter: ThreadCounter) {
+    loop {
+        {
+            let counter = counter.lock().unwrap();
+            let message = Reply {
+                dest: &dest,
+                src: &src,
+                body: ResponseBody::Replicate {
+                    r#type: "replicate",
+                    msg: &counter,
+                },
+            };
+            transport::send(&message);
+        }
+        sleep(config::gossip_interval(Duration::from_millis(5000))).await;
+    }
+}
+
+impl Node {
+    fn new(id: String, neighbours: Vec<String>) -> Node {
+        let mut node = Node {
+            id,
+            neighbours,
+            next_msg_id: 0,
+            counter: Arc::new(Mutex::new(BoundedCounter::default())),
+        };
+        node.replicate_neighbours();
+        node
+    }
+    fn replicate_neighbours(&mut self) {
+        for n in &self.neighbours {
+            if *n != self.id {
+                tokio::spawn(replicate(n.clone(), self.id.clone(), self.counter.clone()));
+            }
+        }
+    }
+    // Splits the shortfall across peers by the rights our replica says they
+    // hold, so together they are never asked for more than we need; anything
+    // our replica cannot place is asked of the first peer. A peer with less
+    // than we thought hands over what it has.
+    fn request_rights(&mut self, amount: i64) {
+        let mut asks: Vec<(String, i64)> = Vec::new();
+        {
+            let counter = self.counter.lock().unwrap();
+            let mut remaining = amount;
+            for n in self.neighbours.iter().filter(|n| **n != self.id) {
+                let ask = min(remaining, max(counter.rights(n), 0));
+                if ask > 0 {
+                    asks.push((n.clone(), ask));
+                    remaining -= ask;
+                }
+            }
+            if remaining > 0 {
+                match asks.first_mut() {
+                    Some((_, ask)) => *ask += remaining,
+                    None => {
+                        if let Some(n) = self.neighbours.iter().find(|n| **n != self.id) {
+                            asks.push((n.clone(), remaining));
+                        }
+                    }
+                }
+            }
+        }
+        for (n, amount) in &asks {
+            self.next_msg_id += 1;
+            let message = Reply {
+                dest: n,
+                src: &self.id,
+                body: ResponseBody::TransferRights {
+                    r#type: "transfer_rights",
+                    msg_id: self.next_msg_id,
+                    amount: *amount,
+                },
+            };
+            transport::send(&message);
+        }
+    }
+}
+
+#[derive(Serialize)]
+enum ResponseBody<'a> {
+    #[serde(rename = "body")]
+    Init {
+        msg_id: i64,
+        r#type: &'a str,
+        in_reply_to: i64,
+    },
+    #[serde(rename = "body")]
+    Replicate {
+        r#type: &'a str,
+        msg: &'a BoundedCounter,
+    },
+    #[serde(rename = "body")]
+    Add {
+        r#type: &'a str,
+        in_reply_to: i64,
+        msg_id: i64,
+    },
+    #[serde(rename = "body")]
+    Read {
+        r#type: &'a str,
+        value: i64,
+        in_reply_to: i64,
+    },
+    #[serde(rename = "body")]
+    TransferRights {
+        r#type: &'a str,
+        msg_id: i64,
+        amount: i64,
+    },
+    #[serde(rename = "body")]
+    TransferRightsOk {
+        r#type: &'a str,
+        in_reply_to: i64,
+        msg_id: i64,
+        transferred: i64,
+    },
+    #[serde(rename = "body")]
+    Error {
+        r#type: &'a str,
+        in_reply_to: i64,
+        code: i64,
+        text: &'a str,
+        msg_id: i64,
+    },
+}
+
+#[derive(Serialize)]
+struct Reply<'a> {
+    src: &'a str,
+    dest: &'a str,
+    #[serde(flatten)]
+    body: ResponseBody<'a>,
+}
+
+pub async fn run() {
+    let mut node: Option<Node> = None;
+    while let Some(parsed) = transport::receive().await {
+        let _span = transport::request_span(&parsed).entered();
+        let body = &parsed["body"];
+        match body["type"].as_str().unwrap() {
+            "init" => {
+                node = Some(Node::new(
+                    body["node_id"].as_str().unwrap().to_string(),
+                    serde_json::from_value(body["node_ids"].clone()).unwrap(),
+                ));
+
+                info!("Initialized node {:?}", node.as_ref().map(|s| &s.id));
+                if let Some(s) = node.as_mut() {
+                    s.next_msg_id += 1;
+                }
+                let reply = Reply {
+                    dest: parsed["src"].as_str().unwrap(),
+                    src: node.as_ref().map(|s| &s.id).unwrap(),
+                    body: ResponseBody::Init {
+                        msg_id: node.as_ref().map(|s| s.next_msg_id).unwrap(),
+                        r#type: "init_ok",
+                        in_reply_to: body["msg_id"].as_i64().unwrap(),
+                    },
+                };
+                transport::send(&reply);
+            }
+            "add" => {
+                if let Some(s) = node.as_mut() {
+                    let delta = body["delta"].as_i64().unwrap();
+                    let (applied, rights) = {
+                        let mut counter = s.counter.lock().unwrap();
+                        match delta {
+                            0.. => {
+                                counter.increment(&s.id, delta);
+                                (true, 0)
+                            }
+                            _ => (counter.decrement(&s.id, -delta), counter.rights(&s.id)),
+                        }
+                    };
+                    s.next_msg_id += 1;
+                    let reply = match applied {
+                        true => Reply {
+                            dest: parsed["src"].as_str().unwrap(),
+                            src: &s.id,
+                            body: ResponseBody::Add {
+                                msg_id: s.next_msg_id,
+                                r#type: "add_ok",
+                                in_reply_to: body["msg_id"].as_i64().unwrap(),
+                            },
+                        },
+                        _ => Reply {
+                            dest: parsed["src"].as_str().unwrap(),
+                            src: &s.id,
+                            body: ResponseBody::Error {
+                                msg_id: s.next_msg_id,
+                                r#type: "error",
+                                in_reply_to: body["msg_id"].as_i64().unwrap(),
+                                text: "Insufficient rights",
+                                code: PRECONDITION_FAILED,
+                            },
+                        },
+                    };
+                    transport::send(&reply);
+                    if !applied {
+                        // Ask peers for the shortfall so a retry can succeed.
+                        s.request_rights(-delta - rights);
+                    }
+                }
+            }
+            "transfer_rights" => {
+                if let Some(s) = node.as_mut() {
+                    let to = parsed["src"].as_str().unwrap();
+                    let transferred = {
+                        let mut counter = s.counter.lock().unwrap();
+                        counter.transfer(&s.id, to, body["amount"].as_i64().unwrap())
+                    };
+                    s.next_msg_id += 1;
+                    let reply = Reply {
+                        dest: to,
+                        src: &s.id,
+                        body: ResponseBody::TransferRightsOk {
+                            msg_id: s.next_msg_id,
+                            r#type: "transfer_rights_ok",
+                            in_reply_to: body["msg_id"].as_i64().unwrap(),
+                            transferred,
+                        },
+                    };
+                    transport::send(&reply);
+                }
+            }
+            "transfer_rights_ok" => {
+                if let Some(s) = node.as_mut() {
+                    let from = parsed["src"].as_str().unwrap();
+                    let transferred = body["transferred"].as_i64().unwrap();
+                    let mut counter = s.counter.lock().unwrap();
+                    if transferred > counter.transferred(from, &s.id) {
+                        counter
+                            .transfers
+                            .entry(from.to_string())
+                            .or_default()
+                            .insert(s.id.clone(), transferred);
+                    }
+                }
+            }
+            "replicate" => {
+                if let Some(s) = node.as_mut() {
+                    let r: BoundedCounter = serde_json::from_value(body["msg"].clone()).unwrap();
+                    let mut counter = s.counter.lock().unwrap();
+                    counter.merge(&r);
+                }
+            }
+            "read" => {
+                if let Some(s) = node.as_mut() {
+                    s.next_msg_id += 1;
+                    let counter = s.counter.lock().unwrap();
+                    let reply = Reply {
+                        dest: parsed["src"].as_str().unwrap(),
+                        src: &s.id,
+                        body: ResponseBody::Read {
+                            value: counter.value(),
+                            r#type: "read_ok",
+                            in_reply_to: body["msg_id"].as_i64().unwrap(),
+                        },
+                    };
+                    transport::send(&reply);
+                }
+            }
+            _ => continue,
+        }
+    }
+    transport::shutdown().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn copy(counter: &BoundedCounter) -> BoundedCounter {
+        serde_json::from_value(serde_json::to_value(counter).unwrap()).unwrap()
+    }
+
+    fn same(a: &BoundedCounter, b: &BoundedCounter) -> bool {
+        a.increments == b.increments && a.decrements == b.decrements && a.transfers == b.transfers
+    }
+
+    #[test]
+    fn merges_commute_and_are_idempotent() {
+        let (mut a, mut b) = (BoundedCounter::default(), BoundedCounter::default());
+        a.increment("n0", 5);
+        a.transfer("n0", "n1", 2);
+        b.increment("n1", 3);
+        assert!(b.decrement("n1", 1));
+        let mut ab = copy(&a);
+        ab.merge(&b);
+        let mut ba = copy(&b);
+        ba.merge(&a);
+        assert!(same(&ab, &ba));
+        ab.merge(&ba);
+        assert!(same(&ab, &ba));
+        assert_eq!(ab.value(), 7);
+    }
+
+    #[test]
+    fn nodes_never_spend_more_than_their_rights() {
+        let (mut a, mut b) = (BoundedCounter::default(), BoundedCounter::default());
+        a.increment("n0", 10);
+        // Only what n0 holds can be handed over.
+        assert_eq!(a.transfer("n0", "n1", 4), 4);
+        assert_eq!(a.transfer("n0", "n1", 100), 10);
+        a.merge(&copy(&b));
+        b.merge(&copy(&a));
+        assert!(!a.decrement("n0", 1));
+        assert!(b.decrement("n1", 10));
+        assert!(!b.decrement("n1", 1));
+        a.merge(&b);
+        for id in ["n0", "n1"] {
+            // increments + received - given - decrements
+            assert!(a.rights(id) >= 0);
+        }
+        assert_eq!(a.value(), 0);
+    }
+}
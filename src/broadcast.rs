@@ -1,13 +1,16 @@
+use crate::failure_detector::{self, FailureDetector, ThreadDetector};
+use crate::{config, transport};
 use serde::Serialize;
-use serde_json::Value;
-use std::collections::HashSet;
-use std::io;
+use std::collections::{HashMap, HashSet};
 use std::sync::{Arc, Mutex};
 use tokio::time::{sleep, Duration};
+use tracing::{debug, info};
 
 struct Node {
     id: String,
     neighbours: Vec<String>,
+    topology: HashMap<String, Vec<String>>,
+    detector: ThreadDetector,
     next_msg_id: i64,
     messages: Vec<i64>,
     seen_messages: HashSet<i64>,
@@ -16,26 +19,52 @@ struct Node {
 
 type ThreadSet = Arc<Mutex<HashSet<i64>>>;
 
-async fn broadcast(dest: String, src: String, msg: i64, msg_id: i64, seen: ThreadSet) {
+// While `dest` is suspected down we stop sending to it and instead hand the
+// message, once, to `detour`: its own neighbours, so the nodes behind it still
+// hear about it. We go back to retrying `dest` as soon as it looks alive again.
+async fn broadcast(
+    dest: String,
+    src: String,
+    msg: i64,
+    msg_id: i64,
+    seen: ThreadSet,
+    detector: ThreadDetector,
+    detour: Vec<String>,
+) {
+    let mut detoured = false;
     loop {
         {
             let set = seen.lock().unwrap();
             if !set.contains(&msg_id) {
                 break;
             }
-            let message = Reply {
-                dest: &dest,
-                src: &src,
-                body: ResponseBody::Broadcast {
-                    r#type: "broadcast",
-                    message: msg,
-                    msg_id,
-                },
-            };
-            eprintln!("Sending {}", serde_json::to_string(&message).unwrap());
-            println!("{}", serde_json::to_string(&message).unwrap());
+            if !detector.lock().unwrap().suspected(&dest) {
+                let message = Reply {
+                    dest: &dest,
+                    src: &src,
+                    body: ResponseBody::Broadcast {
+                        r#type: "broadcast",
+                        message: msg,
+                        msg_id,
+                    },
+                };
+                transport::send(&message);
+            } else if !detoured {
+                for n in &detour {
+                    let message = Reply {
+                        dest: n,
+                        src: &src,
+                        body: ResponseBody::Forward {
+                            r#type: "broadcast",
+                            message: msg,
+                        },
+                    };
+                    transport::send(&message);
+                }
+                detoured = true;
+            }
         }
-        sleep(Duration::from_millis(2000)).await;
+        sleep(config::retry_interval(Duration::from_millis(2000))).await;
     }
 }
 
@@ -46,12 +75,22 @@ impl Node {
                 self.next_msg_id += 1;
                 let mut db = self.ack_messages.lock().unwrap();
                 db.insert(self.next_msg_id);
+                let detour = self
+                    .topology
+                    .get(n)
+                    .into_iter()
+                    .flatten()
+                    .filter(|d| **d != self.id)
+                    .cloned()
+                    .collect();
                 tokio::spawn(broadcast(
                     n.clone(),
                     self.id.clone(),
                     msg,
                     self.next_msg_id,
                     self.ack_messages.clone(),
+                    self.detector.clone(),
+                    detour,
                 ));
             }
         }
@@ -85,6 +124,9 @@ enum ResponseBody<'a> {
         message: i64,
         msg_id: i64,
     },
+    // A broadcast that expects no acknowledgement.
+    #[serde(rename = "body")]
+    Forward { r#type: &'a str, message: i64 },
     #[serde(rename = "body")]
     BroadcastOk {
         r#type: &'a str,
@@ -107,140 +149,142 @@ struct Reply<'a> {
     body: ResponseBody<'a>,
 }
 
-#[tokio::main]
-async fn main() {
+pub async fn run() {
     let mut node: Option<Node> = None;
-    loop {
-        let mut input = String::new();
-        match io::stdin().read_line(&mut input) {
-            Err(error) => println!("error: {}", error),
-            Ok(_) => {
-                eprintln!("Received {}", input);
-                let parsed: Value = serde_json::from_str(&input).unwrap();
-                let body = &parsed["body"];
-                match body["type"].as_str().unwrap() {
-                    "init" => {
-                        node = Some(Node {
-                            id: body["node_id"].as_str().unwrap().to_string(),
-                            next_msg_id: 0,
-                            neighbours: serde_json::from_value(body["node_ids"].clone()).unwrap(),
-                            messages: Vec::new(),
-                            seen_messages: HashSet::new(),
-                            ack_messages: Arc::new(Mutex::new(HashSet::new())),
-                        });
-                        eprintln!("Initialized node {:?}", node.as_ref().map(|s| &s.id));
-                        if let Some(s) = node.as_mut() {
-                            s.next_msg_id += 1;
-                        }
-                        let reply = Reply {
-                            dest: parsed["src"].as_str().unwrap(),
-                            src: node.as_ref().map(|s| &s.id).unwrap(),
-                            body: ResponseBody::Init {
-                                msg_id: node.as_ref().map(|s| s.next_msg_id).unwrap(),
-                                r#type: "init_ok",
-                                in_reply_to: body["msg_id"].as_i64().unwrap(),
-                            },
-                        };
-                        eprintln!("Sending {}", serde_json::to_string(&reply).unwrap());
-                        println!("{}", serde_json::to_string(&reply).unwrap());
-                    }
-                    "echo" => {
-                        eprintln!("Echoing {}", body["echo"].as_str().unwrap());
-                        // node.as_mut().map(|s| s.next_msg_id += 1);
-                        if let Some(s) = node.as_mut() {
-                            s.next_msg_id += 1;
-                        }
-                        let reply = Reply {
-                            dest: parsed["src"].as_str().unwrap(),
-                            src: node.as_ref().map(|s| &s.id).unwrap(),
-                            body: ResponseBody::Echo {
-                                msg_id: node.as_ref().map(|s| s.next_msg_id).unwrap(),
-                                r#type: "echo_ok",
-                                in_reply_to: body["msg_id"].as_i64().unwrap(),
-                                echo: body["echo"].as_str().unwrap(),
-                            },
-                        };
-                        eprintln!("Sending {}", serde_json::to_string(&reply).unwrap());
-                        println!("{}", serde_json::to_string(&reply).unwrap());
-                    }
-                    "topology" => {
-                        if let Some(s) = node.as_mut() {
-                            s.neighbours =
-                                serde_json::from_value(body["topology"][&s.id].clone()).unwrap();
-                            eprintln!("My neighbours are {:?}", s.neighbours);
-                        }
-                        if let Some(s) = node.as_mut() {
-                            s.next_msg_id += 1;
-                        }
-                        let reply = Reply {
-                            dest: parsed["src"].as_str().unwrap(),
-                            src: node.as_ref().map(|s| &s.id).unwrap(),
-                            body: ResponseBody::Topology {
-                                msg_id: node.as_ref().map(|s| s.next_msg_id).unwrap(),
-                                r#type: "topology_ok",
-                                in_reply_to: body["msg_id"].as_i64().unwrap(),
-                            },
-                        };
-                        eprintln!("Sending {}", serde_json::to_string(&reply).unwrap());
-                        println!("{}", serde_json::to_string(&reply).unwrap());
+    while let Some(parsed) = transport::receive().await {
+        let _span = transport::request_span(&parsed).entered();
+        let body = &parsed["body"];
+        if let Some(s) = node.as_ref() {
+            let src = parsed["src"].as_str().unwrap();
+            let mut detector = s.detector.lock().unwrap();
+            match body["type"] == "heartbeat" {
+                true => detector.heartbeat(src),
+                _ => detector.heard(src),
+            }
+        }
+        match body["type"].as_str().unwrap() {
+            "init" => {
+                let id = body["node_id"].as_str().unwrap().to_string();
+                let node_ids: Vec<String> =
+                    serde_json::from_value(body["node_ids"].clone()).unwrap();
+                tokio::spawn(failure_detector::heartbeat(id.clone(), node_ids.clone()));
+                node = Some(Node {
+                    detector: Arc::new(Mutex::new(FailureDetector::new(&id, &node_ids))),
+                    id,
+                    next_msg_id: 0,
+                    neighbours: node_ids,
+                    topology: HashMap::new(),
+                    messages: Vec::new(),
+                    seen_messages: HashSet::new(),
+                    ack_messages: Arc::new(Mutex::new(HashSet::new())),
+                });
+                info!("Initialized node {:?}", node.as_ref().map(|s| &s.id));
+                if let Some(s) = node.as_mut() {
+                    s.next_msg_id += 1;
+                }
+                let reply = Reply {
+                    dest: parsed["src"].as_str().unwrap(),
+                    src: node.as_ref().map(|s| &s.id).unwrap(),
+                    body: ResponseBody::Init {
+                        msg_id: node.as_ref().map(|s| s.next_msg_id).unwrap(),
+                        r#type: "init_ok",
+                        in_reply_to: body["msg_id"].as_i64().unwrap(),
+                    },
+                };
+                transport::send(&reply);
+            }
+            "echo" => {
+                debug!("Echoing {}", body["echo"].as_str().unwrap());
+                // node.as_mut().map(|s| s.next_msg_id += 1);
+                if let Some(s) = node.as_mut() {
+                    s.next_msg_id += 1;
+                }
+                let reply = Reply {
+                    dest: parsed["src"].as_str().unwrap(),
+                    src: node.as_ref().map(|s| &s.id).unwrap(),
+                    body: ResponseBody::Echo {
+                        msg_id: node.as_ref().map(|s| s.next_msg_id).unwrap(),
+                        r#type: "echo_ok",
+                        in_reply_to: body["msg_id"].as_i64().unwrap(),
+                        echo: body["echo"].as_str().unwrap(),
+                    },
+                };
+                transport::send(&reply);
+            }
+            "topology" => {
+                if let Some(s) = node.as_mut() {
+                    s.topology = serde_json::from_value(body["topology"].clone()).unwrap();
+                    s.neighbours = s.topology.get(&s.id).cloned().unwrap_or_default();
+                    debug!("My neighbours are {:?}", s.neighbours);
+                }
+                if let Some(s) = node.as_mut() {
+                    s.next_msg_id += 1;
+                }
+                let reply = Reply {
+                    dest: parsed["src"].as_str().unwrap(),
+                    src: node.as_ref().map(|s| &s.id).unwrap(),
+                    body: ResponseBody::Topology {
+                        msg_id: node.as_ref().map(|s| s.next_msg_id).unwrap(),
+                        r#type: "topology_ok",
+                        in_reply_to: body["msg_id"].as_i64().unwrap(),
+                    },
+                };
+                transport::send(&reply);
+            }
+            "broadcast" => {
+                if let Some(s) = node.as_mut() {
+                    if !s.seen_messages.contains(&body["message"].as_i64().unwrap()) {
+                        s.broadcast_neighbours(
+                            body["message"].as_i64().unwrap(),
+                            parsed["src"].as_str().unwrap(),
+                        );
+                        s.messages.push(body["message"].as_i64().unwrap());
+                        s.seen_messages.insert(body["message"].as_i64().unwrap());
                     }
-                    "broadcast" => {
-                        if let Some(s) = node.as_mut() {
-                            if !s.seen_messages.contains(&body["message"].as_i64().unwrap()) {
-                                s.broadcast_neighbours(
-                                    body["message"].as_i64().unwrap(),
-                                    parsed["src"].as_str().unwrap(),
-                                );
-                                s.messages.push(body["message"].as_i64().unwrap());
-                                s.seen_messages.insert(body["message"].as_i64().unwrap());
-                            }
-                        }
-
-                        if let Some(msg_id) = body["msg_id"].as_i64() {
-                            if let Some(s) = node.as_mut() {
-                                s.next_msg_id += 1;
-                            }
+                }
 
-                            let reply = Reply {
-                                dest: parsed["src"].as_str().unwrap(),
-                                src: node.as_ref().map(|s| &s.id).unwrap(),
-                                body: ResponseBody::BroadcastOk {
-                                    msg_id: node.as_ref().map(|s| s.next_msg_id).unwrap(),
-                                    r#type: "broadcast_ok",
-                                    in_reply_to: msg_id,
-                                },
-                            };
-                            eprintln!("Sending {}", serde_json::to_string(&reply).unwrap());
-                            println!("{}", serde_json::to_string(&reply).unwrap());
-                        }
-                    }
-                    "broadcast_ok" => {
-                        if let Some(s) = node.as_mut() {
-                            let mut set = s.ack_messages.lock().unwrap();
-                            if set.contains(&body["in_reply_to"].as_i64().unwrap()) {
-                                set.remove(&body["in_reply_to"].as_i64().unwrap());
-                            }
-                        }
+                if let Some(msg_id) = body["msg_id"].as_i64() {
+                    if let Some(s) = node.as_mut() {
+                        s.next_msg_id += 1;
                     }
-                    "read" => {
-                        if let Some(s) = node.as_mut() {
-                            s.next_msg_id += 1;
-                        }
-                        let reply = Reply {
-                            dest: parsed["src"].as_str().unwrap(),
-                            src: node.as_ref().map(|s| &s.id).unwrap(),
-                            body: ResponseBody::Read {
-                                messages: node.as_ref().map(|s| &s.messages[..]).unwrap(),
-                                r#type: "read_ok",
-                                in_reply_to: body["msg_id"].as_i64().unwrap(),
-                            },
-                        };
-                        eprintln!("Sending {}", serde_json::to_string(&reply).unwrap());
-                        println!("{}", serde_json::to_string(&reply).unwrap());
+
+                    let reply = Reply {
+                        dest: parsed["src"].as_str().unwrap(),
+                        src: node.as_ref().map(|s| &s.id).unwrap(),
+                        body: ResponseBody::BroadcastOk {
+                            msg_id: node.as_ref().map(|s| s.next_msg_id).unwrap(),
+                            r#type: "broadcast_ok",
+                            in_reply_to: msg_id,
+                        },
+                    };
+                    transport::send(&reply);
+                }
+            }
+            "broadcast_ok" => {
+                if let Some(s) = node.as_mut() {
+                    let mut set = s.ack_messages.lock().unwrap();
+                    if set.contains(&body["in_reply_to"].as_i64().unwrap()) {
+                        set.remove(&body["in_reply_to"].as_i64().unwrap());
                     }
-                    _ => continue,
                 }
             }
+            "read" => {
+                if let Some(s) = node.as_mut() {
+                    s.next_msg_id += 1;
+                }
+                let reply = Reply {
+                    dest: parsed["src"].as_str().unwrap(),
+                    src: node.as_ref().map(|s| &s.id).unwrap(),
+                    body: ResponseBody::Read {
+                        messages: node.as_ref().map(|s| &s.messages[..]).unwrap(),
+                        r#type: "read_ok",
+                        in_reply_to: body["msg_id"].as_i64().unwrap(),
+                    },
+                };
+                transport::send(&reply);
+            }
+            _ => continue,
         }
     }
+    transport::shutdown().await;
 }
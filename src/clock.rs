@@ -0,0 +1,273 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::cmp::{max, Ordering};
+use std::collections::BTreeMap;
+use std::sync::{Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+// How far a remote hybrid timestamp may run ahead of our wall clock before we
+// refuse to adopt it. Without a bound, one node with a bad clock would drag
+// every other node's timestamps into the future with it.
+pub const MAX_DRIFT_MS: i64 = 1000;
+
+pub fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as i64
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct Lamport(pub i64);
+
+impl Lamport {
+    pub fn tick(&mut self) -> i64 {
+        self.0 += 1;
+        self.0
+    }
+
+    pub fn observe(&mut self, remote: Lamport) -> i64 {
+        self.0 = max(self.0, remote.0) + 1;
+        self.0
+    }
+}
+
+// Missing entries count as zero, so clocks that differ only in zeros compare
+// equal.
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq, Eq, Debug)]
+pub struct VectorClock(pub BTreeMap<String, i64>);
+
+impl VectorClock {
+    pub fn get(&self, node: &str) -> i64 {
+        self.0.get(node).copied().unwrap_or(0)
+    }
+
+    pub fn increment(&mut self, node: &str) -> i64 {
+        let count = self.0.entry(node.to_string()).or_insert(0);
+        *count += 1;
+        *count
+    }
+
+    pub fn merge(&mut self, other: &VectorClock) {
+        for (node, count) in &other.0 {
+            let current = self.0.entry(node.clone()).or_insert(0);
+            *current = max(*current, *count);
+        }
+    }
+
+    // `None` when the clocks are concurrent.
+    pub fn compare(&self, other: &VectorClock) -> Option<Ordering> {
+        let (mut less, mut greater) = (false, false);
+        for node in self.0.keys().chain(other.0.keys()) {
+            match self.get(node).cmp(&other.get(node)) {
+                Ordering::Less => less = true,
+                Ordering::Greater => greater = true,
+                Ordering::Equal => {}
+            }
+        }
+        match (less, greater) {
+            (false, false) => Some(Ordering::Equal),
+            (true, false) => Some(Ordering::Less),
+            (false, true) => Some(Ordering::Greater),
+            _ => None,
+        }
+    }
+}
+
+// Hybrid logical clock: wall-clock millis, and a logical counter for events
+// within the same millisecond. It never runs backwards and stays close to
+// physical time.
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct Hlc(pub i64, pub i64);
+
+impl Hlc {
+    fn advance(&mut self, remote: Hlc) -> Hlc {
+        let Hlc(pt, l) = *self;
+        let Hlc(rpt, rl) = remote;
+        let wall = max(now(), max(pt, rpt));
+        let logical = match (wall == pt, wall == rpt) {
+            (true, true) => max(l, rl) + 1,
+            (true, false) => l + 1,
+            (false, true) => rl + 1,
+            _ => 0,
+        };
+        *self = Hlc(wall, logical);
+        *self
+    }
+
+    // A local or send event.
+    pub fn tick(&mut self) -> Hlc {
+        self.advance(Hlc::default())
+    }
+
+    // A receive event. A remote timestamp more than MAX_DRIFT_MS ahead of our
+    // wall clock is rejected with the drift, leaving the clock unchanged.
+    pub fn observe(&mut self, remote: Hlc) -> Result<Hlc, i64> {
+        let drift = remote.0 - now();
+        if drift > MAX_DRIFT_MS {
+            return Err(drift);
+        }
+        Ok(self.advance(remote))
+    }
+}
+
+// What a node piggybacks on the messages it sends to other nodes, under the
+// body's `clock` field.
+#[derive(Serialize, Deserialize)]
+pub struct Stamp {
+    pub lamport: Lamport,
+    pub vector: VectorClock,
+    pub hlc: Hlc,
+}
+
+// A node's clocks. Sending a message to another node is an event on all
+// three; receiving one merges the sender's stamp into them. Clients and
+// services get no stamp. transport.rs does both for every message, so
+// workloads only read the clocks, or tick them for events of their own, via
+// `with`.
+pub struct Clocks {
+    id: String,
+    node_ids: Vec<String>,
+    pub lamport: Lamport,
+    pub vector: VectorClock,
+    pub hlc: Hlc,
+}
+
+impl Clocks {
+    pub fn new(id: &str, node_ids: &[String]) -> Clocks {
+        Clocks {
+            id: id.to_string(),
+            node_ids: node_ids.to_vec(),
+            lamport: Lamport::default(),
+            vector: VectorClock::default(),
+            hlc: Hlc::default(),
+        }
+    }
+
+    pub fn stamp(&mut self, dest: &str, body: &mut Value) {
+        if !self.node_ids.iter().any(|n| n == dest) {
+            return;
+        }
+        self.lamport.tick();
+        self.vector.increment(&self.id);
+        self.hlc.tick();
+        body["clock"] = serde_json::to_value(Stamp {
+            lamport: self.lamport,
+            vector: self.vector.clone(),
+            hlc: self.hlc,
+        })
+        .unwrap();
+    }
+
+    // Merges the stamp on `body`, if any. The Lamport and vector clocks always
+    // take it; the hybrid clock refuses one too far ahead and returns the drift.
+    pub fn observe(&mut self, body: &Value) -> Result<(), i64> {
+        let stamp: Stamp = match serde_json::from_value(body["clock"].clone()) {
+            Ok(stamp) => stamp,
+            Err(_) => return Ok(()),
+        };
+        self.lamport.observe(stamp.lamport);
+        self.vector.merge(&stamp.vector);
+        self.vector.increment(&self.id);
+        self.hlc.observe(stamp.hlc).map(|_| ())
+    }
+}
+
+fn clocks() -> &'static Mutex<Clocks> {
+    static CLOCKS: OnceLock<Mutex<Clocks>> = OnceLock::new();
+    CLOCKS.get_or_init(|| Mutex::new(Clocks::new("", &[])))
+}
+
+// Called by transport.rs when `init` tells us who we and our peers are.
+pub fn init(id: &str, node_ids: &[String]) {
+    *clocks().lock().unwrap() = Clocks::new(id, node_ids);
+}
+
+// Runs `f` on this node's clocks.
+pub fn with<T>(f: impl FnOnce(&mut Clocks) -> T) -> T {
+    f(&mut clocks().lock().unwrap())
+}
+
+// Called by transport.rs with each message as it goes out or comes in.
+pub fn stamp(message: &mut Value) {
+    let dest = message["dest"].as_str().unwrap_or_default().to_string();
+    with(|clocks| clocks.stamp(&dest, &mut message["body"]));
+}
+
+pub fn observe(message: &Value) {
+    if let Err(drift) = with(|clocks| clocks.observe(&message["body"])) {
+        warn!("Clock of {} is {}ms ahead of ours", message["src"], drift);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vector(counts: &[(&str, i64)]) -> VectorClock {
+        VectorClock(counts.iter().map(|(n, c)| (n.to_string(), *c)).collect())
+    }
+
+    #[test]
+    fn vector_clocks_compare_by_causality() {
+        let a = vector(&[("n0", 1), ("n1", 2)]);
+        assert_eq!(a.compare(&a.clone()), Some(Ordering::Equal));
+        assert_eq!(
+            a.compare(&vector(&[("n0", 1), ("n1", 2), ("n2", 0)])),
+            Some(Ordering::Equal)
+        );
+        assert_eq!(
+            a.compare(&vector(&[("n0", 1), ("n1", 3)])),
+            Some(Ordering::Less)
+        );
+        assert_eq!(a.compare(&vector(&[("n0", 1)])), Some(Ordering::Greater));
+        assert_eq!(a.compare(&vector(&[("n0", 2), ("n1", 1)])), None);
+        assert_eq!(a.compare(&vector(&[("n2", 1)])), None);
+
+        let mut merged = a.clone();
+        merged.merge(&vector(&[("n0", 2), ("n2", 1)]));
+        assert_eq!(merged, vector(&[("n0", 2), ("n1", 2), ("n2", 1)]));
+    }
+
+    #[test]
+    fn hybrid_clock_never_runs_backwards() {
+        let mut hlc = Hlc::default();
+        let mut last = hlc;
+        for _ in 0..1000 {
+            let next = hlc.tick();
+            assert!(next > last);
+            last = next;
+        }
+
+        // A remote timestamp ahead of our wall clock, but within bounds, is
+        // adopted and followed.
+        let remote = Hlc(now() + MAX_DRIFT_MS / 2, 7);
+        let observed = hlc.observe(remote).unwrap();
+        assert!(observed > remote && observed > last);
+        assert!(hlc.tick() > observed);
+
+        // One too far ahead is refused and leaves the clock alone.
+        let before = hlc;
+        assert!(hlc.observe(Hlc(now() + 10 * MAX_DRIFT_MS, 0)).is_err());
+        assert_eq!(hlc, before);
+    }
+
+    #[test]
+    fn only_messages_between_nodes_are_stamped() {
+        let nodes = ["n0".to_string(), "n1".to_string()];
+        let mut n0 = Clocks::new("n0", &nodes);
+        let mut n1 = Clocks::new("n1", &nodes);
+
+        let mut body = serde_json::json!({"type": "read_ok"});
+        n0.stamp("c1", &mut body);
+        assert!(body.get("clock").is_none());
+
+        let mut body = serde_json::json!({"type": "gossip"});
+        n0.stamp("n1", &mut body);
+        n1.observe(&body).unwrap();
+        assert!(n1.lamport > n0.lamport);
+        assert_eq!(n1.vector, vector(&[("n0", 1), ("n1", 1)]));
+        assert!(n1.hlc > n0.hlc);
+    }
+}
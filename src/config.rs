@@ -0,0 +1,221 @@
+use std::collections::HashMap;
+use std::env;
+use std::process;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+// The command line picks the workload to run and tunes it:
+//
+//   raft <workload> [--<flag> <value>]...
+//
+// e.g. `raft g-counter --backend seq-kv` or `raft broadcast --retry-ms 500`.
+// Flags that pick a mode take one of a fixed list of values, the first being
+// the default; the rest take a number of milliseconds or a directory, and
+// anything not given keeps the default of the code that reads it. Every workload takes
+// --rpc-timeout-ms, how long a node waits for a reply before giving up on it.
+
+enum Arg {
+    Mode(&'static [&'static str]),
+    Millis,
+    Dir,
+}
+
+struct Flag {
+    name: &'static str,
+    arg: Arg,
+}
+
+const fn mode(name: &'static str, values: &'static [&'static str]) -> Flag {
+    Flag {
+        name,
+        arg: Arg::Mode(values),
+    }
+}
+
+const fn millis(name: &'static str) -> Flag {
+    Flag {
+        name,
+        arg: Arg::Millis,
+    }
+}
+
+const fn dir(name: &'static str) -> Flag {
+    Flag {
+        name,
+        arg: Arg::Dir,
+    }
+}
+
+const RPC_TIMEOUT: Flag = millis("rpc-timeout-ms");
+const GOSSIP: Flag = millis("gossip-ms");
+const RETRY: Flag = millis("retry-ms");
+const HEARTBEAT: Flag = millis("heartbeat-ms");
+
+// Each workload and the flags it takes besides --rpc-timeout-ms.
+const WORKLOADS: &[(&str, &[Flag])] = &[
+    ("echo", &[]),
+    (
+        "unique-ids",
+        &[mode("format", &["int", "uuid"]), dir("lease-dir")],
+    ),
+    ("broadcast", &[RETRY, HEARTBEAT]),
+    (
+        "g-counter",
+        &[mode("backend", &["gossip", "seq-kv"]), GOSSIP],
+    ),
+    ("pn-counter", &[GOSSIP]),
+    ("bounded-counter", &[GOSSIP]),
+    ("crdts", &[GOSSIP]),
+    ("or-set", &[GOSSIP]),
+    ("or-map", &[GOSSIP]),
+    ("lww-register", &[GOSSIP]),
+    ("mv-register", &[GOSSIP]),
+    ("rga", &[GOSSIP]),
+    ("kafka", &[mode("backend", &["sharded", "lin-kv"])]),
+    (
+        "txn",
+        &[
+            mode("backend", &["lin-kv", "2pc", "calvin", "single-node"]),
+            RETRY,
+            HEARTBEAT,
+            millis("prepare-timeout-ms"),
+            millis("intent-timeout-ms"),
+            millis("epoch-ms"),
+        ],
+    ),
+    ("txn-rw-register", &[GOSSIP]),
+];
+
+struct Config {
+    workload: &'static str,
+    values: HashMap<&'static str, String>,
+}
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+fn usage() -> ! {
+    eprintln!("usage: raft <workload> [--<flag> <value>]...");
+    eprintln!();
+    for (workload, flags) in WORKLOADS {
+        let mut line = format!("  {:<16} --{} <ms>", workload, RPC_TIMEOUT.name);
+        for flag in flags.iter() {
+            let value = match flag.arg {
+                Arg::Mode(values) => values.join("|"),
+                Arg::Millis => "<ms>".to_string(),
+                Arg::Dir => "<dir>".to_string(),
+            };
+            line += &format!(" --{} {}", flag.name, value);
+        }
+        eprintln!("{}", line);
+    }
+    process::exit(2);
+}
+
+fn fail(message: String) -> ! {
+    eprintln!("raft: {}", message);
+    usage();
+}
+
+// Reads the command line, exiting with the usage if it does not parse. Returns
+// the workload to run.
+pub fn parse() -> &'static str {
+    let mut args = env::args().skip(1);
+    let name = args.next().unwrap_or_else(|| usage());
+    let (workload, flags) = WORKLOADS
+        .iter()
+        .find(|(workload, _)| *workload == name)
+        .unwrap_or_else(|| fail(format!("unknown workload `{}`", name)));
+    let mut values = HashMap::new();
+    while let Some(arg) = args.next() {
+        let flag = arg
+            .strip_prefix("--")
+            .and_then(|arg| {
+                flags
+                    .iter()
+                    .chain([&RPC_TIMEOUT])
+                    .find(|flag| flag.name == arg)
+            })
+            .unwrap_or_else(|| fail(format!("{} does not take `{}`", workload, arg)));
+        let value = args
+            .next()
+            .unwrap_or_else(|| fail(format!("--{} needs a value", flag.name)));
+        let valid = match flag.arg {
+            Arg::Mode(values) => values.contains(&value.as_str()),
+            Arg::Millis => value.parse::<u64>().is_ok(),
+            Arg::Dir => !value.is_empty(),
+        };
+        if !valid {
+            fail(format!("bad value `{}` for --{}", value, flag.name));
+        }
+        values.insert(flag.name, value);
+    }
+    CONFIG.get_or_init(|| Config { workload, values }).workload
+}
+
+fn value(name: &str) -> Option<&'static str> {
+    CONFIG.get()?.values.get(name).map(String::as_str)
+}
+
+// The workload being run, or nothing before `parse`.
+pub fn workload() -> &'static str {
+    CONFIG.get().map_or("", |config| config.workload)
+}
+
+// The value of a mode flag, or its default.
+fn choice(name: &str) -> &'static str {
+    value(name).unwrap_or_else(|| {
+        let workload = workload();
+        let (_, flags) = WORKLOADS.iter().find(|(w, _)| *w == workload).unwrap();
+        flags
+            .iter()
+            .find_map(|flag| match flag.arg {
+                Arg::Mode(values) if flag.name == name => Some(values[0]),
+                _ => None,
+            })
+            .unwrap()
+    })
+}
+
+fn duration(name: &str, default: Duration) -> Duration {
+    value(name).map_or(default, |ms| Duration::from_millis(ms.parse().unwrap()))
+}
+
+pub fn backend() -> &'static str {
+    choice("backend")
+}
+
+pub fn format() -> &'static str {
+    choice("format")
+}
+
+pub fn lease_dir() -> Option<&'static str> {
+    value("lease-dir")
+}
+
+pub fn rpc_timeout(default: Duration) -> Duration {
+    duration(RPC_TIMEOUT.name, default)
+}
+
+pub fn gossip_interval(default: Duration) -> Duration {
+    duration(GOSSIP.name, default)
+}
+
+pub fn retry_interval(default: Duration) -> Duration {
+    duration(RETRY.name, default)
+}
+
+pub fn heartbeat_interval(default: Duration) -> Duration {
+    duration(HEARTBEAT.name, default)
+}
+
+pub fn prepare_timeout(default: Duration) -> Duration {
+    duration("prepare-timeout-ms", default)
+}
+
+pub fn intent_timeout(default: Duration) -> Duration {
+    duration("intent-timeout-ms", default)
+}
+
+pub fn epoch(default: Duration) -> Duration {
+    duration("epoch-ms", default)
+}
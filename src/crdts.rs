@@ -1,9 +1,9 @@
+use crate::{config, transport};
 use serde::Serialize;
-use serde_json::Value;
 use std::collections::HashSet;
-use std::io;
 use std::sync::{Arc, Mutex};
 use tokio::time::{sleep, Duration};
+use tracing::{debug, info};
 
 struct Node {
     id: String,
@@ -26,10 +26,9 @@ async fn replicate(dest: String, src: String, messges: ThreadSet) {
                     message: &set,
                 },
             };
-            eprintln!("Sending {}", serde_json::to_string(&message).unwrap());
-            println!("{}", serde_json::to_string(&message).unwrap());
+            transport::send(&message);
         }
-        sleep(Duration::from_millis(5000)).await;
+        sleep(config::gossip_interval(Duration::from_millis(5000))).await;
     }
 }
 
@@ -99,127 +98,115 @@ struct Reply<'a> {
     body: ResponseBody<'a>,
 }
 
-#[tokio::main]
-async fn main() {
+pub async fn run() {
     let mut node: Option<Node> = None;
-    loop {
-        let mut input = String::new();
-        match io::stdin().read_line(&mut input) {
-            Err(error) => println!("error: {}", error),
-            Ok(_) => {
-                eprintln!("Received {}", input);
-                let parsed: Value = serde_json::from_str(&input).unwrap();
-                let body = &parsed["body"];
-                match body["type"].as_str().unwrap() {
-                    "init" => {
-                        node = Some(Node::new(
-                            body["node_id"].as_str().unwrap().to_string(),
-                            serde_json::from_value(body["node_ids"].clone()).unwrap(),
-                        ));
+    while let Some(parsed) = transport::receive().await {
+        let _span = transport::request_span(&parsed).entered();
+        let body = &parsed["body"];
+        match body["type"].as_str().unwrap() {
+            "init" => {
+                node = Some(Node::new(
+                    body["node_id"].as_str().unwrap().to_string(),
+                    serde_json::from_value(body["node_ids"].clone()).unwrap(),
+                ));
 
-                        eprintln!("Initialized node {:?}", node.as_ref().map(|s| &s.id));
-                        if let Some(s) = node.as_mut() {
-                            s.next_msg_id += 1;
-                        }
-                        let reply = Reply {
-                            dest: parsed["src"].as_str().unwrap(),
-                            src: node.as_ref().map(|s| &s.id).unwrap(),
-                            body: ResponseBody::Init {
-                                msg_id: node.as_ref().map(|s| s.next_msg_id).unwrap(),
-                                r#type: "init_ok",
-                                in_reply_to: body["msg_id"].as_i64().unwrap(),
-                            },
-                        };
-                        eprintln!("Sending {}", serde_json::to_string(&reply).unwrap());
-                        println!("{}", serde_json::to_string(&reply).unwrap());
-                    }
-                    "echo" => {
-                        eprintln!("Echoing {}", body["echo"].as_str().unwrap());
-                        // node.as_mut().map(|s| s.next_msg_id += 1);
-                        if let Some(s) = node.as_mut() {
-                            s.next_msg_id += 1;
-                        }
-                        let reply = Reply {
-                            dest: parsed["src"].as_str().unwrap(),
-                            src: node.as_ref().map(|s| &s.id).unwrap(),
-                            body: ResponseBody::Echo {
-                                msg_id: node.as_ref().map(|s| s.next_msg_id).unwrap(),
-                                r#type: "echo_ok",
-                                in_reply_to: body["msg_id"].as_i64().unwrap(),
-                                echo: body["echo"].as_str().unwrap(),
-                            },
-                        };
-                        eprintln!("Sending {}", serde_json::to_string(&reply).unwrap());
-                        println!("{}", serde_json::to_string(&reply).unwrap());
-                    }
-                    "topology" => {
-                        if let Some(s) = node.as_mut() {
-                            s.neighbours =
-                                serde_json::from_value(body["topology"][&s.id].clone()).unwrap();
-                            eprintln!("My neighbours are {:?}", s.neighbours);
-                        }
-                        if let Some(s) = node.as_mut() {
-                            s.next_msg_id += 1;
-                        }
-                        let reply = Reply {
-                            dest: parsed["src"].as_str().unwrap(),
-                            src: node.as_ref().map(|s| &s.id).unwrap(),
-                            body: ResponseBody::Topology {
-                                msg_id: node.as_ref().map(|s| s.next_msg_id).unwrap(),
-                                r#type: "topology_ok",
-                                in_reply_to: body["msg_id"].as_i64().unwrap(),
-                            },
-                        };
-                        eprintln!("Sending {}", serde_json::to_string(&reply).unwrap());
-                        println!("{}", serde_json::to_string(&reply).unwrap());
-                    }
-                    "add" => {
-                        if let Some(s) = node.as_mut() {
-                            let mut set = s.messages.lock().unwrap();
-                            set.insert(body["element"].as_i64().unwrap());
-                            s.next_msg_id += 1;
+                info!("Initialized node {:?}", node.as_ref().map(|s| &s.id));
+                if let Some(s) = node.as_mut() {
+                    s.next_msg_id += 1;
+                }
+                let reply = Reply {
+                    dest: parsed["src"].as_str().unwrap(),
+                    src: node.as_ref().map(|s| &s.id).unwrap(),
+                    body: ResponseBody::Init {
+                        msg_id: node.as_ref().map(|s| s.next_msg_id).unwrap(),
+                        r#type: "init_ok",
+                        in_reply_to: body["msg_id"].as_i64().unwrap(),
+                    },
+                };
+                transport::send(&reply);
+            }
+            "echo" => {
+                debug!("Echoing {}", body["echo"].as_str().unwrap());
+                // node.as_mut().map(|s| s.next_msg_id += 1);
+                if let Some(s) = node.as_mut() {
+                    s.next_msg_id += 1;
+                }
+                let reply = Reply {
+                    dest: parsed["src"].as_str().unwrap(),
+                    src: node.as_ref().map(|s| &s.id).unwrap(),
+                    body: ResponseBody::Echo {
+                        msg_id: node.as_ref().map(|s| s.next_msg_id).unwrap(),
+                        r#type: "echo_ok",
+                        in_reply_to: body["msg_id"].as_i64().unwrap(),
+                        echo: body["echo"].as_str().unwrap(),
+                    },
+                };
+                transport::send(&reply);
+            }
+            "topology" => {
+                if let Some(s) = node.as_mut() {
+                    // A topology that leaves us out gives us no neighbours.
+                    let topology = body["topology"][&s.id].clone();
+                    s.neighbours = serde_json::from_value(topology).unwrap_or_default();
+                    debug!("My neighbours are {:?}", s.neighbours);
+                }
+                if let Some(s) = node.as_mut() {
+                    s.next_msg_id += 1;
+                }
+                let reply = Reply {
+                    dest: parsed["src"].as_str().unwrap(),
+                    src: node.as_ref().map(|s| &s.id).unwrap(),
+                    body: ResponseBody::Topology {
+                        msg_id: node.as_ref().map(|s| s.next_msg_id).unwrap(),
+                        r#type: "topology_ok",
+                        in_reply_to: body["msg_id"].as_i64().unwrap(),
+                    },
+                };
+                transport::send(&reply);
+            }
+            "add" => {
+                if let Some(s) = node.as_mut() {
+                    let mut set = s.messages.lock().unwrap();
+                    set.insert(body["element"].as_i64().unwrap());
+                    s.next_msg_id += 1;
 
-                            let reply = Reply {
-                                dest: parsed["src"].as_str().unwrap(),
-                                src: &s.id,
-                                body: ResponseBody::Add {
-                                    msg_id: s.next_msg_id,
-                                    r#type: "add_ok",
-                                    in_reply_to: body["msg_id"].as_i64().unwrap(),
-                                },
-                            };
-                            eprintln!("Sending {}", serde_json::to_string(&reply).unwrap());
-                            println!("{}", serde_json::to_string(&reply).unwrap());
-                        }
-                    }
-                    "replicate" => {
-                        if let Some(s) = node.as_mut() {
-                            let mut set = s.messages.lock().unwrap();
-                            let r: HashSet<i64> =
-                                serde_json::from_value(body["message"].clone()).unwrap();
-                            set.extend(&r);
-                        }
-                    }
-                    "read" => {
-                        if let Some(s) = node.as_mut() {
-                            s.next_msg_id += 1;
-                            let set = s.messages.lock().unwrap();
-                            let reply = Reply {
-                                dest: parsed["src"].as_str().unwrap(),
-                                src: &s.id,
-                                body: ResponseBody::Read {
-                                    value: &set,
-                                    r#type: "read_ok",
-                                    in_reply_to: body["msg_id"].as_i64().unwrap(),
-                                },
-                            };
-                            eprintln!("Sending {}", serde_json::to_string(&reply).unwrap());
-                            println!("{}", serde_json::to_string(&reply).unwrap());
-                        }
-                    }
-                    _ => continue,
+                    let reply = Reply {
+                        dest: parsed["src"].as_str().unwrap(),
+                        src: &s.id,
+                        body: ResponseBody::Add {
+                            msg_id: s.next_msg_id,
+                            r#type: "add_ok",
+                            in_reply_to: body["msg_id"].as_i64().unwrap(),
+                        },
+                    };
+                    transport::send(&reply);
+                }
+            }
+            "replicate" => {
+                if let Some(s) = node.as_mut() {
+                    let mut set = s.messages.lock().unwrap();
+                    let r: HashSet<i64> = serde_json::from_value(body["message"].clone()).unwrap();
+                    set.extend(&r);
+                }
+            }
+            "read" => {
+                if let Some(s) = node.as_mut() {
+                    s.next_msg_id += 1;
+                    let set = s.messages.lock().unwrap();
+                    let reply = Reply {
+                        dest: parsed["src"].as_str().unwrap(),
+                        src: &s.id,
+                        body: ResponseBody::Read {
+                            value: &set,
+                            r#type: "read_ok",
+                            in_reply_to: body["msg_id"].as_i64().unwrap(),
+                        },
+                    };
+                    transport::send(&reply);
                 }
             }
+            _ => continue,
         }
     }
+    transport::shutdown().await;
 }
@@ -0,0 +1,154 @@
+use crate::micro_ops::{changes, keys, run_transactions, TxnType};
+use crate::mvcc::Mvcc;
+use crate::transport;
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+use tracing::{debug, info};
+
+struct Node {
+    id: String,
+    next_msg_id: i64,
+    txn: ThreadMap,
+    clock: i64,
+}
+type ThreadMap = Arc<Mutex<Mvcc<i64>>>;
+
+impl Node {
+    fn new(id: String) -> Node {
+        Node {
+            id,
+            next_msg_id: 0,
+            txn: Arc::new(Mutex::new(Mvcc::default())),
+            clock: 0,
+        }
+    }
+}
+
+#[derive(Serialize)]
+enum ResponseBody<'a> {
+    #[serde(rename = "body")]
+    Init {
+        msg_id: i64,
+        r#type: &'a str,
+        in_reply_to: i64,
+    },
+    #[serde(rename = "body")]
+    Echo {
+        msg_id: i64,
+        r#type: &'a str,
+        in_reply_to: i64,
+        echo: &'a str,
+    },
+    #[serde(rename = "body")]
+    Txn {
+        r#type: &'a str,
+        in_reply_to: i64,
+        msg_id: i64,
+        txn: &'a [TxnType],
+    },
+    #[serde(rename = "body")]
+    Error {
+        r#type: &'a str,
+        in_reply_to: i64,
+        code: i64,
+        text: &'a str,
+        msg_id: i64,
+    },
+}
+
+#[derive(Serialize)]
+struct Reply<'a> {
+    src: &'a str,
+    dest: &'a str,
+    #[serde(flatten)]
+    body: ResponseBody<'a>,
+}
+
+pub async fn run() {
+    let mut node: Option<Node> = None;
+    while let Some(parsed) = transport::receive().await {
+        let _span = transport::request_span(&parsed).entered();
+        let body = &parsed["body"];
+        match body["type"].as_str().unwrap() {
+            "init" => {
+                node = Some(Node::new(body["node_id"].as_str().unwrap().to_string()));
+
+                info!("Initialized node {:?}", node.as_ref().map(|s| &s.id));
+                if let Some(s) = node.as_mut() {
+                    s.next_msg_id += 1;
+                }
+                let reply = Reply {
+                    dest: parsed["src"].as_str().unwrap(),
+                    src: node.as_ref().map(|s| &s.id).unwrap(),
+                    body: ResponseBody::Init {
+                        msg_id: node.as_ref().map(|s| s.next_msg_id).unwrap(),
+                        r#type: "init_ok",
+                        in_reply_to: body["msg_id"].as_i64().unwrap(),
+                    },
+                };
+                transport::send(&reply);
+            }
+            "echo" => {
+                debug!("Echoing {}", body["echo"].as_str().unwrap());
+                if let Some(s) = node.as_mut() {
+                    s.next_msg_id += 1;
+                }
+                let reply = Reply {
+                    dest: parsed["src"].as_str().unwrap(),
+                    src: node.as_ref().map(|s| &s.id).unwrap(),
+                    body: ResponseBody::Echo {
+                        msg_id: node.as_ref().map(|s| s.next_msg_id).unwrap(),
+                        r#type: "echo_ok",
+                        in_reply_to: body["msg_id"].as_i64().unwrap(),
+                        echo: body["echo"].as_str().unwrap(),
+                    },
+                };
+                transport::send(&reply);
+            }
+            "txn" => {
+                if let Some(s) = node.as_mut() {
+                    let mut store = s.txn.lock().unwrap();
+                    // Run against a snapshot so a failed micro-op leaves no partial writes.
+                    let start = s.clock;
+                    store.begin(&start);
+                    let before = store.snapshot(keys(&body["txn"]), &start);
+                    let mut values = before.clone();
+                    let result = run_transactions(&mut values, &body["txn"]).and_then(|txs_json| {
+                        s.clock += 1;
+                        store
+                            .commit(&start, s.clock, changes(&before, &values))
+                            .map(|_| txs_json)
+                    });
+                    store.finish(&start);
+                    s.next_msg_id += 1;
+                    let reply = match &result {
+                        Ok(txs_json) => Reply {
+                            dest: parsed["src"].as_str().unwrap(),
+                            src: &s.id,
+                            body: ResponseBody::Txn {
+                                msg_id: s.next_msg_id,
+                                r#type: "txn_ok",
+                                in_reply_to: body["msg_id"].as_i64().unwrap(),
+                                txn: txs_json,
+                            },
+                        },
+                        Err(error) => Reply {
+                            dest: parsed["src"].as_str().unwrap(),
+                            src: &s.id,
+                            body: ResponseBody::Error {
+                                msg_id: s.next_msg_id,
+                                r#type: "error",
+                                in_reply_to: body["msg_id"].as_i64().unwrap(),
+                                text: &error.text,
+                                code: error.code,
+                            },
+                        },
+                    };
+                    transport::send(&reply);
+                }
+            }
+            _ => continue,
+        }
+    }
+    transport::shutdown().await;
+}
@@ -1,13 +1,13 @@
-use serde::{Deserialize, Serialize};
-use serde_json::Value;
-use std::io;
+use crate::transport;
+use serde::Serialize;
+use tracing::{debug, info};
 
 struct Node {
     id: String,
     next_msg_id: i32,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize)]
 enum ResponseBody<'a> {
     #[serde(rename = "body")]
     InitOkBody {
@@ -24,7 +24,7 @@ enum ResponseBody<'a> {
     },
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize)]
 struct InitOk<'a> {
     src: &'a str,
     dest: &'a str,
@@ -32,61 +32,53 @@ struct InitOk<'a> {
     body: ResponseBody<'a>,
 }
 
-fn main() {
+pub async fn run() {
     let mut node: Option<Node> = None;
-    loop {
-        let mut input = String::new();
-
-        match io::stdin().read_line(&mut input) {
-            Err(error) => println!("error: {}", error),
-            Ok(_) => {
-                eprintln!("Received {}", input);
-                let parsed: Value = serde_json::from_str(&input).unwrap();
-                // let obj: Map<String, Value> = parsed.as_object().unwrap().to_owned();
-                let body = &parsed["body"];
-                // eprintln!("type is {:?}", &body["type"].as_str().unwrap());
-                match body["type"].as_str().unwrap() {
-                    "init" => {
-                        node = Some(Node {
-                            id: body["node_id"].as_str().unwrap().to_string(),
-                            next_msg_id: 0,
-                        });
-                        eprintln!("Initialized node {:?}", node.as_ref().map(|s| &s.id));
-                        if let Some(s) = node.as_mut() {
-                            s.next_msg_id += 1;
-                        }
-                        let reply = InitOk {
-                            dest: parsed["src"].as_str().unwrap(),
-                            src: node.as_ref().map(|s| &s.id).unwrap(),
-                            body: ResponseBody::InitOkBody {
-                                msg_id: node.as_ref().map(|s| s.next_msg_id).unwrap(),
-                                r#type: "init_ok",
-                                in_reply_to: body["msg_id"].as_i64().unwrap(),
-                            },
-                        };
-                        println!("{}", serde_json::to_string(&reply).unwrap());
-                    }
-                    "echo" => {
-                        eprintln!("Echoing {}", body["echo"].as_str().unwrap());
-                        // node.as_mut().map(|s| s.next_msg_id += 1);
-                        if let Some(s) = node.as_mut() {
-                            s.next_msg_id += 1;
-                        }
-                        let reply = InitOk {
-                            dest: parsed["src"].as_str().unwrap(),
-                            src: node.as_ref().map(|s| &s.id).unwrap(),
-                            body: ResponseBody::EchoOkBody {
-                                msg_id: node.as_ref().map(|s| s.next_msg_id).unwrap(),
-                                r#type: "echo_ok",
-                                in_reply_to: body["msg_id"].as_i64().unwrap(),
-                                echo: body["echo"].as_str().unwrap(),
-                            },
-                        };
-                        println!("{}", serde_json::to_string(&reply).unwrap());
-                    }
-                    _ => continue,
+    while let Some(parsed) = transport::receive().await {
+        let _span = transport::request_span(&parsed).entered();
+        // let obj: Map<String, Value> = parsed.as_object().unwrap().to_owned();
+        let body = &parsed["body"];
+        match body["type"].as_str().unwrap() {
+            "init" => {
+                node = Some(Node {
+                    id: body["node_id"].as_str().unwrap().to_string(),
+                    next_msg_id: 0,
+                });
+                info!("Initialized node {:?}", node.as_ref().map(|s| &s.id));
+                if let Some(s) = node.as_mut() {
+                    s.next_msg_id += 1;
+                }
+                let reply = InitOk {
+                    dest: parsed["src"].as_str().unwrap(),
+                    src: node.as_ref().map(|s| &s.id).unwrap(),
+                    body: ResponseBody::InitOkBody {
+                        msg_id: node.as_ref().map(|s| s.next_msg_id).unwrap(),
+                        r#type: "init_ok",
+                        in_reply_to: body["msg_id"].as_i64().unwrap(),
+                    },
+                };
+                transport::send(&reply);
+            }
+            "echo" => {
+                debug!("Echoing {}", body["echo"].as_str().unwrap());
+                // node.as_mut().map(|s| s.next_msg_id += 1);
+                if let Some(s) = node.as_mut() {
+                    s.next_msg_id += 1;
                 }
+                let reply = InitOk {
+                    dest: parsed["src"].as_str().unwrap(),
+                    src: node.as_ref().map(|s| &s.id).unwrap(),
+                    body: ResponseBody::EchoOkBody {
+                        msg_id: node.as_ref().map(|s| s.next_msg_id).unwrap(),
+                        r#type: "echo_ok",
+                        in_reply_to: body["msg_id"].as_i64().unwrap(),
+                        echo: body["echo"].as_str().unwrap(),
+                    },
+                };
+                transport::send(&reply);
             }
+            _ => continue,
         }
     }
+    transport::shutdown().await;
 }
@@ -0,0 +1,149 @@
+use crate::{config, transport};
+use serde_json::json;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Instant;
+use tokio::time::{sleep, Duration};
+
+pub const HEARTBEAT_MS: u64 = 1000;
+// Suspicion level above which a peer is treated as down. A phi of 8 means the
+// odds of the next heartbeat still being on its way are about 1 in 10^8.
+pub const THRESHOLD: f64 = 8.0;
+const MAX_SAMPLES: usize = 100;
+// Floors the spread of the arrival intervals, so a run of perfectly regular
+// heartbeats does not make the detector jump at the first late one.
+const MIN_STD_DEV_MS: f64 = 200.0;
+
+// Phi-accrual failure detection. Rather than a yes/no timeout, each peer gets
+// a suspicion level phi that grows the longer it stays silent, scaled by how
+// regularly its heartbeats arrive. Any message from a peer shows it is alive,
+// but only heartbeats and probe replies, which come at a steady pace, are
+// sampled: a burst of traffic would otherwise drag the mean interval towards
+// 0 and get the peer suspected at its next ordinary pause.
+struct Arrivals {
+    // The last message of any kind.
+    last: Instant,
+    // The last heartbeat, which the next interval is measured from.
+    beat: Instant,
+    intervals: VecDeque<f64>,
+}
+
+impl Arrivals {
+    fn phi(&self) -> f64 {
+        let n = self.intervals.len() as f64;
+        let mean = self.intervals.iter().sum::<f64>() / n;
+        let variance = self
+            .intervals
+            .iter()
+            .map(|i| (i - mean).powi(2))
+            .sum::<f64>()
+            / n;
+        let std_dev = variance.sqrt().max(MIN_STD_DEV_MS);
+        let elapsed = self.last.elapsed().as_millis() as f64;
+        // Logistic approximation of the normal distribution's tail.
+        let y = (elapsed - mean) / std_dev;
+        let e = (-y * (1.5976 + 0.070566 * y * y)).exp();
+        -(e / (1.0 + e)).log10()
+    }
+}
+
+pub struct FailureDetector {
+    peers: HashMap<String, Arrivals>,
+}
+
+pub type ThreadDetector = Arc<Mutex<FailureDetector>>;
+
+impl FailureDetector {
+    // Every peer starts out as if it had just sent a heartbeat, so one that
+    // never shows up is eventually suspected too.
+    pub fn new(id: &str, node_ids: &[String]) -> FailureDetector {
+        let now = Instant::now();
+        FailureDetector {
+            peers: node_ids
+                .iter()
+                .filter(|n| *n != id)
+                .map(|n| {
+                    let arrivals = Arrivals {
+                        last: now,
+                        beat: now,
+                        intervals: VecDeque::from([interval().as_millis() as f64]),
+                    };
+                    (n.clone(), arrivals)
+                })
+                .collect(),
+        }
+    }
+
+    pub fn heartbeat(&mut self, peer: &str) {
+        if let Some(arrivals) = self.peers.get_mut(peer) {
+            let now = Instant::now();
+            arrivals
+                .intervals
+                .push_back(now.duration_since(arrivals.beat).as_millis() as f64);
+            if arrivals.intervals.len() > MAX_SAMPLES {
+                arrivals.intervals.pop_front();
+            }
+            arrivals.beat = now;
+            arrivals.last = now;
+        }
+    }
+
+    // Any other message from `peer`: it is alive, but the gap is not sampled.
+    pub fn heard(&mut self, peer: &str) {
+        if let Some(arrivals) = self.peers.get_mut(peer) {
+            arrivals.last = Instant::now();
+        }
+    }
+
+    // How strongly we suspect `peer` is down. Anything that is not a peer,
+    // such as a client or a Maelstrom service, is never suspected.
+    pub fn phi(&self, peer: &str) -> f64 {
+        self.peers.get(peer).map_or(0.0, |arrivals| arrivals.phi())
+    }
+
+    pub fn suspected(&self, peer: &str) -> bool {
+        self.phi(peer) > THRESHOLD
+    }
+}
+
+pub fn interval() -> Duration {
+    config::heartbeat_interval(Duration::from_millis(HEARTBEAT_MS))
+}
+
+// When each destination was last sent anything, so heartbeats only go to
+// peers that would otherwise not hear from us.
+fn sent_at() -> &'static Mutex<HashMap<String, Instant>> {
+    static SENT_AT: OnceLock<Mutex<HashMap<String, Instant>>> = OnceLock::new();
+    SENT_AT.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+// Called by `transport::send` for every outgoing message.
+pub fn sent(dest: &str) {
+    let mut sent_at = sent_at().lock().unwrap();
+    match sent_at.get_mut(dest) {
+        Some(at) => *at = Instant::now(),
+        None => {
+            sent_at.insert(dest.to_string(), Instant::now());
+        }
+    }
+}
+
+// Sends a heartbeat every interval to each peer we have not sent anything
+// else to in that time, so a busy cluster adds no heartbeat traffic.
+pub async fn heartbeat(src: String, node_ids: Vec<String>) {
+    loop {
+        let quiet: Vec<&String> = {
+            let sent_at = sent_at().lock().unwrap();
+            node_ids
+                .iter()
+                .filter(|n| **n != src)
+                .filter(|n| sent_at.get(*n).is_none_or(|at| at.elapsed() >= interval()))
+                .collect()
+        };
+        for dest in quiet {
+            let message = json!({"src": src, "dest": dest, "body": {"type": "heartbeat"}});
+            transport::send(&message);
+        }
+        sleep(interval()).await;
+    }
+}
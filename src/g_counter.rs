@@ -0,0 +1,492 @@
+use crate::{config, transport};
+use serde::Serialize;
+use serde_json::Value;
+use std::cmp::max;
+use std::collections::HashMap;
+use std::sync::mpsc;
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use tokio::time::{sleep, Duration};
+use tracing::{debug, info};
+
+struct Node {
+    id: String,
+    neighbours: Vec<String>,
+    next_msg_id: MessageCounter,
+    counter: ThreadMap,
+    senders: MessageHash,
+    total: ThreadTotal,
+    seq_kv: bool,
+}
+
+type ThreadMap = Arc<Mutex<HashMap<String, i64>>>;
+type MessageCounter = Arc<Mutex<i64>>;
+type MessageHash = Arc<Mutex<HashMap<i64, Sender<Value>>>>;
+// This node's total as last known to be in seq-kv, or `None` until it is read
+// back. Held for the whole of an add, so a node's adds go one at a time.
+type ThreadTotal = Arc<tokio::sync::Mutex<Option<i64>>>;
+const KV: &str = "seq-kv";
+const SENTINEL: &str = "sentinel";
+const TIMEOUT: i64 = 0;
+const TEMPORARILY_UNAVAILABLE: i64 = 11;
+const KEY_DOES_NOT_EXIST: i64 = 20;
+// Attempts at each seq-kv step before the request fails, with a backoff that
+// grows by BACKOFF after each failed one.
+const MAX_ATTEMPTS: u32 = 5;
+const BACKOFF: Duration = Duration::from_millis(50);
+
+async fn replicate(dest: String, src: String, messges: ThreadMap) {
+    loop {
+        {
+            let hash = messges.lock().unwrap();
+            let message = Reply {
+                dest: &dest,
+                src: &src,
+                body: ResponseBody::Replicate {
+                    r#type: "replicate",
+                    msg: &hash,
+                },
+            };
+            transport::send(&message);
+        }
+        sleep(config::gossip_interval(Duration::from_millis(5000))).await;
+    }
+}
+
+fn next_id(next_msg_id: &MessageCounter) -> i64 {
+    let mut msg_id = next_msg_id.lock().unwrap();
+    *msg_id += 1;
+    *msg_id
+}
+
+// Sends a request to seq-kv and waits for the body of its reply.
+fn send_kv(
+    src: &str,
+    next_msg_id: &MessageCounter,
+    sender_hash: &MessageHash,
+    request: impl FnOnce(i64) -> ResponseBody<'static>,
+) -> Result<Value, mpsc::RecvTimeoutError> {
+    let (tx, rx): (Sender<Value>, Receiver<Value>) = mpsc::channel();
+    let wait_key = next_id(next_msg_id);
+    let message = Reply {
+        dest: KV,
+        src,
+        body: request(wait_key),
+    };
+    let mut lookup = sender_hash.lock().unwrap();
+    lookup.insert(wait_key, tx);
+    drop(lookup);
+    transport::send(&message);
+    let timeout = config::rpc_timeout(Duration::from_secs(5));
+    let reply = tokio::task::block_in_place(|| rx.recv_timeout(timeout));
+    let mut lookup = sender_hash.lock().unwrap();
+    lookup.remove(&wait_key);
+    reply
+}
+
+// Reads a node's contribution, treating a key nobody has written yet as zero.
+fn read_kv(
+    src: &str,
+    key: String,
+    next_msg_id: &MessageCounter,
+    sender_hash: &MessageHash,
+) -> Option<i64> {
+    let reply = send_kv(src, next_msg_id, sender_hash, |msg_id| {
+        ResponseBody::KvRead {
+            r#type: "read",
+            msg_id,
+            key,
+        }
+    });
+    match reply {
+        Ok(body) if body["type"] == "read_ok" => body["value"].as_i64(),
+        Ok(body) if body["code"] == KEY_DOES_NOT_EXIST => Some(0),
+        _ => None,
+    }
+}
+
+// Runs a seq-kv step until it succeeds or MAX_ATTEMPTS have failed.
+async fn retry<T>(mut attempt: impl FnMut() -> Option<T>) -> Option<T> {
+    for n in 1..=MAX_ATTEMPTS {
+        if let Some(result) = attempt() {
+            return Some(result);
+        }
+        sleep(BACKOFF * n).await;
+    }
+    None
+}
+
+fn error(code: i64, text: &'static str, in_reply_to: i64, msg_id: i64) -> ResponseBody<'static> {
+    ResponseBody::Error {
+        r#type: "error",
+        in_reply_to,
+        code,
+        text,
+        msg_id,
+    }
+}
+
+fn unavailable(in_reply_to: i64, msg_id: i64) -> ResponseBody<'static> {
+    error(
+        TEMPORARILY_UNAVAILABLE,
+        "seq-kv did not answer",
+        in_reply_to,
+        msg_id,
+    )
+}
+
+// Adds `delta` by compare-and-setting this node's key from its total to the
+// total plus `delta`. Only this node writes the key, so the total is tracked
+// here and read back from seq-kv only after a CAS goes wrong. Repeating a CAS
+// cannot add twice: once one lands the others fail their precondition, and
+// the read that follows finds the total it set.
+async fn kv_add(
+    src: String,
+    next_msg_id: MessageCounter,
+    sender_hash: MessageHash,
+    total: ThreadTotal,
+    delta: i64,
+    dest: String,
+    incoming_id: i64,
+) {
+    let mut total = total.lock().await;
+    // What the last CAS left unanswered would have set the key to, if it did.
+    let mut unanswered = None;
+    let mut added = false;
+    for n in 1..=MAX_ATTEMPTS {
+        let from = match *total {
+            Some(from) => Some(from),
+            None => read_kv(&src, src.clone(), &next_msg_id, &sender_hash),
+        };
+        if let Some(from) = from {
+            if unanswered == Some(from) {
+                *total = Some(from);
+                added = true;
+                break;
+            }
+            let key = src.clone();
+            let reply = send_kv(&src, &next_msg_id, &sender_hash, |msg_id| {
+                ResponseBody::Cas {
+                    r#type: "cas",
+                    msg_id,
+                    key,
+                    from,
+                    to: from + delta,
+                    create_if_not_exists: true,
+                }
+            });
+            match reply {
+                Ok(body) if body["type"] == "cas_ok" => {
+                    *total = Some(from + delta);
+                    added = true;
+                    break;
+                }
+                Ok(_) => *total = None,
+                Err(_) => {
+                    *total = None;
+                    unanswered = Some(from + delta);
+                }
+            }
+        }
+        sleep(BACKOFF * n).await;
+    }
+    let msg_id = next_id(&next_msg_id);
+    let reply = Reply {
+        dest: &dest,
+        src: &src,
+        body: match (added, unanswered) {
+            (true, _) => ResponseBody::Add {
+                msg_id,
+                r#type: "add_ok",
+                in_reply_to: incoming_id,
+            },
+            // The unanswered CAS may still land, so the add may yet happen.
+            (false, Some(_)) => error(
+                TIMEOUT,
+                "seq-kv did not confirm the add",
+                incoming_id,
+                msg_id,
+            ),
+            (false, None) => unavailable(incoming_id, msg_id),
+        },
+    };
+    transport::send(&reply);
+}
+
+// Sums every node's key. seq-kv may serve stale reads, so we first write a
+// value nobody has written before; the reads that follow must be ordered after
+// it.
+async fn kv_read(
+    src: String,
+    nodes: Vec<String>,
+    next_msg_id: MessageCounter,
+    sender_hash: MessageHash,
+    dest: String,
+    incoming_id: i64,
+) {
+    // Without the sentinel in place the reads could be stale, so failing to
+    // write it fails the read.
+    let sentinel = format!("{}-{}", src, next_id(&next_msg_id));
+    let written = retry(|| {
+        let reply = send_kv(&src, &next_msg_id, &sender_hash, |msg_id| {
+            ResponseBody::Write {
+                r#type: "write",
+                msg_id,
+                key: SENTINEL.to_string(),
+                value: sentinel.clone(),
+            }
+        });
+        reply.ok().filter(|body| body["type"] == "write_ok")
+    })
+    .await;
+    let mut value = written.map(|_| 0);
+    for n in nodes {
+        value = match value {
+            Some(sum) => retry(|| read_kv(&src, n.clone(), &next_msg_id, &sender_hash))
+                .await
+                .map(|v| sum + v),
+            None => break,
+        };
+    }
+    let msg_id = next_id(&next_msg_id);
+    let reply = Reply {
+        dest: &dest,
+        src: &src,
+        body: match value {
+            Some(value) => ResponseBody::Read {
+                value,
+                r#type: "read_ok",
+                in_reply_to: incoming_id,
+            },
+            None => unavailable(incoming_id, msg_id),
+        },
+    };
+    transport::send(&reply);
+}
+
+impl Node {
+    fn new(id: String, neighbours: Vec<String>, seq_kv: bool) -> Node {
+        let mut node = Node {
+            id: id.clone(),
+            neighbours,
+            next_msg_id: Arc::new(Mutex::new(0)),
+            counter: Arc::new(Mutex::new(HashMap::from([(id, 0)]))),
+            senders: Arc::new(Mutex::new(HashMap::new())),
+            total: Arc::new(tokio::sync::Mutex::new(None)),
+            seq_kv,
+        };
+        if !seq_kv {
+            node.replicate_neighbours();
+        }
+        node
+    }
+    fn replicate_neighbours(&mut self) {
+        for n in &self.neighbours {
+            {
+                let mut hash = self.counter.lock().unwrap();
+                hash.insert(n.to_string(), 0);
+            }
+            tokio::spawn(replicate(n.clone(), self.id.clone(), self.counter.clone()));
+        }
+    }
+}
+
+#[derive(Serialize)]
+enum ResponseBody<'a> {
+    #[serde(rename = "body")]
+    Init {
+        msg_id: i64,
+        r#type: &'a str,
+        in_reply_to: i64,
+    },
+    #[serde(rename = "body")]
+    Echo {
+        msg_id: i64,
+        r#type: &'a str,
+        in_reply_to: i64,
+        echo: &'a str,
+    },
+    #[serde(rename = "body")]
+    Replicate {
+        r#type: &'a str,
+        msg: &'a HashMap<String, i64>,
+    },
+    #[serde(rename = "body")]
+    Add {
+        r#type: &'a str,
+        in_reply_to: i64,
+        msg_id: i64,
+    },
+    #[serde(rename = "body")]
+    Read {
+        r#type: &'a str,
+        value: i64,
+        in_reply_to: i64,
+    },
+    #[serde(rename = "body")]
+    KvRead {
+        r#type: &'a str,
+        msg_id: i64,
+        key: String,
+    },
+    #[serde(rename = "body")]
+    Write {
+        r#type: &'a str,
+        msg_id: i64,
+        key: String,
+        value: String,
+    },
+    #[serde(rename = "body")]
+    Cas {
+        r#type: &'a str,
+        msg_id: i64,
+        key: String,
+        from: i64,
+        to: i64,
+        create_if_not_exists: bool,
+    },
+    #[serde(rename = "body")]
+    Error {
+        r#type: &'a str,
+        in_reply_to: i64,
+        code: i64,
+        text: &'a str,
+        msg_id: i64,
+    },
+}
+
+#[derive(Serialize)]
+struct Reply<'a> {
+    src: &'a str,
+    dest: &'a str,
+    #[serde(flatten)]
+    body: ResponseBody<'a>,
+}
+
+pub async fn run() {
+    // `--backend seq-kv` stores each node's count in Maelstrom's seq-kv service
+    // instead of gossiping it between peers.
+    let seq_kv = config::backend() == KV;
+    let mut node: Option<Node> = None;
+    while let Some(parsed) = transport::receive().await {
+        let _span = transport::request_span(&parsed).entered();
+        let body = &parsed["body"];
+        match body["type"].as_str().unwrap() {
+            "init" => {
+                node = Some(Node::new(
+                    body["node_id"].as_str().unwrap().to_string(),
+                    serde_json::from_value(body["node_ids"].clone()).unwrap(),
+                    seq_kv,
+                ));
+
+                info!("Initialized node {:?}", node.as_ref().map(|s| &s.id));
+                let reply = Reply {
+                    dest: parsed["src"].as_str().unwrap(),
+                    src: node.as_ref().map(|s| &s.id).unwrap(),
+                    body: ResponseBody::Init {
+                        msg_id: node.as_ref().map(|s| next_id(&s.next_msg_id)).unwrap(),
+                        r#type: "init_ok",
+                        in_reply_to: body["msg_id"].as_i64().unwrap(),
+                    },
+                };
+                transport::send(&reply);
+            }
+            "echo" => {
+                debug!("Echoing {}", body["echo"].as_str().unwrap());
+                let reply = Reply {
+                    dest: parsed["src"].as_str().unwrap(),
+                    src: node.as_ref().map(|s| &s.id).unwrap(),
+                    body: ResponseBody::Echo {
+                        msg_id: node.as_ref().map(|s| next_id(&s.next_msg_id)).unwrap(),
+                        r#type: "echo_ok",
+                        in_reply_to: body["msg_id"].as_i64().unwrap(),
+                        echo: body["echo"].as_str().unwrap(),
+                    },
+                };
+                transport::send(&reply);
+            }
+            "add" => {
+                if let Some(s) = node.as_ref().filter(|s| s.seq_kv) {
+                    transport::spawn(kv_add(
+                        s.id.clone(),
+                        s.next_msg_id.clone(),
+                        s.senders.clone(),
+                        s.total.clone(),
+                        body["delta"].as_i64().unwrap(),
+                        parsed["src"].as_str().unwrap().to_string(),
+                        body["msg_id"].as_i64().unwrap(),
+                    ));
+                } else if let Some(s) = node.as_mut() {
+                    let current;
+                    let mut hash = s.counter.lock().unwrap();
+                    {
+                        current = *hash.get(&s.id).unwrap();
+                    }
+                    hash.insert(s.id.clone(), current + body["delta"].as_i64().unwrap());
+
+                    let reply = Reply {
+                        dest: parsed["src"].as_str().unwrap(),
+                        src: &s.id,
+                        body: ResponseBody::Add {
+                            msg_id: next_id(&s.next_msg_id),
+                            r#type: "add_ok",
+                            in_reply_to: body["msg_id"].as_i64().unwrap(),
+                        },
+                    };
+                    transport::send(&reply);
+                }
+            }
+            "replicate" => {
+                if let Some(s) = node.as_mut() {
+                    let r: HashMap<String, i64> =
+                        serde_json::from_value(body["msg"].clone()).unwrap();
+                    let mut hash = s.counter.lock().unwrap();
+                    for k in r.keys() {
+                        let current: i64 = match hash.get(k) {
+                            Some(val) => *val,
+                            None => 0,
+                        };
+                        let value = *r.get(k).unwrap();
+                        hash.insert(k.to_string(), max(current, value));
+                    }
+                }
+            }
+            "read" => {
+                if let Some(s) = node.as_ref().filter(|s| s.seq_kv) {
+                    transport::spawn(kv_read(
+                        s.id.clone(),
+                        s.neighbours.clone(),
+                        s.next_msg_id.clone(),
+                        s.senders.clone(),
+                        parsed["src"].as_str().unwrap().to_string(),
+                        body["msg_id"].as_i64().unwrap(),
+                    ));
+                } else if let Some(s) = node.as_mut() {
+                    next_id(&s.next_msg_id);
+                    let hash = s.counter.lock().unwrap();
+                    let reply = Reply {
+                        dest: parsed["src"].as_str().unwrap(),
+                        src: &s.id,
+                        body: ResponseBody::Read {
+                            value: hash.values().sum(),
+                            r#type: "read_ok",
+                            in_reply_to: body["msg_id"].as_i64().unwrap(),
+                        },
+                    };
+                    transport::send(&reply);
+                }
+            }
+            "read_ok" | "write_ok" | "cas_ok" | "error" => {
+                let msg_id = body["in_reply_to"].as_i64().unwrap();
+                if let Some(s) = node.as_ref() {
+                    let lookup = s.senders.lock().unwrap();
+                    if lookup.contains_key(&msg_id) {
+                        lookup[&msg_id].send(body.to_owned()).unwrap();
+                    }
+                }
+            }
+            _ => continue,
+        }
+    }
+    transport::shutdown().await;
+}
@@ -0,0 +1,587 @@
+use crate::{config, transport};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::mpsc;
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use tokio::time::{sleep, Duration};
+use tracing::info;
+
+struct Node {
+    id: String,
+    node_ids: Vec<String>,
+    next_msg_id: MessageCounter,
+    senders: MessageHash,
+    logs: ThreadLogs,
+    lin_kv: bool,
+}
+
+type MessageCounter = Arc<Mutex<i64>>;
+type MessageHash = Arc<Mutex<HashMap<i64, Sender<Value>>>>;
+type ThreadLogs = Arc<Mutex<Logs>>;
+type Offsets = HashMap<String, i64>;
+type Messages = HashMap<String, Vec<(i64, Value)>>;
+const KV: &str = "lin-kv";
+const KEY_DOES_NOT_EXIST: i64 = 20;
+const TIMEOUT: i64 = 0;
+const TEMPORARILY_UNAVAILABLE: i64 = 11;
+const PRECONDITION_FAILED: i64 = 22;
+// Attempts at a lin-kv step that gets no answer, or an error other than a lost
+// race, before the client is told to try again, with a backoff that grows by
+// BACKOFF after each.
+const MAX_ATTEMPTS: u32 = 5;
+const BACKOFF: Duration = Duration::from_millis(50);
+// Upper bound on messages returned per key by a single poll.
+const POLL_LIMIT: i64 = 32;
+
+// Logs for the keys this node owns. Offsets are indexes into the log, so they
+// are monotonic and dense per key.
+#[derive(Default)]
+struct Logs {
+    logs: HashMap<String, Vec<Value>>,
+    committed: Offsets,
+}
+
+impl Logs {
+    fn append(&mut self, key: &str, msg: Value) -> i64 {
+        let log = self.logs.entry(key.to_string()).or_default();
+        log.push(msg);
+        log.len() as i64 - 1
+    }
+
+    fn poll(&self, offsets: &Offsets) -> Messages {
+        offsets
+            .iter()
+            .filter_map(|(k, from)| {
+                self.logs.get(k).map(|log| {
+                    let msgs = log
+                        .iter()
+                        .enumerate()
+                        .skip(*from as usize)
+                        .take(POLL_LIMIT as usize)
+                        .map(|(i, m)| (i as i64, m.clone()))
+                        .collect();
+                    (k.clone(), msgs)
+                })
+            })
+            .collect()
+    }
+
+    fn commit(&mut self, offsets: &Offsets) {
+        for (k, v) in offsets {
+            let current = self.committed.entry(k.clone()).or_insert(*v);
+            *current = (*current).max(*v);
+        }
+    }
+
+    fn list_committed(&self, keys: &[String]) -> Offsets {
+        keys.iter()
+            .filter_map(|k| self.committed.get(k).map(|v| (k.clone(), *v)))
+            .collect()
+    }
+}
+
+fn next_id(next_msg_id: &MessageCounter) -> i64 {
+    let mut msg_id = next_msg_id.lock().unwrap();
+    *msg_id += 1;
+    *msg_id
+}
+
+// Sends a request and waits for the body of its reply.
+fn send_rpc(
+    src: &str,
+    dest: &str,
+    next_msg_id: &MessageCounter,
+    sender_hash: &MessageHash,
+    mut body: Value,
+) -> Result<Value, mpsc::RecvTimeoutError> {
+    let (tx, rx): (Sender<Value>, Receiver<Value>) = mpsc::channel();
+    let wait_key = next_id(next_msg_id);
+    body["msg_id"] = Value::from(wait_key);
+    let message = Reply {
+        dest,
+        src,
+        body: ResponseBody::Request(body),
+    };
+    let mut lookup = sender_hash.lock().unwrap();
+    lookup.insert(wait_key, tx);
+    drop(lookup);
+    transport::send(&message);
+    let timeout = config::rpc_timeout(Duration::from_secs(5));
+    let reply = tokio::task::block_in_place(|| rx.recv_timeout(timeout));
+    let mut lookup = sender_hash.lock().unwrap();
+    lookup.remove(&wait_key);
+    reply
+}
+
+fn reply(src: &str, dest: &str, next_msg_id: &MessageCounter, incoming_id: i64, body: Value) {
+    let mut body = body;
+    body["msg_id"] = Value::from(next_id(next_msg_id));
+    body["in_reply_to"] = Value::from(incoming_id);
+    let reply = Reply {
+        dest,
+        src,
+        body: ResponseBody::Request(body),
+    };
+    transport::send(&reply);
+}
+
+// FNV-1a, so every node agrees on which node owns a key.
+fn owner<'a>(key: &str, node_ids: &'a [String]) -> &'a str {
+    let hash = key.bytes().fold(0xcbf29ce484222325u64, |h, b| {
+        (h ^ b as u64).wrapping_mul(0x100000001b3)
+    });
+    &node_ids[(hash % node_ids.len() as u64) as usize]
+}
+
+#[derive(Clone)]
+struct Context {
+    id: String,
+    node_ids: Vec<String>,
+    next_msg_id: MessageCounter,
+    senders: MessageHash,
+    logs: ThreadLogs,
+}
+
+impl Context {
+    // Err holds the code to answer with: the owner's own, or timeout if it
+    // never answered, since the request may still have taken effect.
+    fn rpc(&self, dest: &str, body: Value) -> Result<Value, i64> {
+        match send_rpc(&self.id, dest, &self.next_msg_id, &self.senders, body) {
+            Ok(body) if body["type"] == "error" => Err(body["code"].as_i64().unwrap_or(TIMEOUT)),
+            Ok(body) => Ok(body),
+            Err(_) => Err(TIMEOUT),
+        }
+    }
+
+    fn kv_read(&self, key: String) -> Result<Option<Value>, ()> {
+        match send_rpc(
+            &self.id,
+            KV,
+            &self.next_msg_id,
+            &self.senders,
+            json!({"type": "read", "key": key}),
+        ) {
+            Ok(body) if body["type"] == "read_ok" => Ok(Some(body["value"].to_owned())),
+            Ok(body) if body["code"] == KEY_DOES_NOT_EXIST => Ok(None),
+            _ => Err(()),
+        }
+    }
+
+    // Ok(false) if the key no longer holds `from`; Err if we cannot tell
+    // whether the CAS happened.
+    fn kv_cas(&self, key: String, from: Value, to: Value) -> Result<bool, ()> {
+        match send_rpc(
+            &self.id,
+            KV,
+            &self.next_msg_id,
+            &self.senders,
+            json!({"type": "cas", "key": key, "from": from, "to": to, "create_if_not_exists": true}),
+        ) {
+            Ok(body) if body["type"] == "cas_ok" => Ok(true),
+            Ok(body) if body["code"] == PRECONDITION_FAILED => Ok(false),
+            _ => Err(()),
+        }
+    }
+
+    // Raises the counter at `key` to at least `to`. False if lin-kv stopped
+    // answering first.
+    async fn raise(&self, key: String, to: i64) -> bool {
+        let mut failures = 0;
+        while failures < MAX_ATTEMPTS {
+            let current = match self.kv_read(key.clone()) {
+                Ok(current) => current,
+                Err(_) => {
+                    failures += 1;
+                    sleep(BACKOFF * failures).await;
+                    continue;
+                }
+            };
+            if current.as_ref().and_then(|c| c.as_i64()).unwrap_or(-1) >= to {
+                return true;
+            }
+            match self.kv_cas(key.clone(), current.unwrap_or(Value::Null), Value::from(to)) {
+                Ok(true) => return true,
+                Ok(false) => continue,
+                Err(_) => {
+                    failures += 1;
+                    sleep(BACKOFF * failures).await;
+                }
+            }
+        }
+        false
+    }
+
+    // Splits keyed request parts by the node that owns each key.
+    fn by_owner<T>(
+        &self,
+        parts: impl Iterator<Item = (String, T)>,
+    ) -> HashMap<String, Vec<(String, T)>> {
+        let mut owners: HashMap<String, Vec<(String, T)>> = HashMap::new();
+        for (k, v) in parts {
+            owners
+                .entry(owner(&k, &self.node_ids).to_string())
+                .or_default()
+                .push((k, v));
+        }
+        owners
+    }
+}
+
+// Key-sharded mode: every key has one owner that orders its log, so appends to
+// different keys never contend. Requests for keys we don't own are forwarded.
+async fn sharded(ctx: Context, body: Value, dest: String) {
+    let incoming_id = body["msg_id"].as_i64().unwrap();
+    let result = match body["type"].as_str().unwrap() {
+        "send" => {
+            let key = body["key"].as_str().unwrap();
+            ctx.rpc(owner(key, &ctx.node_ids), body.clone())
+                .map(|r| json!({"type": "send_ok", "offset": r["offset"]}))
+        }
+        // A key whose owner did not answer fails the whole poll rather than
+        // reading as empty.
+        "poll" => {
+            let offsets: Offsets = serde_json::from_value(body["offsets"].clone()).unwrap();
+            let mut msgs: Messages = HashMap::new();
+            let mut ok = true;
+            for (node, parts) in ctx.by_owner(offsets.into_iter()) {
+                let parts: Offsets = parts.into_iter().collect();
+                if node == ctx.id {
+                    msgs.extend(ctx.logs.lock().unwrap().poll(&parts));
+                } else {
+                    match ctx.rpc(&node, json!({"type": "poll", "offsets": parts})) {
+                        Ok(r) => {
+                            let m: Messages = serde_json::from_value(r["msgs"].clone()).unwrap();
+                            msgs.extend(m);
+                        }
+                        Err(_) => ok = false,
+                    }
+                }
+            }
+            match ok {
+                true => Ok(json!({"type": "poll_ok", "msgs": msgs})),
+                _ => Err(TEMPORARILY_UNAVAILABLE),
+            }
+        }
+        "commit_offsets" => {
+            let offsets: Offsets = serde_json::from_value(body["offsets"].clone()).unwrap();
+            let mut ok = true;
+            for (node, parts) in ctx.by_owner(offsets.into_iter()) {
+                let parts: Offsets = parts.into_iter().collect();
+                if node == ctx.id {
+                    ctx.logs.lock().unwrap().commit(&parts);
+                } else {
+                    ok &= ctx
+                        .rpc(&node, json!({"type": "commit_offsets", "offsets": parts}))
+                        .is_ok();
+                }
+            }
+            match ok {
+                true => Ok(json!({"type": "commit_offsets_ok"})),
+                _ => Err(TEMPORARILY_UNAVAILABLE),
+            }
+        }
+        _ => {
+            let keys: Vec<String> = serde_json::from_value(body["keys"].clone()).unwrap();
+            let mut offsets: Offsets = HashMap::new();
+            let mut ok = true;
+            for (node, parts) in ctx.by_owner(keys.into_iter().map(|k| (k, ()))) {
+                let keys: Vec<String> = parts.into_iter().map(|(k, _)| k).collect();
+                if node == ctx.id {
+                    offsets.extend(ctx.logs.lock().unwrap().list_committed(&keys));
+                } else {
+                    match ctx.rpc(
+                        &node,
+                        json!({"type": "list_committed_offsets", "keys": keys}),
+                    ) {
+                        Ok(r) => {
+                            let o: Offsets = serde_json::from_value(r["offsets"].clone()).unwrap();
+                            offsets.extend(o);
+                        }
+                        Err(_) => ok = false,
+                    }
+                }
+            }
+            match ok {
+                true => Ok(json!({"type": "list_committed_offsets_ok", "offsets": offsets})),
+                _ => Err(TEMPORARILY_UNAVAILABLE),
+            }
+        }
+    };
+    reply_or_error(&ctx, &dest, incoming_id, result);
+}
+
+// lin-kv mode: any node serves any key. Messages and committed offsets live
+// in lin-kv. A message takes its offset by creating the slot `msg-{key}-{n}`
+// with a CAS that only succeeds if the slot is still empty, so an offset is
+// never taken without its message being there: a poll reading slots in order
+// cannot meet a gap. `offset-{key}` only records how far the slots are known
+// to be taken, so a send need not probe them all from 0.
+async fn linearizable(ctx: Context, body: Value, dest: String) {
+    let incoming_id = body["msg_id"].as_i64().unwrap();
+    let result = match body["type"].as_str().unwrap() {
+        "send" => {
+            let key = body["key"].as_str().unwrap();
+            // Tagged so that after a CAS we got no answer to, we can tell
+            // whether the slot holds our message or someone else's.
+            let tag = format!("{}-{}", ctx.id, next_id(&ctx.next_msg_id));
+            let slot = json!({"msg": body["msg"], "by": tag});
+            let mut next = match ctx.kv_read(format!("offset-{}", key)) {
+                Ok(Some(taken)) => taken.as_i64().unwrap() + 1,
+                _ => 0,
+            };
+            let mut offset = None;
+            let mut failures = 0;
+            // Whether a CAS on the current slot went unanswered. It may have
+            // landed, or still land, so the slot must be read before it is
+            // given up on.
+            let mut unanswered = false;
+            while offset.is_none() && failures < MAX_ATTEMPTS {
+                let msg_key = format!("msg-{}-{}", key, next);
+                if unanswered {
+                    match ctx.kv_read(msg_key.clone()) {
+                        Ok(Some(taken)) if taken == slot => {
+                            offset = Some(next);
+                            continue;
+                        }
+                        Ok(Some(_)) => {
+                            unanswered = false;
+                            next += 1;
+                            continue;
+                        }
+                        Ok(None) => {}
+                        Err(_) => {
+                            failures += 1;
+                            sleep(BACKOFF * failures).await;
+                            continue;
+                        }
+                    }
+                }
+                match ctx.kv_cas(msg_key, Value::Null, slot.clone()) {
+                    Ok(true) => offset = Some(next),
+                    // Taken, perhaps by our own unanswered CAS: read it first.
+                    Ok(false) if unanswered => {}
+                    Ok(false) => next += 1,
+                    Err(_) => {
+                        unanswered = true;
+                        failures += 1;
+                        sleep(BACKOFF * failures).await;
+                    }
+                }
+            }
+            match offset {
+                Some(offset) => {
+                    // Losing this only makes later sends probe further.
+                    ctx.raise(format!("offset-{}", key), offset).await;
+                    Ok(json!({"type": "send_ok", "offset": offset}))
+                }
+                None if unanswered => Err(TIMEOUT),
+                None => Err(TEMPORARILY_UNAVAILABLE),
+            }
+        }
+        "poll" => {
+            let offsets: Offsets = serde_json::from_value(body["offsets"].clone()).unwrap();
+            let mut msgs: Messages = HashMap::new();
+            let mut ok = true;
+            for (k, from) in offsets {
+                let mut log = Vec::new();
+                for offset in from..from + POLL_LIMIT {
+                    match ctx.kv_read(format!("msg-{}-{}", k, offset)) {
+                        Ok(Some(slot)) => log.push((offset, slot["msg"].to_owned())),
+                        Ok(None) => break,
+                        Err(_) => {
+                            ok = false;
+                            break;
+                        }
+                    }
+                }
+                msgs.insert(k, log);
+            }
+            match ok {
+                true => Ok(json!({"type": "poll_ok", "msgs": msgs})),
+                _ => Err(TEMPORARILY_UNAVAILABLE),
+            }
+        }
+        "commit_offsets" => {
+            let offsets: Offsets = serde_json::from_value(body["offsets"].clone()).unwrap();
+            let mut ok = true;
+            for (k, v) in offsets {
+                ok &= ctx.raise(format!("committed-{}", k), v).await;
+            }
+            match ok {
+                true => Ok(json!({"type": "commit_offsets_ok"})),
+                _ => Err(TEMPORARILY_UNAVAILABLE),
+            }
+        }
+        _ => {
+            let keys: Vec<String> = serde_json::from_value(body["keys"].clone()).unwrap();
+            let mut offsets: Offsets = HashMap::new();
+            let mut ok = true;
+            for k in keys {
+                match ctx.kv_read(format!("committed-{}", k)) {
+                    Ok(Some(v)) => {
+                        offsets.insert(k, v.as_i64().unwrap());
+                    }
+                    Ok(None) => {}
+                    Err(_) => ok = false,
+                }
+            }
+            match ok {
+                true => Ok(json!({"type": "list_committed_offsets_ok", "offsets": offsets})),
+                _ => Err(TEMPORARILY_UNAVAILABLE),
+            }
+        }
+    };
+    reply_or_error(&ctx, &dest, incoming_id, result);
+}
+
+fn reply_or_error(ctx: &Context, dest: &str, incoming_id: i64, result: Result<Value, i64>) {
+    let body = result.unwrap_or_else(|code| {
+        let text = match code {
+            TIMEOUT => "Owner or lin-kv did not confirm the request; it may have happened",
+            _ => "Owner or lin-kv did not answer",
+        };
+        json!({"type": "error", "code": code, "text": text})
+    });
+    reply(&ctx.id, dest, &ctx.next_msg_id, incoming_id, body);
+}
+
+impl Node {
+    fn new(id: String, node_ids: Vec<String>, lin_kv: bool) -> Node {
+        Node {
+            id,
+            node_ids,
+            next_msg_id: Arc::new(Mutex::new(0)),
+            senders: Arc::new(Mutex::new(HashMap::new())),
+            logs: Arc::new(Mutex::new(Logs::default())),
+            lin_kv,
+        }
+    }
+
+    fn context(&self) -> Context {
+        Context {
+            id: self.id.clone(),
+            node_ids: self.node_ids.clone(),
+            next_msg_id: self.next_msg_id.clone(),
+            senders: self.senders.clone(),
+            logs: self.logs.clone(),
+        }
+    }
+
+    // Serves a request locally when we own every key it touches.
+    fn local(&self, body: &Value) -> Option<Value> {
+        let owned = |k: &String| owner(k, &self.node_ids) == self.id;
+        let mut logs = self.logs.lock().unwrap();
+        match body["type"].as_str().unwrap() {
+            "send" => {
+                let key = body["key"].as_str().unwrap().to_string();
+                owned(&key).then(|| {
+                    let offset = logs.append(&key, body["msg"].to_owned());
+                    json!({"type": "send_ok", "offset": offset})
+                })
+            }
+            "poll" => {
+                let offsets: Offsets = serde_json::from_value(body["offsets"].clone()).unwrap();
+                offsets
+                    .keys()
+                    .all(owned)
+                    .then(|| json!({"type": "poll_ok", "msgs": logs.poll(&offsets)}))
+            }
+            "commit_offsets" => {
+                let offsets: Offsets = serde_json::from_value(body["offsets"].clone()).unwrap();
+                offsets.keys().all(owned).then(|| {
+                    logs.commit(&offsets);
+                    json!({"type": "commit_offsets_ok"})
+                })
+            }
+            _ => {
+                let keys: Vec<String> = serde_json::from_value(body["keys"].clone()).unwrap();
+                keys.iter().all(owned).then(|| {
+                    json!({"type": "list_committed_offsets_ok", "offsets": logs.list_committed(&keys)})
+                })
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+enum ResponseBody<'a> {
+    #[serde(rename = "body")]
+    Init {
+        msg_id: i64,
+        r#type: &'a str,
+        in_reply_to: i64,
+    },
+    #[serde(rename = "body")]
+    Request(Value),
+}
+
+#[derive(Serialize)]
+struct Reply<'a> {
+    src: &'a str,
+    dest: &'a str,
+    #[serde(flatten)]
+    body: ResponseBody<'a>,
+}
+
+pub async fn run() {
+    // `--backend lin-kv` keeps logs in Maelstrom's lin-kv service instead of
+    // sharding keys across nodes.
+    let lin_kv = config::backend() == KV;
+    let mut node: Option<Node> = None;
+    while let Some(parsed) = transport::receive().await {
+        let _span = transport::request_span(&parsed).entered();
+        let body = &parsed["body"];
+        if let (Some(msg_id), Some(s)) = (body["in_reply_to"].as_i64(), node.as_ref()) {
+            let lookup = s.senders.lock().unwrap();
+            if lookup.contains_key(&msg_id) {
+                lookup[&msg_id].send(body.to_owned()).unwrap();
+            }
+            continue;
+        }
+        match body["type"].as_str().unwrap() {
+            "init" => {
+                node = Some(Node::new(
+                    body["node_id"].as_str().unwrap().to_string(),
+                    serde_json::from_value(body["node_ids"].clone()).unwrap(),
+                    lin_kv,
+                ));
+
+                info!("Initialized node {:?}", node.as_ref().map(|s| &s.id));
+                if let Some(s) = node.as_ref() {
+                    let reply = Reply {
+                        dest: parsed["src"].as_str().unwrap(),
+                        src: &s.id,
+                        body: ResponseBody::Init {
+                            msg_id: next_id(&s.next_msg_id),
+                            r#type: "init_ok",
+                            in_reply_to: body["msg_id"].as_i64().unwrap(),
+                        },
+                    };
+                    transport::send(&reply);
+                }
+            }
+            "send" | "poll" | "commit_offsets" | "list_committed_offsets" => {
+                if let Some(s) = node.as_ref() {
+                    let dest = parsed["src"].as_str().unwrap().to_string();
+                    if s.lin_kv {
+                        transport::spawn(linearizable(s.context(), body.to_owned(), dest));
+                    } else if let Some(result) = s.local(body) {
+                        reply(
+                            &s.id,
+                            &dest,
+                            &s.next_msg_id,
+                            body["msg_id"].as_i64().unwrap(),
+                            result,
+                        );
+                    } else {
+                        transport::spawn(sharded(s.context(), body.to_owned(), dest));
+                    }
+                }
+            }
+            _ => continue,
+        }
+    }
+    transport::shutdown().await;
+}
@@ -0,0 +1,240 @@
+use crate::clock::{self, Hlc};
+use crate::{config, transport};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::{Arc, Mutex};
+use tokio::time::{sleep, Duration};
+use tracing::{info, warn};
+
+struct Node {
+    id: String,
+    neighbours: Vec<String>,
+    next_msg_id: i64,
+    register: ThreadRegister,
+}
+
+type ThreadRegister = Arc<Mutex<LwwRegister>>;
+
+// Hybrid logical clock timestamp: wall-clock millis, a logical counter for
+// events within the same millisecond, and the writer's id to break ties.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, PartialOrd, Ord, Default)]
+struct Timestamp(i64, i64, String);
+
+#[derive(Serialize, Deserialize, Default)]
+struct LwwRegister {
+    value: Value,
+    timestamp: Timestamp,
+}
+
+impl LwwRegister {
+    fn write(&mut self, id: &str, value: Value) {
+        let Hlc(pt, l) = clock::with(|clocks| clocks.hlc.tick());
+        self.value = value;
+        self.timestamp = Timestamp(pt, l, id.to_string());
+    }
+
+    // A write stamped too far in the future is dropped, so a node with a
+    // runaway clock cannot win every register for as long as it is ahead.
+    fn merge(&mut self, other: LwwRegister) {
+        let stamp = Hlc(other.timestamp.0, other.timestamp.1);
+        if let Err(drift) = clock::with(|clocks| clocks.hlc.observe(stamp)) {
+            warn!("Dropping write {}ms ahead of our clock", drift);
+            return;
+        }
+        if other.timestamp > self.timestamp {
+            self.value = other.value;
+            self.timestamp = other.timestamp;
+        }
+    }
+}
+
+async fn replicate(dest: String, src: String, register: ThreadRegister) {
+    loop {
+        {
+            let register = register.lock().unwrap();
+            let message = Reply {
+                dest: &dest,
+                src: &src,
+                body: ResponseBody::Replicate {
+                    r#type: "replicate",
+                    msg: &register,
+                },
+            };
+            transport::send(&message);
+        }
+        sleep(config::gossip_interval(Duration::from_millis(5000))).await;
+    }
+}
+
+impl Node {
+    fn new(id: String, neighbours: Vec<String>) -> Node {
+        let mut node = Node {
+            id,
+            neighbours,
+            next_msg_id: 0,
+            register: Arc::new(Mutex::new(LwwRegister::default())),
+        };
+        node.replicate_neighbours();
+        node
+    }
+    fn replicate_neighbours(&mut self) {
+        for n in &self.neighbours {
+            if *n != self.id {
+                tokio::spawn(replicate(n.clone(), self.id.clone(), self.register.clone()));
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+enum ResponseBody<'a> {
+    #[serde(rename = "body")]
+    Init {
+        msg_id: i64,
+        r#type: &'a str,
+        in_reply_to: i64,
+    },
+    #[serde(rename = "body")]
+    Replicate {
+        r#type: &'a str,
+        msg: &'a LwwRegister,
+    },
+    #[serde(rename = "body")]
+    Write {
+        r#type: &'a str,
+        in_reply_to: i64,
+        msg_id: i64,
+    },
+    #[serde(rename = "body")]
+    Read {
+        r#type: &'a str,
+        value: &'a Value,
+        in_reply_to: i64,
+    },
+}
+
+#[derive(Serialize)]
+struct Reply<'a> {
+    src: &'a str,
+    dest: &'a str,
+    #[serde(flatten)]
+    body: ResponseBody<'a>,
+}
+
+pub async fn run() {
+    let mut node: Option<Node> = None;
+    while let Some(parsed) = transport::receive().await {
+        let _span = transport::request_span(&parsed).entered();
+        let body = &parsed["body"];
+        match body["type"].as_str().unwrap() {
+            "init" => {
+                node = Some(Node::new(
+                    body["node_id"].as_str().unwrap().to_string(),
+                    serde_json::from_value(body["node_ids"].clone()).unwrap(),
+                ));
+
+                info!("Initialized node {:?}", node.as_ref().map(|s| &s.id));
+                if let Some(s) = node.as_mut() {
+                    s.next_msg_id += 1;
+                }
+                let reply = Reply {
+                    dest: parsed["src"].as_str().unwrap(),
+                    src: node.as_ref().map(|s| &s.id).unwrap(),
+                    body: ResponseBody::Init {
+                        msg_id: node.as_ref().map(|s| s.next_msg_id).unwrap(),
+                        r#type: "init_ok",
+                        in_reply_to: body["msg_id"].as_i64().unwrap(),
+                    },
+                };
+                transport::send(&reply);
+            }
+            "write" => {
+                if let Some(s) = node.as_mut() {
+                    let mut register = s.register.lock().unwrap();
+                    register.write(&s.id, body["value"].to_owned());
+                    s.next_msg_id += 1;
+
+                    let reply = Reply {
+                        dest: parsed["src"].as_str().unwrap(),
+                        src: &s.id,
+                        body: ResponseBody::Write {
+                            msg_id: s.next_msg_id,
+                            r#type: "write_ok",
+                            in_reply_to: body["msg_id"].as_i64().unwrap(),
+                        },
+                    };
+                    transport::send(&reply);
+                }
+            }
+            "replicate" => {
+                if let Some(s) = node.as_mut() {
+                    let r: LwwRegister = serde_json::from_value(body["msg"].clone()).unwrap();
+                    let mut register = s.register.lock().unwrap();
+                    register.merge(r);
+                }
+            }
+            "read" => {
+                if let Some(s) = node.as_mut() {
+                    s.next_msg_id += 1;
+                    let register = s.register.lock().unwrap();
+                    let reply = Reply {
+                        dest: parsed["src"].as_str().unwrap(),
+                        src: &s.id,
+                        body: ResponseBody::Read {
+                            value: &register.value,
+                            r#type: "read_ok",
+                            in_reply_to: body["msg_id"].as_i64().unwrap(),
+                        },
+                    };
+                    transport::send(&reply);
+                }
+            }
+            _ => continue,
+        }
+    }
+    transport::shutdown().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn register(value: Value, pt: i64, id: &str) -> LwwRegister {
+        LwwRegister {
+            value,
+            timestamp: Timestamp(pt, 0, id.to_string()),
+        }
+    }
+
+    fn copy(register: &LwwRegister) -> LwwRegister {
+        serde_json::from_value(serde_json::to_value(register).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn merges_commute_and_are_idempotent() {
+        let now = clock::now();
+        // Stamped in the same millisecond: the writer's id breaks the tie.
+        let (a, b) = (register(json!(1), now, "n0"), register(json!(2), now, "n1"));
+        let mut ab = copy(&a);
+        ab.merge(copy(&b));
+        let mut ba = copy(&b);
+        ba.merge(copy(&a));
+        assert_eq!(ab.value, json!(2));
+        assert_eq!(ba.value, json!(2));
+        assert!(ab.timestamp == ba.timestamp);
+        ab.merge(copy(&ba));
+        assert_eq!(ab.value, json!(2));
+    }
+
+    #[test]
+    fn writes_from_a_runaway_clock_are_dropped() {
+        let now = clock::now();
+        let mut a = register(json!(1), now, "n0");
+        a.merge(register(json!(2), now + 10 * clock::MAX_DRIFT_MS, "n1"));
+        assert_eq!(a.value, json!(1));
+        // A later write within the drift bound still wins.
+        a.merge(register(json!(3), now + clock::MAX_DRIFT_MS / 2, "n1"));
+        assert_eq!(a.value, json!(3));
+    }
+}
@@ -0,0 +1,344 @@
+use crate::{config, transport};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tracing::info;
+
+// Counts every message a node sends and receives, as seen by transport.rs, so
+// no handler has to report anything itself:
+//
+// - messages sent and received, by body type and by destination or source;
+// - RPC latency, from a request (a message with a msg_id and no in_reply_to)
+//   to its reply, as a histogram per request type;
+// - retries, where a request goes out again with the same msg_id;
+// - error replies by request type and code; code 22 on a `cas` is a CAS
+//   conflict;
+// - timeouts, requests left unanswered for RPC_TIMEOUT;
+// - the output queue: how deep it got, how often and how long `send` had to
+//   wait for room, and the lines and bytes written per flush;
+// - with network.rs sending MessagePack, the size of each message type in
+//   both encodings, and how many messages were checked to decode back to
+//   their JSON form and how many did not.
+//
+// The counts are logged as JSON when the node shuts down, and returned in a
+// `metrics_ok` reply to a `metrics` message.
+
+// Matches the longest wait of any RPC in the workloads, unless set with
+// --rpc-timeout-ms; a reply later than this is never used.
+const RPC_TIMEOUT: Duration = Duration::from_secs(5);
+const CAS_CONFLICT: i64 = 22;
+// Upper bounds of the latency buckets, in ms. The last bucket is unbounded.
+const BUCKETS_MS: [u64; 12] = [1, 2, 5, 10, 20, 50, 100, 200, 500, 1000, 2000, 5000];
+
+#[derive(Deserialize)]
+struct Header {
+    r#type: Option<String>,
+    msg_id: Option<i64>,
+    in_reply_to: Option<i64>,
+    code: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct Envelope {
+    src: String,
+    dest: String,
+    body: Header,
+}
+
+#[derive(Default)]
+struct Histogram {
+    counts: [u64; BUCKETS_MS.len() + 1],
+    total_ms: f64,
+    max_ms: f64,
+}
+
+impl Histogram {
+    fn record(&mut self, latency: Duration) {
+        let ms = latency.as_secs_f64() * 1000.0;
+        let bucket = BUCKETS_MS
+            .iter()
+            .position(|bound| ms <= *bound as f64)
+            .unwrap_or(BUCKETS_MS.len());
+        self.counts[bucket] += 1;
+        self.total_ms += ms;
+        self.max_ms = self.max_ms.max(ms);
+    }
+
+    // The upper bound of the bucket holding the given quantile.
+    fn quantile(&self, q: f64) -> Value {
+        let count: u64 = self.counts.iter().sum();
+        if count == 0 {
+            return Value::Null;
+        }
+        let rank = (count as f64 * q).ceil() as u64;
+        let mut seen = 0;
+        for (i, n) in self.counts.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                return BUCKETS_MS.get(i).map_or(json!("inf"), |b| json!(b));
+            }
+        }
+        Value::Null
+    }
+
+    fn to_json(&self) -> Value {
+        let count: u64 = self.counts.iter().sum();
+        let buckets: BTreeMap<String, u64> = self
+            .counts
+            .iter()
+            .enumerate()
+            .filter(|(_, n)| **n > 0)
+            .map(|(i, n)| {
+                let bound = BUCKETS_MS
+                    .get(i)
+                    .map_or("inf".to_string(), |b| b.to_string());
+                (format!("le_{}", bound), *n)
+            })
+            .collect();
+        json!({
+            "count": count,
+            "mean_ms": self.total_ms / count.max(1) as f64,
+            "max_ms": self.max_ms,
+            "p50_ms": self.quantile(0.5),
+            "p99_ms": self.quantile(0.99),
+            "buckets": buckets,
+        })
+    }
+}
+
+type Counts = BTreeMap<String, BTreeMap<String, u64>>;
+
+#[derive(Default)]
+struct Output {
+    queued: usize,
+    max_queued: usize,
+    stalls: Histogram,
+    flushes: u64,
+    lines: u64,
+    bytes: u64,
+}
+
+impl Output {
+    fn to_json(&self) -> Value {
+        json!({
+            "queued": self.queued,
+            "max_queued": self.max_queued,
+            "stalls": self.stalls.to_json(),
+            "flushes": self.flushes,
+            "lines": self.lines,
+            "bytes": self.bytes,
+            "lines_per_flush": self.lines as f64 / self.flushes.max(1) as f64,
+        })
+    }
+}
+
+#[derive(Default)]
+struct Registry {
+    sent: Counts,
+    received: Counts,
+    // Requests awaiting a reply by (dest, msg_id), with their type and when
+    // they were first sent; `expiry` holds the same keys in send order.
+    outstanding: HashMap<(String, i64), (String, Instant)>,
+    expiry: VecDeque<(Instant, (String, i64))>,
+    latency: BTreeMap<String, Histogram>,
+    retries: BTreeMap<String, u64>,
+    errors: Counts,
+    timeouts: BTreeMap<String, u64>,
+    output: Output,
+    // Messages, JSON bytes and MessagePack bytes, by type.
+    encoded: BTreeMap<String, (u64, u64, u64)>,
+    round_trips: (u64, u64),
+}
+
+impl Registry {
+    fn count(counts: &mut Counts, r#type: &str, peer: &str) {
+        *counts
+            .entry(r#type.to_string())
+            .or_default()
+            .entry(peer.to_string())
+            .or_insert(0) += 1;
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some((sent, _)) = self.expiry.front() {
+            if now.duration_since(*sent) < config::rpc_timeout(RPC_TIMEOUT) {
+                break;
+            }
+            let (sent, key) = self.expiry.pop_front().unwrap();
+            if let Some((r#type, first)) = self.outstanding.get(&key) {
+                if *first == sent {
+                    *self.timeouts.entry(r#type.clone()).or_insert(0) += 1;
+                    self.outstanding.remove(&key);
+                }
+            }
+        }
+    }
+
+    fn sent(&mut self, message: Envelope) {
+        let now = Instant::now();
+        self.expire(now);
+        let r#type = message.body.r#type.unwrap_or_default();
+        Registry::count(&mut self.sent, &r#type, &message.dest);
+        if let (Some(msg_id), None) = (message.body.msg_id, message.body.in_reply_to) {
+            let key = (message.dest, msg_id);
+            if self.outstanding.contains_key(&key) {
+                *self.retries.entry(r#type).or_insert(0) += 1;
+                return;
+            }
+            self.expiry.push_back((now, key.clone()));
+            self.outstanding.insert(key, (r#type, now));
+        }
+    }
+
+    fn received(&mut self, message: Envelope) {
+        let now = Instant::now();
+        self.expire(now);
+        let r#type = message.body.r#type.unwrap_or_default();
+        Registry::count(&mut self.received, &r#type, &message.src);
+        let reply_to = match message.body.in_reply_to {
+            Some(reply_to) => reply_to,
+            None => return,
+        };
+        if let Some((request, sent)) = self.outstanding.remove(&(message.src, reply_to)) {
+            self.latency
+                .entry(request.clone())
+                .or_default()
+                .record(now.duration_since(sent));
+            if let Some(code) = message.body.code.filter(|_| r#type == "error") {
+                Registry::count(&mut self.errors, &request, &code.to_string());
+            }
+        }
+    }
+
+    fn encoding_json(&self) -> Value {
+        let ratio = |json: u64, binary: u64| binary as f64 / json.max(1) as f64;
+        let by_type: BTreeMap<&String, Value> = self
+            .encoded
+            .iter()
+            .map(|(r#type, (messages, json, binary))| {
+                let sizes = json!({
+                    "messages": messages,
+                    "json_bytes": json,
+                    "msgpack_bytes": binary,
+                    "ratio": ratio(*json, *binary),
+                });
+                (r#type, sizes)
+            })
+            .collect();
+        let json: u64 = self.encoded.values().map(|e| e.1).sum();
+        let binary: u64 = self.encoded.values().map(|e| e.2).sum();
+        json!({
+            "by_type": by_type,
+            "json_bytes": json,
+            "msgpack_bytes": binary,
+            "ratio": ratio(json, binary),
+            "round_trips_checked": self.round_trips.0,
+            "round_trips_mismatched": self.round_trips.1,
+        })
+    }
+
+    fn to_json(&self) -> Value {
+        let latency: BTreeMap<&String, Value> = self
+            .latency
+            .iter()
+            .map(|(r#type, histogram)| (r#type, histogram.to_json()))
+            .collect();
+        let cas_conflicts = self
+            .errors
+            .get("cas")
+            .and_then(|codes| codes.get(&CAS_CONFLICT.to_string()))
+            .copied()
+            .unwrap_or(0);
+        json!({
+            "sent": self.sent,
+            "received": self.received,
+            "latency": latency,
+            "retries": self.retries,
+            "errors": self.errors,
+            "cas_conflicts": cas_conflicts,
+            "timeouts": self.timeouts,
+            "outstanding": self.outstanding.len(),
+            "output": self.output.to_json(),
+            "encoding": self.encoding_json(),
+        })
+    }
+}
+
+fn registry() -> &'static Mutex<Registry> {
+    static REGISTRY: OnceLock<Mutex<Registry>> = OnceLock::new();
+    REGISTRY.get_or_init(Mutex::default)
+}
+
+// Called by transport.rs with each message as it goes out or comes in.
+// Anything that is not a message is left out.
+pub fn sent(message: &Value) {
+    if let Ok(message) = Envelope::deserialize(message) {
+        registry().lock().unwrap().sent(message);
+    }
+}
+
+pub fn received(message: &Value) {
+    if let Ok(message) = Envelope::deserialize(message) {
+        registry().lock().unwrap().received(message);
+    }
+}
+
+// Called by transport.rs's writer: `queued` with the queue's depth after each
+// send, `waited` with how long a send that found it full had to wait, and
+// `flushed` after each write to stdout.
+pub fn queued(depth: usize) {
+    let output = &mut registry().lock().unwrap().output;
+    output.queued = depth;
+    output.max_queued = output.max_queued.max(depth);
+}
+
+pub fn waited(stall: Duration) {
+    registry().lock().unwrap().output.stalls.record(stall);
+}
+
+pub fn flushed(lines: u64, bytes: u64) {
+    let output = &mut registry().lock().unwrap().output;
+    output.flushes += 1;
+    output.lines += lines;
+    output.bytes += bytes;
+}
+
+// Called by network.rs for each message it sends as MessagePack, and for each
+// of those it checked.
+pub fn encoded(r#type: &str, json: usize, binary: usize) {
+    let mut registry = registry().lock().unwrap();
+    let sizes = registry.encoded.entry(r#type.to_string()).or_default();
+    sizes.0 += 1;
+    sizes.1 += json as u64;
+    sizes.2 += binary as u64;
+}
+
+pub fn round_trip(matches: bool) {
+    let round_trips = &mut registry().lock().unwrap().round_trips;
+    round_trips.0 += 1;
+    round_trips.1 += !matches as u64;
+}
+
+pub fn snapshot() -> Value {
+    registry().lock().unwrap().to_json()
+}
+
+pub fn dump() {
+    info!("Metrics {}", snapshot());
+}
+
+// Answers a `metrics` admin message.
+pub fn report(request: &Value) {
+    let reply = json!({
+        "src": request["dest"],
+        "dest": request["src"],
+        "body": {
+            "type": "metrics_ok",
+            "in_reply_to": request["body"]["msg_id"],
+            "metrics": snapshot(),
+        },
+    });
+    transport::send(&reply);
+}
@@ -0,0 +1,129 @@
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+pub const NOT_SUPPORTED: i64 = 10;
+pub const MALFORMED_REQUEST: i64 = 12;
+pub const PRECONDITION_FAILED: i64 = 22;
+
+// Values are registers (any JSON value) or lists built by `append`.
+pub type Values = BTreeMap<i64, Value>;
+
+#[derive(Serialize)]
+pub struct TxnType(pub String, pub i64, pub Value);
+
+pub struct TxnError {
+    pub code: i64,
+    pub text: String,
+}
+
+impl TxnError {
+    pub fn new(code: i64, text: String) -> TxnError {
+        TxnError { code, text }
+    }
+}
+
+// A micro-op reads or changes one key and returns the value to report back in
+// its slot of the transaction.
+type MicroOp = fn(&mut Values, i64, &Value) -> Result<Value, TxnError>;
+
+// The supported micro-ops. Adding one is a matter of writing its function and
+// listing it here.
+const MICRO_OPS: &[(&str, MicroOp)] =
+    &[("r", read), ("w", write), ("append", append), ("cas", cas)];
+
+fn read(values: &mut Values, key: i64, _: &Value) -> Result<Value, TxnError> {
+    Ok(values.get(&key).cloned().unwrap_or(Value::Null))
+}
+
+fn write(values: &mut Values, key: i64, arg: &Value) -> Result<Value, TxnError> {
+    values.insert(key, arg.clone());
+    Ok(arg.clone())
+}
+
+fn append(values: &mut Values, key: i64, arg: &Value) -> Result<Value, TxnError> {
+    match values
+        .entry(key)
+        .or_insert_with(|| Value::Array(Vec::new()))
+    {
+        Value::Array(list) => {
+            list.push(arg.clone());
+            Ok(arg.clone())
+        }
+        _ => Err(TxnError::new(
+            PRECONDITION_FAILED,
+            format!("Cannot append to register {}", key),
+        )),
+    }
+}
+
+// ["cas", k, [from, to]] writes `to` only if the key currently holds `from`;
+// otherwise the whole transaction fails.
+fn cas(values: &mut Values, key: i64, arg: &Value) -> Result<Value, TxnError> {
+    let (from, to) = match arg.as_array().map(|a| a.as_slice()) {
+        Some([from, to]) => (from, to),
+        _ => {
+            return Err(TxnError::new(
+                MALFORMED_REQUEST,
+                format!("cas on {} needs [from, to]", key),
+            ))
+        }
+    };
+    let current = values.get(&key).unwrap_or(&Value::Null);
+    if current != from {
+        return Err(TxnError::new(
+            PRECONDITION_FAILED,
+            format!("Expected {} to be {}, but it was {}", key, from, current),
+        ));
+    }
+    values.insert(key, to.clone());
+    Ok(arg.clone())
+}
+
+// Runs every micro-op of `txn` in order. On error `values` may hold a partial
+// result, so callers should run against a copy they can throw away.
+pub fn run_transactions(values: &mut Values, txn: &Value) -> Result<Vec<TxnType>, TxnError> {
+    let ops = txn
+        .as_array()
+        .ok_or_else(|| TxnError::new(MALFORMED_REQUEST, "txn must be an array".to_string()))?;
+    let mut result = Vec::new();
+    for op in ops {
+        let (f, key, arg) = match op.as_array().map(|o| o.as_slice()) {
+            Some([Value::String(f), key, arg]) if key.is_i64() => (f, key.as_i64().unwrap(), arg),
+            _ => {
+                return Err(TxnError::new(
+                    MALFORMED_REQUEST,
+                    format!("Malformed micro-op {}", op),
+                ))
+            }
+        };
+        let micro_op = match MICRO_OPS.iter().find(|(name, _)| name == f) {
+            Some((_, micro_op)) => micro_op,
+            None => {
+                return Err(TxnError::new(
+                    NOT_SUPPORTED,
+                    format!("Unsupported micro-op {}", f),
+                ))
+            }
+        };
+        result.push(TxnType(f.clone(), key, micro_op(values, key, arg)?));
+    }
+    Ok(result)
+}
+
+// The keys whose value a transaction changed, with their new values.
+pub fn changes(before: &Values, after: &Values) -> Values {
+    after
+        .iter()
+        .filter(|(k, v)| before.get(k) != Some(v))
+        .map(|(k, v)| (*k, v.clone()))
+        .collect()
+}
+
+// Every key a transaction's micro-ops touch.
+pub fn keys(txn: &Value) -> impl Iterator<Item = i64> + '_ {
+    txn.as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|op| op.get(1).and_then(|k| k.as_i64()))
+}
@@ -0,0 +1,256 @@
+use crate::clock::VectorClock;
+use crate::{config, transport};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::cmp::Ordering;
+use std::sync::{Arc, Mutex};
+use tokio::time::{sleep, Duration};
+use tracing::info;
+
+struct Node {
+    id: String,
+    neighbours: Vec<String>,
+    next_msg_id: i64,
+    register: ThreadRegister,
+}
+
+type ThreadRegister = Arc<Mutex<MvRegister>>;
+
+// Multi-value register. Concurrent writes are kept side by side as siblings,
+// each tagged with the version vector it was written at; a write or merge only
+// drops siblings whose version vector is dominated by another one.
+#[derive(Serialize, Deserialize, Default)]
+struct MvRegister {
+    siblings: Vec<(Value, VectorClock)>,
+}
+
+impl MvRegister {
+    fn write(&mut self, id: &str, value: Value) {
+        let mut vv = VectorClock::default();
+        for (_, v) in &self.siblings {
+            vv.merge(v);
+        }
+        vv.increment(id);
+        self.siblings = vec![(value, vv)];
+    }
+
+    fn merge(&mut self, other: MvRegister) {
+        let mut all = std::mem::take(&mut self.siblings);
+        for s in other.siblings {
+            if !all
+                .iter()
+                .any(|(_, v)| v.compare(&s.1) == Some(Ordering::Equal))
+            {
+                all.push(s);
+            }
+        }
+        self.siblings = all
+            .iter()
+            .filter(|(_, v)| {
+                !all.iter()
+                    .any(|(_, o)| o.compare(v) == Some(Ordering::Greater))
+            })
+            .cloned()
+            .collect();
+    }
+
+    fn value(&self) -> Vec<&Value> {
+        self.siblings.iter().map(|(v, _)| v).collect()
+    }
+}
+
+async fn replicate(dest: String, src: String, register: ThreadRegister) {
+    loop {
+        {
+            let register = register.lock().unwrap();
+            let message = Reply {
+                dest: &dest,
+                src: &src,
+                body: ResponseBody::Replicate {
+                    r#type: "replicate",
+                    msg: &register,
+                },
+            };
+            transport::send(&message);
+        }
+        sleep(config::gossip_interval(Duration::from_millis(5000))).await;
+    }
+}
+
+impl Node {
+    fn new(id: String, neighbours: Vec<String>) -> Node {
+        let mut node = Node {
+            id,
+            neighbours,
+            next_msg_id: 0,
+            register: Arc::new(Mutex::new(MvRegister::default())),
+        };
+        node.replicate_neighbours();
+        node
+    }
+    fn replicate_neighbours(&mut self) {
+        for n in &self.neighbours {
+            if *n != self.id {
+                tokio::spawn(replicate(n.clone(), self.id.clone(), self.register.clone()));
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+enum ResponseBody<'a> {
+    #[serde(rename = "body")]
+    Init {
+        msg_id: i64,
+        r#type: &'a str,
+        in_reply_to: i64,
+    },
+    #[serde(rename = "body")]
+    Replicate {
+        r#type: &'a str,
+        msg: &'a MvRegister,
+    },
+    #[serde(rename = "body")]
+    Write {
+        r#type: &'a str,
+        in_reply_to: i64,
+        msg_id: i64,
+    },
+    #[serde(rename = "body")]
+    Read {
+        r#type: &'a str,
+        value: Vec<&'a Value>,
+        in_reply_to: i64,
+    },
+}
+
+#[derive(Serialize)]
+struct Reply<'a> {
+    src: &'a str,
+    dest: &'a str,
+    #[serde(flatten)]
+    body: ResponseBody<'a>,
+}
+
+pub async fn run() {
+    let mut node: Option<Node> = None;
+    while let Some(parsed) = transport::receive().await {
+        let _span = transport::request_span(&parsed).entered();
+        let body = &parsed["body"];
+        match body["type"].as_str().unwrap() {
+            "init" => {
+                node = Some(Node::new(
+                    body["node_id"].as_str().unwrap().to_string(),
+                    serde_json::from_value(body["node_ids"].clone()).unwrap(),
+                ));
+
+                info!("Initialized node {:?}", node.as_ref().map(|s| &s.id));
+                if let Some(s) = node.as_mut() {
+                    s.next_msg_id += 1;
+                }
+                let reply = Reply {
+                    dest: parsed["src"].as_str().unwrap(),
+                    src: node.as_ref().map(|s| &s.id).unwrap(),
+                    body: ResponseBody::Init {
+                        msg_id: node.as_ref().map(|s| s.next_msg_id).unwrap(),
+                        r#type: "init_ok",
+                        in_reply_to: body["msg_id"].as_i64().unwrap(),
+                    },
+                };
+                transport::send(&reply);
+            }
+            "write" => {
+                if let Some(s) = node.as_mut() {
+                    let mut register = s.register.lock().unwrap();
+                    register.write(&s.id, body["value"].to_owned());
+                    s.next_msg_id += 1;
+
+                    let reply = Reply {
+                        dest: parsed["src"].as_str().unwrap(),
+                        src: &s.id,
+                        body: ResponseBody::Write {
+                            msg_id: s.next_msg_id,
+                            r#type: "write_ok",
+                            in_reply_to: body["msg_id"].as_i64().unwrap(),
+                        },
+                    };
+                    transport::send(&reply);
+                }
+            }
+            "replicate" => {
+                if let Some(s) = node.as_mut() {
+                    let r: MvRegister = serde_json::from_value(body["msg"].clone()).unwrap();
+                    let mut register = s.register.lock().unwrap();
+                    register.merge(r);
+                }
+            }
+            "read" => {
+                if let Some(s) = node.as_mut() {
+                    s.next_msg_id += 1;
+                    let register = s.register.lock().unwrap();
+                    let reply = Reply {
+                        dest: parsed["src"].as_str().unwrap(),
+                        src: &s.id,
+                        body: ResponseBody::Read {
+                            value: register.value(),
+                            r#type: "read_ok",
+                            in_reply_to: body["msg_id"].as_i64().unwrap(),
+                        },
+                    };
+                    transport::send(&reply);
+                }
+            }
+            _ => continue,
+        }
+    }
+    transport::shutdown().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::collections::BTreeSet;
+
+    fn copy(register: &MvRegister) -> MvRegister {
+        serde_json::from_value(serde_json::to_value(register).unwrap()).unwrap()
+    }
+
+    fn values(register: &MvRegister) -> BTreeSet<String> {
+        register.value().iter().map(|v| v.to_string()).collect()
+    }
+
+    #[test]
+    fn concurrent_writes_are_kept_as_siblings() {
+        let (mut a, mut b) = (MvRegister::default(), MvRegister::default());
+        a.write("n0", json!(1));
+        b.write("n1", json!(2));
+        let mut ab = copy(&a);
+        ab.merge(copy(&b));
+        let mut ba = copy(&b);
+        ba.merge(copy(&a));
+        assert_eq!(
+            values(&ab),
+            BTreeSet::from(["1".to_string(), "2".to_string()])
+        );
+        assert_eq!(values(&ab), values(&ba));
+        ab.merge(copy(&ba));
+        assert_eq!(ab.siblings.len(), 2);
+    }
+
+    #[test]
+    fn a_write_that_saw_the_siblings_replaces_them() {
+        let (mut a, mut b) = (MvRegister::default(), MvRegister::default());
+        a.write("n0", json!(1));
+        b.write("n1", json!(2));
+        a.merge(copy(&b));
+        a.write("n0", json!(3));
+        b.merge(copy(&a));
+        assert_eq!(values(&b), BTreeSet::from(["3".to_string()]));
+        // A stale sibling arriving late is dominated and dropped.
+        let mut stale = MvRegister::default();
+        stale.write("n1", json!(2));
+        b.merge(stale);
+        assert_eq!(values(&b), BTreeSet::from(["3".to_string()]));
+    }
+}
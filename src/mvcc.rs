@@ -0,0 +1,152 @@
+use crate::micro_ops::{TxnError, Values};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+
+pub const TXN_CONFLICT: i64 = 30;
+
+// Multi-version store. Each key keeps its committed versions ordered by
+// timestamp, so a transaction can read the snapshot as of the moment it
+// started while others commit. The timestamp type is up to the caller: a
+// plain counter on a single node, a (Lamport, node) pair when replicated.
+pub struct Mvcc<T: Ord + Clone> {
+    versions: HashMap<i64, Vec<(T, Value)>>,
+    // Start timestamps of running transactions, with how many started there.
+    active: BTreeMap<T, usize>,
+}
+
+impl<T: Ord + Clone> Default for Mvcc<T> {
+    fn default() -> Self {
+        Mvcc {
+            versions: HashMap::new(),
+            active: BTreeMap::new(),
+        }
+    }
+}
+
+impl<T: Ord + Clone> Mvcc<T> {
+    // Registers a transaction reading at `at`, holding back garbage collection
+    // of the versions it can see until `finish`.
+    pub fn begin(&mut self, at: &T) {
+        *self.active.entry(at.clone()).or_insert(0) += 1;
+    }
+
+    pub fn finish(&mut self, at: &T) {
+        if let Some(count) = self.active.get_mut(at) {
+            *count -= 1;
+            if *count == 0 {
+                self.active.remove(at);
+            }
+        }
+    }
+
+    pub fn read(&self, key: i64, at: &T) -> Option<&Value> {
+        self.versions
+            .get(&key)?
+            .iter()
+            .rev()
+            .find(|(ts, _)| ts <= at)
+            .map(|(_, v)| v)
+    }
+
+    // The values of `keys` as of `at`, for micro-ops to run against.
+    pub fn snapshot(&self, keys: impl Iterator<Item = i64>, at: &T) -> Values {
+        keys.filter_map(|k| self.read(k, at).map(|v| (k, v.clone())))
+            .collect()
+    }
+
+    // First committer wins: a transaction that started at `start` may not
+    // commit a key someone else committed after `start`. On conflict the store
+    // is left unchanged.
+    pub fn commit(&mut self, start: &T, at: T, writes: Values) -> Result<(), TxnError> {
+        for k in writes.keys() {
+            if let Some((ts, _)) = self.versions.get(k).and_then(|v| v.last()) {
+                if ts > start {
+                    return Err(TxnError::new(
+                        TXN_CONFLICT,
+                        format!("Key {} was committed concurrently", k),
+                    ));
+                }
+            }
+        }
+        self.install(at, writes);
+        Ok(())
+    }
+
+    // Adds versions without conflict checks, e.g. writes replicated from a peer
+    // that were already committed there. Versions may land in the past.
+    pub fn install(&mut self, at: T, writes: Values) {
+        let horizon = self.active.keys().next().cloned();
+        for (k, v) in writes {
+            let versions = self.versions.entry(k).or_default();
+            let i = versions.partition_point(|(ts, _)| *ts < at);
+            if versions.get(i).is_none_or(|(ts, _)| *ts != at) {
+                versions.insert(i, (at.clone(), v));
+            }
+            let horizon = horizon
+                .clone()
+                .unwrap_or_else(|| versions.last().unwrap().0.clone());
+            collect(versions, &horizon);
+        }
+    }
+}
+
+// Drops versions no snapshot at or after `horizon` can read: everything older
+// than the newest version at or before it.
+fn collect<T: Ord>(versions: &mut Vec<(T, Value)>, horizon: &T) {
+    let visible = versions.partition_point(|(ts, _)| ts <= horizon);
+    if visible > 1 {
+        versions.drain(..visible - 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn writes(k: i64, v: Value) -> Values {
+        Values::from([(k, v)])
+    }
+
+    #[test]
+    fn reads_see_the_snapshot_they_started_at() {
+        let mut mvcc = Mvcc::default();
+        assert!(mvcc.commit(&0, 1, writes(1, json!([1]))).is_ok());
+        mvcc.begin(&1);
+        assert!(mvcc.commit(&1, 2, writes(1, json!([1, 2]))).is_ok());
+        assert_eq!(mvcc.read(1, &1), Some(&json!([1])));
+        assert_eq!(mvcc.read(1, &2), Some(&json!([1, 2])));
+        assert_eq!(mvcc.read(1, &0), None);
+        assert_eq!(mvcc.snapshot([1, 2].into_iter(), &1), writes(1, json!([1])));
+    }
+
+    #[test]
+    fn the_first_committer_wins() {
+        let mut mvcc = Mvcc::default();
+        mvcc.begin(&0);
+        mvcc.begin(&0);
+        assert!(mvcc.commit(&0, 1, writes(1, json!(1))).is_ok());
+        let error = mvcc.commit(&0, 2, writes(1, json!(2))).unwrap_err();
+        assert_eq!(error.code, TXN_CONFLICT);
+        assert_eq!(mvcc.read(1, &2), Some(&json!(1)));
+        // Disjoint keys do not conflict.
+        assert!(mvcc.commit(&0, 3, writes(2, json!(3))).is_ok());
+    }
+
+    #[test]
+    fn versions_are_collected_below_the_oldest_active_snapshot() {
+        let mut mvcc = Mvcc::default();
+        assert!(mvcc.commit(&0, 1, writes(1, json!(1))).is_ok());
+        assert!(mvcc.commit(&1, 2, writes(1, json!(2))).is_ok());
+        // With nothing running, only the latest version is kept.
+        assert_eq!(mvcc.versions[&1], vec![(2, json!(2))]);
+        mvcc.begin(&2);
+        assert!(mvcc.commit(&2, 3, writes(1, json!(3))).is_ok());
+        assert!(mvcc.commit(&3, 4, writes(1, json!(4))).is_ok());
+        assert_eq!(mvcc.read(1, &2), Some(&json!(2)));
+        assert_eq!(mvcc.versions[&1].len(), 3);
+        mvcc.finish(&2);
+        assert!(mvcc.commit(&4, 5, writes(1, json!(5))).is_ok());
+        assert_eq!(mvcc.versions[&1], vec![(5, json!(5))]);
+    }
+}
@@ -0,0 +1,495 @@
+use crate::metrics;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs, UdpSocket};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+
+// Setting RAFT_PEERS to a peer map runs the node over the network instead of
+// stdin and stdout, so a cluster can run outside Maelstrom:
+//
+//   RAFT_PEERS=peers.json RAFT_NODE=n1 target/debug/raft txn --backend 2pc
+//
+// where peers.json maps every node id to the address it listens on:
+//
+//   {"n0": "127.0.0.1:7000", "n1": "127.0.0.1:7001", "n2": "127.0.0.1:7002"}
+//
+// Messages are the same newline-delimited JSON. RAFT_PROTOCOL picks `tcp`, the
+// default, or `udp`, with one message per datagram. Since no one else will,
+// the node sends itself the `init` Maelstrom would, naming every node in the
+// map. Clients send to a node's address like peers do, and get replies on the
+// connection, or at the address, they sent from.
+//
+// Maelstrom's services are not provided, so workloads that need lin-kv and
+// friends only run in the modes that do without them.
+//
+// RAFT_ENCODING=msgpack sends to peers in MessagePack rather than JSON: the
+// same messages, typically a third smaller. Nodes accept either encoding on
+// any connection, telling them apart by their first byte, so the choice is
+// each sender's and nodes configured differently still talk; clients are
+// answered in whichever encoding they used. (Bincode is not an option: it
+// cannot carry the untyped JSON values our bodies are made of.) Everything
+// past this module still sees JSON lines. Setting RAFT_ENCODING_CHECK
+// decodes every binary message again and checks it against the JSON it was
+// made from; the metrics count mismatches, and each encoding's sizes.
+const PEERS_VAR: &str = "RAFT_PEERS";
+const NODE_VAR: &str = "RAFT_NODE";
+const PROTOCOL_VAR: &str = "RAFT_PROTOCOL";
+const ENCODING_VAR: &str = "RAFT_ENCODING";
+const CHECK_VAR: &str = "RAFT_ENCODING_CHECK";
+// Starts a binary frame on a TCP connection, followed by the frame's length
+// as a big-endian u32. MessagePack never uses this byte.
+const BINARY_FRAME: u8 = 0xc1;
+// Stands in for Maelstrom as the sender of `init`; replies to it are dropped.
+const LOCAL: &str = "local";
+const CONNECT_TIMEOUT: Duration = Duration::from_millis(200);
+const WRITE_TIMEOUT: Duration = Duration::from_millis(500);
+// While a peer cannot be reached, messages to it are dropped, as a network
+// would, rather than holding up everything else we send. We try it again
+// after this long.
+const RECONNECT: Duration = Duration::from_secs(1);
+const MAX_DATAGRAM: usize = 65507;
+// The longest line or binary frame we read. A connection sending a longer one
+// is dropped rather than trusted with the allocation.
+const MAX_FRAME: usize = 16 << 20;
+
+#[derive(Deserialize)]
+struct Source {
+    src: String,
+}
+
+#[derive(Deserialize)]
+struct Destination {
+    dest: String,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Encoding {
+    Json,
+    MessagePack,
+}
+
+// Where replies to a client go, and how they want them encoded.
+enum Return {
+    Tcp(TcpStream, Encoding),
+    Udp(SocketAddr, Encoding),
+}
+
+type Clients = Arc<Mutex<HashMap<String, Return>>>;
+
+pub struct Network {
+    peers: BTreeMap<String, SocketAddr>,
+    udp: Option<UdpSocket>,
+    encoding: Encoding,
+    check: bool,
+    clients: Clients,
+    incoming: Mutex<Option<mpsc::UnboundedReceiver<String>>>,
+}
+
+// The network, once set up, if RAFT_PEERS asks for one.
+pub fn network() -> Option<&'static Network> {
+    static NETWORK: OnceLock<Option<Network>> = OnceLock::new();
+    NETWORK
+        .get_or_init(|| Some(Network::start(&env::var(PEERS_VAR).ok()?)))
+        .as_ref()
+}
+
+fn source(line: &str) -> Option<String> {
+    serde_json::from_str::<Source>(line).ok().map(|s| s.src)
+}
+
+// The JSON form of a binary message. One that does not decode is passed on as
+// is, for transport.rs to turn away as malformed.
+fn transcode(frame: &[u8]) -> String {
+    match rmp_serde::from_slice::<Value>(frame) {
+        Ok(message) => message.to_string(),
+        Err(error) => format!("undecodable MessagePack ({})", error),
+    }
+}
+
+// A datagram holds one message in either encoding; JSON starts with `{`.
+fn decode(datagram: &[u8]) -> (String, Encoding) {
+    match datagram.iter().find(|b| !b.is_ascii_whitespace()) {
+        Some(b'{') | None => (
+            String::from_utf8_lossy(datagram).into_owned(),
+            Encoding::Json,
+        ),
+        _ => (transcode(datagram), Encoding::MessagePack),
+    }
+}
+
+// Reads one line or binary frame; `None` once the connection is done.
+fn read_frame(reader: &mut BufReader<TcpStream>) -> Option<(String, Encoding)> {
+    let first = *reader.fill_buf().ok()?.first()?;
+    if first == BINARY_FRAME {
+        reader.consume(1);
+        let mut len = [0; 4];
+        reader.read_exact(&mut len).ok()?;
+        let len = u32::from_be_bytes(len) as usize;
+        if len > MAX_FRAME {
+            return oversized(reader, len);
+        }
+        let mut frame = vec![0; len];
+        reader.read_exact(&mut frame).ok()?;
+        return Some((transcode(&frame), Encoding::MessagePack));
+    }
+    let mut line = Vec::new();
+    match reader
+        .by_ref()
+        .take(MAX_FRAME as u64)
+        .read_until(b'\n', &mut line)
+    {
+        Ok(0) | Err(_) => None,
+        Ok(len) if len == MAX_FRAME && line.last() != Some(&b'\n') => oversized(reader, len),
+        Ok(_) => Some((String::from_utf8_lossy(&line).into_owned(), Encoding::Json)),
+    }
+}
+
+fn oversized<T>(reader: &BufReader<TcpStream>, len: usize) -> Option<T> {
+    warn!("Dropping a connection that sent a {}+ byte frame", len);
+    reader.get_ref().shutdown(Shutdown::Both).ok();
+    None
+}
+
+// Each connection gets a thread, so a slow client holds up no one else.
+fn read_tcp(
+    stream: TcpStream,
+    peers: BTreeMap<String, SocketAddr>,
+    clients: Clients,
+    incoming: mpsc::UnboundedSender<String>,
+) {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    while let Some((line, encoding)) = read_frame(&mut reader) {
+        if let Some(src) = source(&line).filter(|src| !peers.contains_key(src)) {
+            if let Ok(stream) = stream.try_clone() {
+                clients
+                    .lock()
+                    .unwrap()
+                    .insert(src, Return::Tcp(stream, encoding));
+            }
+        }
+        if incoming.send(line).is_err() {
+            return;
+        }
+    }
+}
+
+fn read_udp(
+    socket: UdpSocket,
+    peers: BTreeMap<String, SocketAddr>,
+    clients: Clients,
+    incoming: mpsc::UnboundedSender<String>,
+) {
+    let mut buffer = vec![0; MAX_DATAGRAM];
+    while let Ok((len, from)) = socket.recv_from(&mut buffer) {
+        let (line, encoding) = decode(&buffer[..len]);
+        if let Some(src) = source(&line).filter(|src| !peers.contains_key(src)) {
+            clients
+                .lock()
+                .unwrap()
+                .insert(src, Return::Udp(from, encoding));
+        }
+        if incoming.send(line).is_err() {
+            return;
+        }
+    }
+}
+
+impl Network {
+    fn start(path: &str) -> Network {
+        let map = fs::read_to_string(path)
+            .unwrap_or_else(|error| panic!("cannot read peer map {}: {}", path, error));
+        let map: BTreeMap<String, String> = serde_json::from_str(&map)
+            .unwrap_or_else(|error| panic!("cannot parse peer map {}: {}", path, error));
+        let peers: BTreeMap<String, SocketAddr> = map
+            .into_iter()
+            .map(|(node, address)| {
+                let resolved = address.to_socket_addrs().ok().and_then(|mut a| a.next());
+                let resolved =
+                    resolved.unwrap_or_else(|| panic!("cannot resolve {} for {}", address, node));
+                (node, resolved)
+            })
+            .collect();
+        let node = env::var(NODE_VAR).unwrap_or_else(|_| panic!("{} is not set", NODE_VAR));
+        let address = *peers
+            .get(&node)
+            .unwrap_or_else(|| panic!("{} is not in {}", node, path));
+        let udp = env::var(PROTOCOL_VAR).as_deref() == Ok("udp");
+        let encoding = match env::var(ENCODING_VAR).as_deref() {
+            Ok("msgpack") => Encoding::MessagePack,
+            _ => Encoding::Json,
+        };
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        let init = json!({
+            "src": LOCAL,
+            "dest": node,
+            "body": {
+                "type": "init",
+                "msg_id": 0,
+                "node_id": node,
+                "node_ids": peers.keys().collect::<Vec<_>>(),
+            },
+        });
+        tx.send(init.to_string()).unwrap();
+
+        let clients = Clients::default();
+        let (shared, listening) = (peers.clone(), clients.clone());
+        let socket = match udp {
+            true => {
+                let socket = UdpSocket::bind(address)
+                    .unwrap_or_else(|error| panic!("cannot bind {}: {}", address, error));
+                let reader = socket.try_clone().unwrap();
+                thread::spawn(move || read_udp(reader, shared, listening, tx));
+                Some(socket)
+            }
+            _ => {
+                let listener = TcpListener::bind(address)
+                    .unwrap_or_else(|error| panic!("cannot listen on {}: {}", address, error));
+                thread::spawn(move || {
+                    for stream in listener.incoming().flatten() {
+                        stream.set_write_timeout(Some(WRITE_TIMEOUT)).unwrap();
+                        let (peers, clients, tx) = (shared.clone(), listening.clone(), tx.clone());
+                        thread::spawn(move || read_tcp(stream, peers, clients, tx));
+                    }
+                });
+                None
+            }
+        };
+        info!(
+            "Node {} listening on {} over {}, sending {}",
+            node,
+            address,
+            if udp { "udp" } else { "tcp" },
+            if encoding == Encoding::MessagePack {
+                "msgpack"
+            } else {
+                "json"
+            }
+        );
+        Network {
+            peers,
+            udp: socket,
+            encoding,
+            check: env::var(CHECK_VAR).is_ok(),
+            clients,
+            incoming: Mutex::new(Some(rx)),
+        }
+    }
+
+    // Every message sent to this node, starting with its `init`. Can be taken
+    // once.
+    pub fn incoming(&self) -> mpsc::UnboundedReceiver<String> {
+        self.incoming.lock().unwrap().take().unwrap()
+    }
+}
+
+// Sends lines to wherever their dest is. Owned by transport.rs's writer, which
+// calls `send` for each line of a batch and `flush` at the end of it.
+pub struct Router {
+    network: &'static Network,
+    connections: HashMap<String, BufWriter<TcpStream>>,
+    unreachable: HashMap<String, Instant>,
+}
+
+impl Router {
+    pub fn new(network: &'static Network) -> Router {
+        Router {
+            network,
+            connections: HashMap::new(),
+            unreachable: HashMap::new(),
+        }
+    }
+
+    // The bytes of one message in the given encoding, without framing.
+    fn encode(&self, line: &str, encoding: Encoding) -> Vec<u8> {
+        if encoding == Encoding::Json {
+            return line.as_bytes().to_vec();
+        }
+        let message: Value = serde_json::from_str(line).unwrap();
+        let binary = to_msgpack(&message);
+        let r#type = message["body"]["type"].as_str().unwrap_or_default();
+        metrics::encoded(r#type, line.len(), binary.len());
+        if self.network.check {
+            let decoded = rmp_serde::from_slice::<Value>(&binary).ok();
+            let matches = decoded.as_ref() == Some(&message);
+            if !matches {
+                warn!("{} does not survive MessagePack: {:?}", line, decoded);
+            }
+            metrics::round_trip(matches);
+        }
+        binary
+    }
+
+    pub fn send(&mut self, line: &str) {
+        let dest = match serde_json::from_str::<Destination>(line) {
+            Ok(destination) => destination.dest,
+            Err(_) => return,
+        };
+        if let Some(socket) = &self.network.udp {
+            let route = match self.network.peers.get(&dest) {
+                Some(address) => Some((*address, self.network.encoding)),
+                None => match self.network.clients.lock().unwrap().get(&dest) {
+                    Some(Return::Udp(address, encoding)) => Some((*address, *encoding)),
+                    _ => None,
+                },
+            };
+            match route {
+                Some((address, encoding)) => {
+                    if let Err(error) = socket.send_to(&self.encode(line, encoding), address) {
+                        debug!("Cannot send to {}: {}", dest, error);
+                    }
+                }
+                None if dest != LOCAL => debug!("No route to {}", dest),
+                None => {}
+            }
+            return;
+        }
+        if let Some(address) = self.network.peers.get(&dest).copied() {
+            let frame = frame(
+                self.encode(line, self.network.encoding),
+                self.network.encoding,
+            );
+            self.send_to_peer(dest, address, &frame);
+            return;
+        }
+        let mut clients = self.network.clients.lock().unwrap();
+        let (mut stream, encoding): (&TcpStream, Encoding) = match clients.get(&dest) {
+            Some(Return::Tcp(stream, encoding)) => (stream, *encoding),
+            _ => {
+                if dest != LOCAL {
+                    debug!("No route to {}", dest);
+                }
+                return;
+            }
+        };
+        if stream
+            .write_all(&frame(self.encode(line, encoding), encoding))
+            .is_err()
+        {
+            clients.remove(&dest);
+        }
+    }
+
+    fn send_to_peer(&mut self, dest: String, address: SocketAddr, frame: &[u8]) {
+        if !self.connections.contains_key(&dest) {
+            if let Some(failed) = self.unreachable.get(&dest) {
+                if failed.elapsed() < RECONNECT {
+                    return;
+                }
+            }
+            match TcpStream::connect_timeout(&address, CONNECT_TIMEOUT) {
+                Ok(stream) => {
+                    stream.set_write_timeout(Some(WRITE_TIMEOUT)).unwrap();
+                    stream.set_nodelay(true).unwrap();
+                    self.unreachable.remove(&dest);
+                    self.connections
+                        .insert(dest.clone(), BufWriter::new(stream));
+                }
+                Err(error) => {
+                    warn!("Cannot connect to {} at {}: {}", dest, address, error);
+                    self.unreachable.insert(dest, Instant::now());
+                    return;
+                }
+            }
+        }
+        let connection = self.connections.get_mut(&dest).unwrap();
+        if connection.write_all(frame).is_err() {
+            self.connections.remove(&dest);
+            self.unreachable.insert(dest, Instant::now());
+        }
+    }
+
+    pub fn flush(&mut self) {
+        let mut broken = Vec::new();
+        for (dest, connection) in &mut self.connections {
+            if connection.flush().is_err() {
+                broken.push(dest.clone());
+            }
+        }
+        for dest in broken {
+            self.connections.remove(&dest);
+            self.unreachable.insert(dest, Instant::now());
+        }
+    }
+}
+
+fn to_msgpack(message: &Value) -> Vec<u8> {
+    rmp_serde::to_vec_named(message).unwrap()
+}
+
+// A message as it goes on a TCP connection: a JSON line, or a binary frame.
+fn frame(mut bytes: Vec<u8>, encoding: Encoding) -> Vec<u8> {
+    match encoding {
+        Encoding::Json => bytes.push(b'\n'),
+        Encoding::MessagePack => {
+            let len = (bytes.len() as u32).to_be_bytes();
+            bytes.splice(0..0, [BINARY_FRAME].into_iter().chain(len));
+        }
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The two ends of a loopback TCP connection.
+    fn connection() -> (TcpStream, BufReader<TcpStream>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (client, BufReader::new(server))
+    }
+
+    #[test]
+    fn messages_survive_messagepack() {
+        let messages = [
+            // An or-set: each element's set of dots, and the causal context.
+            r#"{"src":"n0","dest":"n1","body":{"type":"replicate","message":{"entries":{"1":[["n0",1],["n1",4]],"2":[]},"context":{"n0":1,"n1":4}}}}"#,
+            // Nested maps of committed writes, keyed by number, and nulls.
+            r#"{"src":"n0","dest":"n1","body":{"type":"replicate","msg_id":7,"msg":{"version":[3,"n0"],"writes":{"1":[1,2],"2":null,"3":{"a":{"b":[]}}}}}}"#,
+            r#"{"src":"n1","dest":"c1","body":{"type":"txn_ok","in_reply_to":4,"txn":[["r",1,null],["append",2,-1]]}}"#,
+            // The ends of the i64 range.
+            r#"{"src":"c1","dest":"n0","body":{"type":"add","msg_id":9223372036854775807,"delta":-9223372036854775808}}"#,
+            r#"{"src":"n0","dest":"c1","body":{"type":"poll_ok","in_reply_to":1,"msgs":{"k1":[[0,9223372036854775807],[1,-9223372036854775808]]}}}"#,
+        ];
+        let (mut client, mut server) = connection();
+        for line in messages {
+            let message: Value = serde_json::from_str(line).unwrap();
+            let encoded = frame(to_msgpack(&message), Encoding::MessagePack);
+            client.write_all(&encoded).unwrap();
+            let (decoded, encoding) = read_frame(&mut server).unwrap();
+            assert!(encoding == Encoding::MessagePack);
+            assert_eq!(serde_json::from_str::<Value>(&decoded).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn oversized_frames_drop_the_connection() {
+        let (mut client, mut server) = connection();
+        let mut header = vec![BINARY_FRAME];
+        header.extend(u32::MAX.to_be_bytes());
+        client.write_all(&header).unwrap();
+        assert!(read_frame(&mut server).is_none());
+        let mut buffer = [0; 1];
+        assert_eq!(client.read(&mut buffer).unwrap(), 0);
+    }
+
+    #[test]
+    fn oversized_lines_drop_the_connection() {
+        let (mut client, mut server) = connection();
+        let writer = thread::spawn(move || {
+            client.write_all(&vec![b' '; MAX_FRAME + 1]).ok();
+        });
+        assert!(read_frame(&mut server).is_none());
+        writer.join().unwrap();
+    }
+}
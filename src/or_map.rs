@@ -0,0 +1,546 @@
+use crate::{config, transport};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::cmp::max;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use tokio::time::{sleep, Duration};
+use tracing::info;
+
+struct Node {
+    id: String,
+    neighbours: Vec<String>,
+    next_msg_id: i64,
+    map: ThreadMap,
+}
+
+type ThreadMap = Arc<Mutex<OrMap>>;
+type GCounter = HashMap<String, i64>;
+const KEY_DOES_NOT_EXIST: i64 = 20;
+const PRECONDITION_FAILED: i64 = 22;
+// Every this many gossip rounds we send every key instead of just the changed
+// ones, so a lost delta is eventually repaired.
+const FULL_SYNC_ROUNDS: i64 = 12;
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+#[serde(tag = "crdt", content = "state", rename_all = "kebab-case")]
+enum Crdt {
+    GCounter(GCounter),
+    PnCounter([GCounter; 2]),
+    GSet(HashSet<i64>),
+}
+
+fn merge_max(ours: &mut GCounter, theirs: &GCounter) {
+    for (k, v) in theirs {
+        let current = ours.entry(k.to_string()).or_insert(0);
+        *current = max(*current, *v);
+    }
+}
+
+impl Crdt {
+    fn new(kind: &str) -> Option<Crdt> {
+        match kind {
+            "g-counter" => Some(Crdt::GCounter(HashMap::new())),
+            "pn-counter" => Some(Crdt::PnCounter([HashMap::new(), HashMap::new()])),
+            "g-set" => Some(Crdt::GSet(HashSet::new())),
+            _ => None,
+        }
+    }
+
+    fn kind(&self) -> &str {
+        match self {
+            Crdt::GCounter(_) => "g-counter",
+            Crdt::PnCounter(_) => "pn-counter",
+            Crdt::GSet(_) => "g-set",
+        }
+    }
+
+    // Applies a client `add`, returning false if the body does not fit this CRDT.
+    fn apply(&mut self, id: &str, body: &Value) -> bool {
+        match (self, body["delta"].as_i64(), body["element"].as_i64()) {
+            (Crdt::GCounter(c), Some(delta @ 0..), _) => {
+                *c.entry(id.to_string()).or_insert(0) += delta;
+                true
+            }
+            (Crdt::PnCounter(c), Some(delta), _) => {
+                match delta {
+                    0.. => *c[0].entry(id.to_string()).or_insert(0) += delta,
+                    _ => *c[1].entry(id.to_string()).or_insert(0) -= delta,
+                }
+                true
+            }
+            (Crdt::GSet(s), _, Some(element)) => {
+                s.insert(element);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn merge(&mut self, other: &Crdt) {
+        match (self, other) {
+            (Crdt::GCounter(a), Crdt::GCounter(b)) => merge_max(a, b),
+            (Crdt::PnCounter(a), Crdt::PnCounter(b)) => {
+                merge_max(&mut a[0], &b[0]);
+                merge_max(&mut a[1], &b[1]);
+            }
+            (Crdt::GSet(a), Crdt::GSet(b)) => a.extend(b),
+            // Different kinds added concurrently: the greater kind wins
+            // outright, so every replica settles on the same one.
+            (this, other) => {
+                if other.kind() > this.kind() {
+                    *this = other.clone();
+                }
+            }
+        }
+    }
+
+    fn value(&self) -> Value {
+        match self {
+            Crdt::GCounter(c) => Value::from(c.values().sum::<i64>()),
+            Crdt::PnCounter(c) => {
+                Value::from(c[0].values().sum::<i64>() - c[1].values().sum::<i64>())
+            }
+            Crdt::GSet(s) => Value::from(s.iter().copied().collect::<Vec<i64>>()),
+        }
+    }
+}
+
+// State of a single key: each replica's live add dot, with everything that
+// replica added under it, and the causal context of everything observed for
+// this key. A key with no dots has been removed; its context is kept so the
+// removal can win over stale replicas. Because the nested state lives on the
+// dots, a remove discards exactly the additions it observed, and an add
+// concurrent with it survives without bringing the removed ones back.
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq)]
+struct Entry {
+    dots: HashMap<String, (i64, Crdt)>,
+    context: HashMap<String, i64>,
+}
+
+impl Entry {
+    fn covers(&self, id: &str, counter: i64) -> bool {
+        match self.context.get(id) {
+            Some(seen) => counter <= *seen,
+            None => false,
+        }
+    }
+
+    // A dot survives if both sides have it or the other side never saw it.
+    fn live(&self, other: &Entry, id: &str, counter: i64) -> bool {
+        other.dots.get(id).is_some_and(|(c, _)| *c == counter) || !other.covers(id, counter)
+    }
+
+    fn merge(&mut self, other: &Entry) {
+        let mut dots: HashMap<String, (i64, Crdt)> = HashMap::new();
+        for (a, b) in [(&*self, other), (other, &*self)] {
+            for (id, (counter, value)) in &a.dots {
+                if a.live(b, id, *counter) {
+                    dots.insert(id.clone(), (*counter, value.clone()));
+                }
+            }
+        }
+        // Dots of a kind that lost to a concurrent add are dropped; the
+        // context still records them, as it would a removal.
+        if let Some(kind) = dots.values().map(|(_, v)| v.kind().to_string()).max() {
+            dots.retain(|_, (_, v)| v.kind() == kind);
+        }
+        self.dots = dots;
+        merge_max(&mut self.context, &other.context);
+    }
+
+    fn value(&self) -> Option<Crdt> {
+        let mut dots = self.dots.values().map(|(_, v)| v);
+        let mut value = dots.next()?.clone();
+        for v in dots {
+            value.merge(v);
+        }
+        Some(value)
+    }
+}
+
+#[derive(Default)]
+struct OrMap {
+    entries: HashMap<String, Entry>,
+    dirty: HashMap<String, HashSet<String>>,
+}
+
+impl OrMap {
+    fn touch(&mut self, key: &str) {
+        for keys in self.dirty.values_mut() {
+            keys.insert(key.to_string());
+        }
+    }
+
+    fn add(&mut self, id: &str, key: &str, kind: &str, body: &Value) -> bool {
+        let entry = self.entries.entry(key.to_string()).or_default();
+        // A live key keeps the kind it was first added as.
+        if entry.dots.values().any(|(_, v)| v.kind() != kind) {
+            return false;
+        }
+        let mut value = match entry.dots.get(id) {
+            Some((_, value)) => value.clone(),
+            None => match Crdt::new(kind) {
+                Some(value) => value,
+                None => return false,
+            },
+        };
+        if !value.apply(id, body) {
+            return false;
+        }
+        let counter = entry.context.entry(id.to_string()).or_insert(0);
+        *counter += 1;
+        entry.dots.insert(id.to_string(), (*counter, value));
+        self.touch(key);
+        true
+    }
+
+    fn remove(&mut self, key: &str) -> bool {
+        match self.entries.get_mut(key) {
+            Some(entry) if !entry.dots.is_empty() => {
+                entry.dots.clear();
+                self.touch(key);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn merge(&mut self, other: HashMap<String, Entry>) {
+        for (k, e) in other {
+            let entry = self.entries.entry(k.clone()).or_default();
+            let before = entry.clone();
+            entry.merge(&e);
+            if *entry != before {
+                self.touch(&k);
+            }
+        }
+    }
+
+    // Keys changed since the last round sent to `dest`, or all keys on a full sync.
+    fn delta(&mut self, dest: &str, full: bool) -> HashMap<String, Entry> {
+        let keys = self.dirty.entry(dest.to_string()).or_default();
+        let delta = self
+            .entries
+            .iter()
+            .filter(|(k, _)| full || keys.contains(*k))
+            .map(|(k, e)| (k.clone(), e.clone()))
+            .collect();
+        keys.clear();
+        delta
+    }
+
+    fn get(&self, key: &str) -> Option<Value> {
+        self.entries.get(key)?.value().map(|v| v.value())
+    }
+
+    fn value(&self) -> HashMap<&str, Value> {
+        self.entries
+            .keys()
+            .filter_map(|k| self.get(k).map(|v| (k.as_str(), v)))
+            .collect()
+    }
+}
+
+async fn replicate(dest: String, src: String, map: ThreadMap) {
+    let mut round = 0;
+    loop {
+        {
+            let mut map = map.lock().unwrap();
+            let delta = map.delta(&dest, round % FULL_SYNC_ROUNDS == 0);
+            if !delta.is_empty() {
+                let message = Reply {
+                    dest: &dest,
+                    src: &src,
+                    body: ResponseBody::Replicate {
+                        r#type: "replicate",
+                        msg: &delta,
+                    },
+                };
+                transport::send(&message);
+            }
+        }
+        round += 1;
+        sleep(config::gossip_interval(Duration::from_millis(5000))).await;
+    }
+}
+
+impl Node {
+    fn new(id: String, neighbours: Vec<String>) -> Node {
+        let mut node = Node {
+            id,
+            neighbours,
+            next_msg_id: 0,
+            map: Arc::new(Mutex::new(OrMap::default())),
+        };
+        node.replicate_neighbours();
+        node
+    }
+    fn replicate_neighbours(&mut self) {
+        for n in &self.neighbours {
+            if *n != self.id {
+                let mut map = self.map.lock().unwrap();
+                map.dirty.insert(n.clone(), HashSet::new());
+                tokio::spawn(replicate(n.clone(), self.id.clone(), self.map.clone()));
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+enum ResponseBody<'a> {
+    #[serde(rename = "body")]
+    Init {
+        msg_id: i64,
+        r#type: &'a str,
+        in_reply_to: i64,
+    },
+    #[serde(rename = "body")]
+    Replicate {
+        r#type: &'a str,
+        msg: &'a HashMap<String, Entry>,
+    },
+    #[serde(rename = "body")]
+    Add {
+        r#type: &'a str,
+        in_reply_to: i64,
+        msg_id: i64,
+    },
+    #[serde(rename = "body")]
+    Remove {
+        r#type: &'a str,
+        in_reply_to: i64,
+        msg_id: i64,
+    },
+    #[serde(rename = "body")]
+    Read {
+        r#type: &'a str,
+        value: Value,
+        in_reply_to: i64,
+    },
+    #[serde(rename = "body")]
+    Error {
+        r#type: &'a str,
+        in_reply_to: i64,
+        code: i64,
+        text: &'a str,
+        msg_id: i64,
+    },
+}
+
+#[derive(Serialize)]
+struct Reply<'a> {
+    src: &'a str,
+    dest: &'a str,
+    #[serde(flatten)]
+    body: ResponseBody<'a>,
+}
+
+pub async fn run() {
+    let mut node: Option<Node> = None;
+    while let Some(parsed) = transport::receive().await {
+        let _span = transport::request_span(&parsed).entered();
+        let body = &parsed["body"];
+        match body["type"].as_str().unwrap() {
+            "init" => {
+                node = Some(Node::new(
+                    body["node_id"].as_str().unwrap().to_string(),
+                    serde_json::from_value(body["node_ids"].clone()).unwrap(),
+                ));
+
+                info!("Initialized node {:?}", node.as_ref().map(|s| &s.id));
+                if let Some(s) = node.as_mut() {
+                    s.next_msg_id += 1;
+                }
+                let reply = Reply {
+                    dest: parsed["src"].as_str().unwrap(),
+                    src: node.as_ref().map(|s| &s.id).unwrap(),
+                    body: ResponseBody::Init {
+                        msg_id: node.as_ref().map(|s| s.next_msg_id).unwrap(),
+                        r#type: "init_ok",
+                        in_reply_to: body["msg_id"].as_i64().unwrap(),
+                    },
+                };
+                transport::send(&reply);
+            }
+            "add" => {
+                if let Some(s) = node.as_mut() {
+                    let applied = {
+                        let mut map = s.map.lock().unwrap();
+                        map.add(
+                            &s.id,
+                            body["key"].as_str().unwrap(),
+                            body["crdt"].as_str().unwrap_or("g-counter"),
+                            body,
+                        )
+                    };
+                    s.next_msg_id += 1;
+                    let reply = match applied {
+                        true => Reply {
+                            dest: parsed["src"].as_str().unwrap(),
+                            src: &s.id,
+                            body: ResponseBody::Add {
+                                msg_id: s.next_msg_id,
+                                r#type: "add_ok",
+                                in_reply_to: body["msg_id"].as_i64().unwrap(),
+                            },
+                        },
+                        _ => Reply {
+                            dest: parsed["src"].as_str().unwrap(),
+                            src: &s.id,
+                            body: ResponseBody::Error {
+                                msg_id: s.next_msg_id,
+                                r#type: "error",
+                                in_reply_to: body["msg_id"].as_i64().unwrap(),
+                                text: "Add does not match the key's CRDT",
+                                code: PRECONDITION_FAILED,
+                            },
+                        },
+                    };
+                    transport::send(&reply);
+                }
+            }
+            "remove" => {
+                if let Some(s) = node.as_mut() {
+                    let removed = {
+                        let mut map = s.map.lock().unwrap();
+                        map.remove(body["key"].as_str().unwrap())
+                    };
+                    s.next_msg_id += 1;
+                    let reply = match removed {
+                        true => Reply {
+                            dest: parsed["src"].as_str().unwrap(),
+                            src: &s.id,
+                            body: ResponseBody::Remove {
+                                msg_id: s.next_msg_id,
+                                r#type: "remove_ok",
+                                in_reply_to: body["msg_id"].as_i64().unwrap(),
+                            },
+                        },
+                        _ => Reply {
+                            dest: parsed["src"].as_str().unwrap(),
+                            src: &s.id,
+                            body: ResponseBody::Error {
+                                msg_id: s.next_msg_id,
+                                r#type: "error",
+                                in_reply_to: body["msg_id"].as_i64().unwrap(),
+                                text: "Key does not exist",
+                                code: KEY_DOES_NOT_EXIST,
+                            },
+                        },
+                    };
+                    transport::send(&reply);
+                }
+            }
+            "replicate" => {
+                if let Some(s) = node.as_mut() {
+                    let r: HashMap<String, Entry> =
+                        serde_json::from_value(body["msg"].clone()).unwrap();
+                    let mut map = s.map.lock().unwrap();
+                    map.merge(r);
+                }
+            }
+            "read" => {
+                if let Some(s) = node.as_mut() {
+                    s.next_msg_id += 1;
+                    let map = s.map.lock().unwrap();
+                    let value = match body["key"].as_str() {
+                        Some(key) => map.get(key),
+                        None => Some(serde_json::to_value(map.value()).unwrap()),
+                    };
+                    let reply = match value {
+                        Some(value) => Reply {
+                            dest: parsed["src"].as_str().unwrap(),
+                            src: &s.id,
+                            body: ResponseBody::Read {
+                                value,
+                                r#type: "read_ok",
+                                in_reply_to: body["msg_id"].as_i64().unwrap(),
+                            },
+                        },
+                        None => Reply {
+                            dest: parsed["src"].as_str().unwrap(),
+                            src: &s.id,
+                            body: ResponseBody::Error {
+                                msg_id: s.next_msg_id,
+                                r#type: "error",
+                                in_reply_to: body["msg_id"].as_i64().unwrap(),
+                                text: "Key does not exist",
+                                code: KEY_DOES_NOT_EXIST,
+                            },
+                        },
+                    };
+                    transport::send(&reply);
+                }
+            }
+            _ => continue,
+        }
+    }
+    transport::shutdown().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn add(map: &mut OrMap, id: &str, kind: &str, body: Value) {
+        assert!(map.add(id, "k", kind, &body));
+    }
+
+    // Merges each map's entries into the other.
+    fn sync(a: &mut OrMap, b: &mut OrMap) {
+        let (ours, theirs) = (a.entries.clone(), b.entries.clone());
+        a.merge(theirs);
+        b.merge(ours);
+    }
+
+    #[test]
+    fn replicas_converge_whatever_the_merge_order() {
+        let (mut a, mut b, mut c) = (OrMap::default(), OrMap::default(), OrMap::default());
+        add(&mut a, "n0", "pn-counter", json!({"delta": 5}));
+        add(&mut b, "n1", "pn-counter", json!({"delta": -2}));
+        add(&mut c, "n2", "pn-counter", json!({"delta": 3}));
+        let mut ab = OrMap::default();
+        ab.merge(a.entries.clone());
+        ab.merge(b.entries.clone());
+        ab.merge(c.entries.clone());
+        let mut cb = OrMap::default();
+        cb.merge(c.entries.clone());
+        cb.merge(b.entries.clone());
+        cb.merge(a.entries.clone());
+        // Merging the same state again changes nothing.
+        cb.merge(b.entries.clone());
+        assert!(ab.entries == cb.entries);
+        assert_eq!(ab.get("k"), Some(json!(6)));
+    }
+
+    #[test]
+    fn concurrent_kinds_resolve_to_the_same_winner() {
+        let (mut a, mut b) = (OrMap::default(), OrMap::default());
+        add(&mut a, "n0", "g-set", json!({"element": 1}));
+        add(&mut b, "n1", "g-counter", json!({"delta": 4}));
+        sync(&mut a, &mut b);
+        assert!(a.entries == b.entries);
+        assert_eq!(a.get("k"), Some(json!([1])));
+        // The key is a g-set now, so a counter add no longer fits it.
+        assert!(!b.add("n1", "k", "g-counter", &json!({"delta": 1})));
+    }
+
+    #[test]
+    fn a_concurrent_re_add_does_not_resurrect_removed_state() {
+        let (mut a, mut b) = (OrMap::default(), OrMap::default());
+        add(&mut a, "n0", "g-counter", json!({"delta": 5}));
+        sync(&mut a, &mut b);
+        assert!(a.remove("k"));
+        add(&mut b, "n1", "g-counter", json!({"delta": 1}));
+        sync(&mut a, &mut b);
+        assert_eq!(a.get("k"), Some(json!(1)));
+        assert_eq!(b.get("k"), Some(json!(1)));
+        // A remove that saw everything wins on both sides.
+        assert!(b.remove("k"));
+        sync(&mut a, &mut b);
+        assert_eq!(a.get("k"), None);
+        assert_eq!(b.get("k"), None);
+    }
+}
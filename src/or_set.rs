@@ -0,0 +1,330 @@
+use crate::{config, transport};
+use serde::{Deserialize, Serialize};
+use std::cmp::max;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use tokio::time::{sleep, Duration};
+use tracing::{debug, info};
+
+struct Node {
+    id: String,
+    neighbours: Vec<String>,
+    next_msg_id: i64,
+    set: ThreadSet,
+}
+
+type ThreadSet = Arc<Mutex<OrSet>>;
+type Dot = (String, i64);
+
+// Observed-remove set without tombstones. Every add is tagged with a fresh dot
+// (node, counter) and `context` records the highest counter seen per node. A dot
+// missing from `entries` but covered by `context` has been removed.
+#[derive(Serialize, Deserialize, Default)]
+struct OrSet {
+    entries: HashMap<i64, HashSet<Dot>>,
+    context: HashMap<String, i64>,
+}
+
+impl OrSet {
+    fn add(&mut self, id: &str, element: i64) {
+        let counter = self.context.entry(id.to_string()).or_insert(0);
+        *counter += 1;
+        // The new dot supersedes every dot we have observed for this element.
+        self.entries
+            .insert(element, HashSet::from([(id.to_string(), *counter)]));
+    }
+
+    fn remove(&mut self, element: i64) {
+        self.entries.remove(&element);
+    }
+
+    fn covers(&self, dot: &Dot) -> bool {
+        match self.context.get(&dot.0) {
+            Some(counter) => dot.1 <= *counter,
+            None => false,
+        }
+    }
+
+    fn merge(&mut self, other: OrSet) {
+        let mut entries: HashMap<i64, HashSet<Dot>> = HashMap::new();
+        let elements: HashSet<i64> = self
+            .entries
+            .keys()
+            .chain(other.entries.keys())
+            .copied()
+            .collect();
+        for e in elements {
+            let empty = HashSet::new();
+            let ours = self.entries.get(&e).unwrap_or(&empty);
+            let theirs = other.entries.get(&e).unwrap_or(&empty);
+            // Keep dots both sides agree on, plus dots the other side has not yet
+            // seen (and so cannot have removed).
+            let dots: HashSet<Dot> = ours
+                .intersection(theirs)
+                .chain(ours.difference(theirs).filter(|d| !other.covers(d)))
+                .chain(theirs.difference(ours).filter(|d| !self.covers(d)))
+                .cloned()
+                .collect();
+            if !dots.is_empty() {
+                entries.insert(e, dots);
+            }
+        }
+        self.entries = entries;
+        for (k, v) in other.context {
+            let current = self.context.entry(k).or_insert(0);
+            *current = max(*current, v);
+        }
+    }
+
+    fn value(&self) -> HashSet<i64> {
+        self.entries.keys().copied().collect()
+    }
+}
+
+async fn replicate(dest: String, src: String, set: ThreadSet) {
+    loop {
+        {
+            let set = set.lock().unwrap();
+            let message = Reply {
+                dest: &dest,
+                src: &src,
+                body: ResponseBody::Replicate {
+                    r#type: "replicate",
+                    message: &set,
+                },
+            };
+            transport::send(&message);
+        }
+        sleep(config::gossip_interval(Duration::from_millis(5000))).await;
+    }
+}
+
+impl Node {
+    fn new(id: String, neighbours: Vec<String>) -> Node {
+        let mut node = Node {
+            id,
+            neighbours,
+            next_msg_id: 0,
+            set: Arc::new(Mutex::new(OrSet::default())),
+        };
+        node.replicate_neighbours();
+        node
+    }
+    fn replicate_neighbours(&mut self) {
+        for n in &self.neighbours {
+            if *n != self.id {
+                tokio::spawn(replicate(n.clone(), self.id.clone(), self.set.clone()));
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+enum ResponseBody<'a> {
+    #[serde(rename = "body")]
+    Init {
+        msg_id: i64,
+        r#type: &'a str,
+        in_reply_to: i64,
+    },
+    #[serde(rename = "body")]
+    Topology {
+        msg_id: i64,
+        r#type: &'a str,
+        in_reply_to: i64,
+    },
+    #[serde(rename = "body")]
+    Replicate { r#type: &'a str, message: &'a OrSet },
+    #[serde(rename = "body")]
+    Add {
+        r#type: &'a str,
+        in_reply_to: i64,
+        msg_id: i64,
+    },
+    #[serde(rename = "body")]
+    Remove {
+        r#type: &'a str,
+        in_reply_to: i64,
+        msg_id: i64,
+    },
+    #[serde(rename = "body")]
+    Read {
+        r#type: &'a str,
+        value: HashSet<i64>,
+        in_reply_to: i64,
+    },
+}
+
+#[derive(Serialize)]
+struct Reply<'a> {
+    src: &'a str,
+    dest: &'a str,
+    #[serde(flatten)]
+    body: ResponseBody<'a>,
+}
+
+pub async fn run() {
+    let mut node: Option<Node> = None;
+    while let Some(parsed) = transport::receive().await {
+        let _span = transport::request_span(&parsed).entered();
+        let body = &parsed["body"];
+        match body["type"].as_str().unwrap() {
+            "init" => {
+                node = Some(Node::new(
+                    body["node_id"].as_str().unwrap().to_string(),
+                    serde_json::from_value(body["node_ids"].clone()).unwrap(),
+                ));
+
+                info!("Initialized node {:?}", node.as_ref().map(|s| &s.id));
+                if let Some(s) = node.as_mut() {
+                    s.next_msg_id += 1;
+                }
+                let reply = Reply {
+                    dest: parsed["src"].as_str().unwrap(),
+                    src: node.as_ref().map(|s| &s.id).unwrap(),
+                    body: ResponseBody::Init {
+                        msg_id: node.as_ref().map(|s| s.next_msg_id).unwrap(),
+                        r#type: "init_ok",
+                        in_reply_to: body["msg_id"].as_i64().unwrap(),
+                    },
+                };
+                transport::send(&reply);
+            }
+            "topology" => {
+                if let Some(s) = node.as_mut() {
+                    // A topology that leaves us out gives us no neighbours.
+                    let topology = body["topology"][&s.id].clone();
+                    s.neighbours = serde_json::from_value(topology).unwrap_or_default();
+                    debug!("My neighbours are {:?}", s.neighbours);
+                }
+                if let Some(s) = node.as_mut() {
+                    s.next_msg_id += 1;
+                }
+                let reply = Reply {
+                    dest: parsed["src"].as_str().unwrap(),
+                    src: node.as_ref().map(|s| &s.id).unwrap(),
+                    body: ResponseBody::Topology {
+                        msg_id: node.as_ref().map(|s| s.next_msg_id).unwrap(),
+                        r#type: "topology_ok",
+                        in_reply_to: body["msg_id"].as_i64().unwrap(),
+                    },
+                };
+                transport::send(&reply);
+            }
+            "add" => {
+                if let Some(s) = node.as_mut() {
+                    let mut set = s.set.lock().unwrap();
+                    set.add(&s.id, body["element"].as_i64().unwrap());
+                    s.next_msg_id += 1;
+
+                    let reply = Reply {
+                        dest: parsed["src"].as_str().unwrap(),
+                        src: &s.id,
+                        body: ResponseBody::Add {
+                            msg_id: s.next_msg_id,
+                            r#type: "add_ok",
+                            in_reply_to: body["msg_id"].as_i64().unwrap(),
+                        },
+                    };
+                    transport::send(&reply);
+                }
+            }
+            "remove" => {
+                if let Some(s) = node.as_mut() {
+                    let mut set = s.set.lock().unwrap();
+                    set.remove(body["element"].as_i64().unwrap());
+                    s.next_msg_id += 1;
+
+                    let reply = Reply {
+                        dest: parsed["src"].as_str().unwrap(),
+                        src: &s.id,
+                        body: ResponseBody::Remove {
+                            msg_id: s.next_msg_id,
+                            r#type: "remove_ok",
+                            in_reply_to: body["msg_id"].as_i64().unwrap(),
+                        },
+                    };
+                    transport::send(&reply);
+                }
+            }
+            "replicate" => {
+                if let Some(s) = node.as_mut() {
+                    let mut set = s.set.lock().unwrap();
+                    let r: OrSet = serde_json::from_value(body["message"].clone()).unwrap();
+                    set.merge(r);
+                }
+            }
+            "read" => {
+                if let Some(s) = node.as_mut() {
+                    s.next_msg_id += 1;
+                    let set = s.set.lock().unwrap();
+                    let reply = Reply {
+                        dest: parsed["src"].as_str().unwrap(),
+                        src: &s.id,
+                        body: ResponseBody::Read {
+                            value: set.value(),
+                            r#type: "read_ok",
+                            in_reply_to: body["msg_id"].as_i64().unwrap(),
+                        },
+                    };
+                    transport::send(&reply);
+                }
+            }
+            _ => continue,
+        }
+    }
+    transport::shutdown().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // What `replicate` sends: a full copy of the set.
+    fn copy(set: &OrSet) -> OrSet {
+        serde_json::from_value(serde_json::to_value(set).unwrap()).unwrap()
+    }
+
+    fn sync(a: &mut OrSet, b: &mut OrSet) {
+        let ours = copy(a);
+        a.merge(copy(b));
+        b.merge(ours);
+    }
+
+    #[test]
+    fn merges_commute_and_are_idempotent() {
+        let (mut a, mut b) = (OrSet::default(), OrSet::default());
+        a.add("n0", 1);
+        a.add("n0", 2);
+        b.add("n1", 2);
+        b.add("n1", 3);
+        let mut ab = copy(&a);
+        ab.merge(copy(&b));
+        let mut ba = copy(&b);
+        ba.merge(copy(&a));
+        assert_eq!(ab.value(), ba.value());
+        assert_eq!(ab.entries, ba.entries);
+        assert_eq!(ab.value(), HashSet::from([1, 2, 3]));
+        ab.merge(copy(&ba));
+        assert_eq!(ab.entries, ba.entries);
+        assert_eq!(ab.context, ba.context);
+    }
+
+    #[test]
+    fn a_remove_wins_only_over_the_adds_it_observed() {
+        let (mut a, mut b) = (OrSet::default(), OrSet::default());
+        a.add("n0", 1);
+        a.add("n0", 2);
+        sync(&mut a, &mut b);
+        // 1 is removed after being seen everywhere; 2 is removed while the
+        // other replica adds it again.
+        a.remove(1);
+        a.remove(2);
+        b.add("n1", 2);
+        sync(&mut a, &mut b);
+        assert_eq!(a.value(), HashSet::from([2]));
+        assert_eq!(b.value(), HashSet::from([2]));
+        // The surviving 2 is tagged only with the new add's dot.
+        assert_eq!(a.entries[&2], HashSet::from([("n1".to_string(), 1)]));
+    }
+}
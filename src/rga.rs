@@ -0,0 +1,418 @@
+use crate::{config, transport};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::cmp::max;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::time::{sleep, Duration};
+use tracing::info;
+
+struct Node {
+    id: String,
+    neighbours: Vec<String>,
+    next_msg_id: i64,
+    list: ThreadList,
+}
+
+type ThreadList = Arc<Mutex<Rga>>;
+// Lamport timestamp of the insert plus the inserting node, unique per element.
+type Id = (i64, String);
+const KEY_DOES_NOT_EXIST: i64 = 20;
+
+#[derive(Serialize, Deserialize, Clone)]
+struct Element {
+    id: Id,
+    after: Option<Id>,
+    value: Value,
+    deleted: bool,
+}
+
+// Replicated growable array. Every element remembers the element it was
+// inserted after; siblings are ordered newest first, and the list is the
+// depth-first walk of that tree. Deletes leave a tombstone so later inserts
+// can still anchor on the deleted element.
+#[derive(Serialize, Deserialize, Default, Clone)]
+struct Rga {
+    elements: HashMap<String, Element>,
+    #[serde(skip)]
+    clock: i64,
+}
+
+fn key(id: &Id) -> String {
+    format!("{}@{}", id.0, id.1)
+}
+
+impl Rga {
+    fn insert(&mut self, node: &str, after: Option<Id>, value: Value) -> Option<Id> {
+        if let Some(a) = &after {
+            if !self.elements.contains_key(&key(a)) {
+                return None;
+            }
+        }
+        self.clock += 1;
+        let id = (self.clock, node.to_string());
+        self.elements.insert(
+            key(&id),
+            Element {
+                id: id.clone(),
+                after,
+                value,
+                deleted: false,
+            },
+        );
+        Some(id)
+    }
+
+    fn delete(&mut self, id: &Id) -> bool {
+        match self.elements.get_mut(&key(id)) {
+            Some(e) => {
+                e.deleted = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn merge(&mut self, other: &Rga) {
+        for (k, e) in &other.elements {
+            self.clock = max(self.clock, e.id.0);
+            let ours = self.elements.entry(k.clone()).or_insert_with(|| e.clone());
+            ours.deleted |= e.deleted;
+        }
+    }
+
+    fn visible(&self) -> Vec<&Element> {
+        let mut children: HashMap<Option<String>, Vec<&Element>> = HashMap::new();
+        for e in self.elements.values() {
+            children
+                .entry(e.after.as_ref().map(key))
+                .or_default()
+                .push(e);
+        }
+        for siblings in children.values_mut() {
+            siblings.sort_by(|a, b| b.id.cmp(&a.id));
+        }
+        let mut list = Vec::new();
+        let mut stack: Vec<&Element> = Vec::new();
+        if let Some(roots) = children.get(&None) {
+            stack.extend(roots.iter().rev());
+        }
+        while let Some(e) = stack.pop() {
+            if !e.deleted {
+                list.push(e);
+            }
+            if let Some(c) = children.get(&Some(key(&e.id))) {
+                stack.extend(c.iter().rev());
+            }
+        }
+        list
+    }
+}
+
+async fn replicate(dest: String, src: String, list: ThreadList) {
+    loop {
+        {
+            let list = list.lock().unwrap();
+            let message = Reply {
+                dest: &dest,
+                src: &src,
+                body: ResponseBody::Replicate {
+                    r#type: "replicate",
+                    msg: &list,
+                },
+            };
+            transport::send(&message);
+        }
+        sleep(config::gossip_interval(Duration::from_millis(5000))).await;
+    }
+}
+
+impl Node {
+    fn new(id: String, neighbours: Vec<String>) -> Node {
+        let mut node = Node {
+            id,
+            neighbours,
+            next_msg_id: 0,
+            list: Arc::new(Mutex::new(Rga::default())),
+        };
+        node.replicate_neighbours();
+        node
+    }
+    fn replicate_neighbours(&mut self) {
+        for n in &self.neighbours {
+            if *n != self.id {
+                tokio::spawn(replicate(n.clone(), self.id.clone(), self.list.clone()));
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+enum ResponseBody<'a> {
+    #[serde(rename = "body")]
+    Init {
+        msg_id: i64,
+        r#type: &'a str,
+        in_reply_to: i64,
+    },
+    #[serde(rename = "body")]
+    Replicate { r#type: &'a str, msg: &'a Rga },
+    #[serde(rename = "body")]
+    Insert {
+        r#type: &'a str,
+        in_reply_to: i64,
+        msg_id: i64,
+        id: Id,
+    },
+    #[serde(rename = "body")]
+    Delete {
+        r#type: &'a str,
+        in_reply_to: i64,
+        msg_id: i64,
+    },
+    #[serde(rename = "body")]
+    Read {
+        r#type: &'a str,
+        value: Vec<&'a Value>,
+        ids: Vec<&'a Id>,
+        in_reply_to: i64,
+    },
+    #[serde(rename = "body")]
+    Error {
+        r#type: &'a str,
+        in_reply_to: i64,
+        code: i64,
+        text: &'a str,
+        msg_id: i64,
+    },
+}
+
+#[derive(Serialize)]
+struct Reply<'a> {
+    src: &'a str,
+    dest: &'a str,
+    #[serde(flatten)]
+    body: ResponseBody<'a>,
+}
+
+pub async fn run() {
+    let mut node: Option<Node> = None;
+    while let Some(parsed) = transport::receive().await {
+        let _span = transport::request_span(&parsed).entered();
+        let body = &parsed["body"];
+        match body["type"].as_str().unwrap() {
+            "init" => {
+                node = Some(Node::new(
+                    body["node_id"].as_str().unwrap().to_string(),
+                    serde_json::from_value(body["node_ids"].clone()).unwrap(),
+                ));
+
+                info!("Initialized node {:?}", node.as_ref().map(|s| &s.id));
+                if let Some(s) = node.as_mut() {
+                    s.next_msg_id += 1;
+                }
+                let reply = Reply {
+                    dest: parsed["src"].as_str().unwrap(),
+                    src: node.as_ref().map(|s| &s.id).unwrap(),
+                    body: ResponseBody::Init {
+                        msg_id: node.as_ref().map(|s| s.next_msg_id).unwrap(),
+                        r#type: "init_ok",
+                        in_reply_to: body["msg_id"].as_i64().unwrap(),
+                    },
+                };
+                transport::send(&reply);
+            }
+            "insert" => {
+                if let Some(s) = node.as_mut() {
+                    let id = {
+                        let mut list = s.list.lock().unwrap();
+                        list.insert(
+                            &s.id,
+                            serde_json::from_value(body["after"].clone()).unwrap(),
+                            body["value"].to_owned(),
+                        )
+                    };
+                    s.next_msg_id += 1;
+                    let reply = match id {
+                        Some(id) => Reply {
+                            dest: parsed["src"].as_str().unwrap(),
+                            src: &s.id,
+                            body: ResponseBody::Insert {
+                                msg_id: s.next_msg_id,
+                                r#type: "insert_ok",
+                                in_reply_to: body["msg_id"].as_i64().unwrap(),
+                                id,
+                            },
+                        },
+                        None => Reply {
+                            dest: parsed["src"].as_str().unwrap(),
+                            src: &s.id,
+                            body: ResponseBody::Error {
+                                msg_id: s.next_msg_id,
+                                r#type: "error",
+                                in_reply_to: body["msg_id"].as_i64().unwrap(),
+                                text: "Unknown element to insert after",
+                                code: KEY_DOES_NOT_EXIST,
+                            },
+                        },
+                    };
+                    transport::send(&reply);
+                }
+            }
+            "delete" => {
+                if let Some(s) = node.as_mut() {
+                    let deleted = {
+                        let mut list = s.list.lock().unwrap();
+                        list.delete(&serde_json::from_value(body["id"].clone()).unwrap())
+                    };
+                    s.next_msg_id += 1;
+                    let reply = match deleted {
+                        true => Reply {
+                            dest: parsed["src"].as_str().unwrap(),
+                            src: &s.id,
+                            body: ResponseBody::Delete {
+                                msg_id: s.next_msg_id,
+                                r#type: "delete_ok",
+                                in_reply_to: body["msg_id"].as_i64().unwrap(),
+                            },
+                        },
+                        _ => Reply {
+                            dest: parsed["src"].as_str().unwrap(),
+                            src: &s.id,
+                            body: ResponseBody::Error {
+                                msg_id: s.next_msg_id,
+                                r#type: "error",
+                                in_reply_to: body["msg_id"].as_i64().unwrap(),
+                                text: "Unknown element",
+                                code: KEY_DOES_NOT_EXIST,
+                            },
+                        },
+                    };
+                    transport::send(&reply);
+                }
+            }
+            "replicate" => {
+                if let Some(s) = node.as_mut() {
+                    let r: Rga = serde_json::from_value(body["msg"].clone()).unwrap();
+                    let mut list = s.list.lock().unwrap();
+                    list.merge(&r);
+                }
+            }
+            "read" => {
+                if let Some(s) = node.as_mut() {
+                    s.next_msg_id += 1;
+                    let list = s.list.lock().unwrap();
+                    let visible = list.visible();
+                    let reply = Reply {
+                        dest: parsed["src"].as_str().unwrap(),
+                        src: &s.id,
+                        body: ResponseBody::Read {
+                            value: visible.iter().map(|e| &e.value).collect(),
+                            ids: visible.iter().map(|e| &e.id).collect(),
+                            r#type: "read_ok",
+                            in_reply_to: body["msg_id"].as_i64().unwrap(),
+                        },
+                    };
+                    transport::send(&reply);
+                }
+            }
+            _ => continue,
+        }
+    }
+    transport::shutdown().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Runs replicas in-process under random interleavings of inserts, deletes
+    // and pairwise merges, then checks every replica reads the same list once
+    // all state has been exchanged.
+    #[test]
+    fn replicas_converge() {
+        let nodes = ["n0", "n1", "n2"];
+        for seed in 1..=200u64 {
+            let mut rng = seed.wrapping_mul(0x9E3779B97F4A7C15);
+            let mut next = |n: usize| {
+                rng ^= rng << 13;
+                rng ^= rng >> 7;
+                rng ^= rng << 17;
+                (rng % n as u64) as usize
+            };
+            let mut replicas: Vec<Rga> = vec![Rga::default(); nodes.len()];
+            for op in 0..200 {
+                let r = next(nodes.len());
+                match next(4) {
+                    0 | 1 => {
+                        let ids: Vec<Id> =
+                            replicas[r].visible().iter().map(|e| e.id.clone()).collect();
+                        let after = match next(ids.len() + 1) {
+                            0 => None,
+                            i => Some(ids[i - 1].clone()),
+                        };
+                        let id = replicas[r]
+                            .insert(nodes[r], after.clone(), Value::from(op))
+                            .unwrap();
+                        // Nothing is concurrent with a local insert, so it
+                        // lands right after its anchor.
+                        let list: Vec<Id> =
+                            replicas[r].visible().iter().map(|e| e.id.clone()).collect();
+                        let anchor =
+                            after.map_or(0, |a| list.iter().position(|i| *i == a).unwrap() + 1);
+                        assert_eq!(list[anchor], id, "seed {} misplaced an insert", seed);
+                    }
+                    2 => {
+                        let ids: Vec<Id> =
+                            replicas[r].visible().iter().map(|e| e.id.clone()).collect();
+                        if !ids.is_empty() {
+                            let id = ids[next(ids.len())].clone();
+                            replicas[r].delete(&id);
+                        }
+                    }
+                    _ => {
+                        let from = replicas[next(nodes.len())].clone();
+                        replicas[r].merge(&from);
+                    }
+                }
+            }
+            for i in 0..nodes.len() {
+                for j in 0..nodes.len() {
+                    let from = replicas[j].clone();
+                    replicas[i].merge(&from);
+                }
+            }
+            let reads: Vec<Vec<&Value>> = replicas
+                .iter()
+                .map(|r| r.visible().iter().map(|e| &e.value).collect())
+                .collect();
+            assert!(
+                reads.iter().all(|r| *r == reads[0]),
+                "seed {} diverged: {:?}",
+                seed,
+                reads
+            );
+        }
+    }
+
+    #[test]
+    fn inserts_land_right_after_their_anchor() {
+        let mut a = Rga::default();
+        let x = a.insert("n0", None, Value::from(1)).unwrap();
+        let y = a.insert("n0", Some(x.clone()), Value::from(2)).unwrap();
+        a.insert("n0", Some(y), Value::from(3)).unwrap();
+        let mut b = Rga::default();
+        b.merge(&a);
+        b.insert("n1", Some(x), Value::from(4)).unwrap();
+        a.merge(&b);
+        a.insert("n0", None, Value::from(0)).unwrap();
+        b.merge(&a);
+        for list in [&a, &b] {
+            let values: Vec<&Value> = list.visible().iter().map(|e| &e.value).collect();
+            assert_eq!(
+                values,
+                [0, 1, 4, 2, 3].map(Value::from).iter().collect::<Vec<_>>()
+            );
+        }
+    }
+}
@@ -0,0 +1,177 @@
+use crate::config;
+use crate::micro_ops::{keys, run_transactions, Values};
+use crate::txn::{next_id, send, MessageCounter};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashSet};
+use std::sync::{Arc, Mutex};
+use tokio::time::{sleep, Duration};
+
+// Calvin-style deterministic transactions. Each node's sequencer collects the
+// txn requests it receives into one batch per epoch and replicates the batch
+// to every node. Once a node holds every node's batch for an epoch it runs
+// them in (epoch, node id) order against its own copy of the store. Every node
+// runs the same transactions in the same order, so the copies agree without
+// locks or validation and nothing aborts on conflicts: a txn fails only if one
+// of its micro-ops does, and then it fails the same way everywhere. The node
+// the client asked replies once its copy has run the txn.
+//
+// A request that arrives after another's reply was sent lands in a later
+// epoch, because that reply needed every node to have sealed the earlier one
+// first. So the order is strictly serializable.
+//
+// The price is availability: no epoch runs until every node's batch for it
+// has arrived, so while any node is cut off, every node stops executing. That
+// is accepted here. Requests queue up rather than fail, clients time them out
+// as indeterminate (which is all a reply with code 0 could say), and once the
+// partition heals the retried batches arrive and the queued epochs run.
+
+const EPOCH_MS: u64 = 20;
+const RETRY_MS: u64 = 500;
+
+#[derive(Serialize, Deserialize, Clone)]
+struct Request {
+    client: String,
+    msg_id: i64,
+    txn: Value,
+}
+
+#[derive(Default)]
+pub struct Sequencer {
+    // The epoch the requests in `batch` will be sealed as.
+    epoch: i64,
+    batch: Vec<Request>,
+    // Replicated batches not yet run, by epoch and then by node.
+    batches: BTreeMap<i64, BTreeMap<String, Vec<Request>>>,
+    // The next epoch to run.
+    executed: i64,
+    values: Values,
+    // Batch messages not yet acknowledged.
+    unacked: HashSet<i64>,
+}
+
+pub type ThreadSequencer = Arc<Mutex<Sequencer>>;
+
+impl Sequencer {
+    fn receive(&mut self, epoch: i64, node: &str, batch: Vec<Request>) {
+        if epoch >= self.executed {
+            self.batches
+                .entry(epoch)
+                .or_default()
+                .insert(node.to_string(), batch);
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Context {
+    pub id: String,
+    pub node_ids: Vec<String>,
+    pub next_msg_id: MessageCounter,
+    pub sequencer: ThreadSequencer,
+}
+
+impl Context {
+    // Runs every epoch for which all batches have arrived, replying to the
+    // clients whose requests came to this node.
+    fn execute(&self) {
+        let mut sequencer = self.sequencer.lock().unwrap();
+        loop {
+            let epoch = sequencer.executed;
+            match sequencer.batches.get(&epoch) {
+                Some(batches) if batches.len() == self.node_ids.len() => {}
+                _ => break,
+            }
+            let batches = sequencer.batches.remove(&epoch).unwrap();
+            for (node, requests) in batches {
+                for request in requests {
+                    // Only the keys the txn touches are copied, so a failed
+                    // txn leaves the store as it was.
+                    let mut values: Values = keys(&request.txn)
+                        .filter_map(|k| sequencer.values.get(&k).map(|v| (k, v.clone())))
+                        .collect();
+                    let body = match run_transactions(&mut values, &request.txn) {
+                        Ok(txs_json) => {
+                            sequencer.values.extend(values);
+                            json!({"type": "txn_ok", "txn": txs_json})
+                        }
+                        Err(error) => {
+                            json!({"type": "error", "code": error.code, "text": error.text})
+                        }
+                    };
+                    if node == self.id {
+                        let mut body = body;
+                        body["msg_id"] = Value::from(next_id(&self.next_msg_id));
+                        body["in_reply_to"] = Value::from(request.msg_id);
+                        send(&self.id, &request.client, body);
+                    }
+                }
+            }
+            sequencer.executed += 1;
+        }
+    }
+}
+
+pub fn submit(ctx: &Context, client: String, msg_id: i64, txn: Value) {
+    let mut sequencer = ctx.sequencer.lock().unwrap();
+    sequencer.batch.push(Request {
+        client,
+        msg_id,
+        txn,
+    });
+}
+
+async fn deliver(ctx: Context, dest: String, body: Value, msg_id: i64) {
+    loop {
+        if !ctx.sequencer.lock().unwrap().unacked.contains(&msg_id) {
+            break;
+        }
+        send(&ctx.id, &dest, body.clone());
+        sleep(config::retry_interval(Duration::from_millis(RETRY_MS))).await;
+    }
+}
+
+// Seals this node's batch every epoch, even when it is empty, since no node
+// can run an epoch until it has heard from all of them.
+pub async fn sequence(ctx: Context) {
+    loop {
+        sleep(config::epoch(Duration::from_millis(EPOCH_MS))).await;
+        {
+            let mut sequencer = ctx.sequencer.lock().unwrap();
+            let epoch = sequencer.epoch;
+            sequencer.epoch += 1;
+            let batch = std::mem::take(&mut sequencer.batch);
+            for node in ctx.node_ids.iter().filter(|n| **n != ctx.id) {
+                let msg_id = next_id(&ctx.next_msg_id);
+                sequencer.unacked.insert(msg_id);
+                let body =
+                    json!({"type": "batch", "msg_id": msg_id, "epoch": epoch, "batch": batch});
+                tokio::spawn(deliver(ctx.clone(), node.clone(), body, msg_id));
+            }
+            sequencer.receive(epoch, &ctx.id, batch);
+        }
+        ctx.execute();
+    }
+}
+
+pub fn handle(ctx: &Context, src: &str, body: &Value) {
+    let batch = serde_json::from_value(body["batch"].clone()).unwrap();
+    ctx.sequencer
+        .lock()
+        .unwrap()
+        .receive(body["epoch"].as_i64().unwrap(), src, batch);
+    let reply = json!({
+        "type": "batch_ok",
+        "msg_id": next_id(&ctx.next_msg_id),
+        "in_reply_to": body["msg_id"],
+    });
+    send(&ctx.id, src, reply);
+    ctx.execute();
+}
+
+pub fn acknowledged(ctx: &Context, body: &Value) {
+    let mut sequencer = ctx.sequencer.lock().unwrap();
+    sequencer
+        .unacked
+        .remove(&body["in_reply_to"].as_i64().unwrap());
+}
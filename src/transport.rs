@@ -0,0 +1,487 @@
+use crate::network::{self, Router};
+use crate::{clock, config, failure_detector, metrics};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::env;
+use std::fs::{File, OpenOptions};
+use std::future::Future;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncBufReadExt, BufReader, Stdin};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::sleep;
+use tracing::{debug, error, info_span, warn, Span};
+use tracing_subscriber::EnvFilter;
+
+// Setting RAFT_TRACE to a path makes the node append every message it receives
+// and sends to that file, one JSON object per line:
+//
+//   {"timestamp": <micros since epoch>, "direction": "recv" | "send",
+//    "node": <our node id>, "message": <the full message>}
+//
+// Several nodes can share one file; each line is written in a single append.
+// `replay` reads these files back.
+const TRACE_VAR: &str = "RAFT_TRACE";
+// Log filter in tracing's env-filter syntax, e.g. `debug` to log every message
+// or `info,raft=debug`. Defaults to `info`.
+const LOG_VAR: &str = "RAFT_LOG";
+// Milliseconds the writer waits after the first of a batch of outgoing
+// messages for more to join it before flushing. Defaults to 0: flush as soon
+// as nothing else is queued, trading a little latency for fewer writes when
+// raised.
+const LINGER_VAR: &str = "RAFT_FLUSH_MS";
+
+// Outgoing lines queued for the writer before `send` has to wait for it.
+const QUEUE_CAPACITY: usize = 1024;
+// Most lines written between two flushes.
+const MAX_BATCH: usize = 256;
+
+const MALFORMED_REQUEST: i64 = 12;
+// Shutdown waits for requests still being handled for a little longer than
+// any RPC they are waiting on takes to time out.
+const RPC_TIMEOUT: Duration = Duration::from_secs(5);
+const DRAIN_GRACE: Duration = Duration::from_secs(1);
+
+const DRAIN_POLL: Duration = Duration::from_millis(10);
+
+// Requests being handled by tasks started with `spawn`.
+static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+// Whether `init` has arrived; handlers can take it that it has.
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+// Whether a body field holds what its handler unwraps it as.
+type Check = fn(&Value) -> bool;
+type Field = (&'static str, Check);
+type Offsets = HashMap<String, i64>;
+
+fn is<T: DeserializeOwned>(value: &Value) -> bool {
+    T::deserialize(value).is_ok()
+}
+
+const MSG_ID: Field = ("msg_id", is::<i64>);
+
+// The fields the handlers of each request a client may send rely on, for the
+// workloads listed or, when none are, for every workload. Besides these, every
+// reply must say what it is replying to. Otherwise messages between nodes are
+// trusted, as are fields handlers only read optionally.
+const REQUESTS: &[(&str, &[&str], &[Field])] = &[
+    (
+        "init",
+        &[],
+        &[
+            MSG_ID,
+            ("node_id", is::<String>),
+            ("node_ids", is::<Vec<String>>),
+        ],
+    ),
+    ("echo", &[], &[MSG_ID, ("echo", is::<String>)]),
+    ("generate", &[], &[MSG_ID]),
+    (
+        "topology",
+        &[],
+        &[MSG_ID, ("topology", is::<HashMap<String, Vec<String>>>)],
+    ),
+    // Nodes broadcast to each other without a msg_id.
+    ("broadcast", &[], &[("message", is::<i64>)]),
+    (
+        "add",
+        &["crdts", "or-set"],
+        &[MSG_ID, ("element", is::<i64>)],
+    ),
+    ("add", &["or-map"], &[MSG_ID, ("key", is::<String>)]),
+    ("add", &[], &[MSG_ID, ("delta", is::<i64>)]),
+    ("remove", &["or-map"], &[MSG_ID, ("key", is::<String>)]),
+    ("remove", &[], &[MSG_ID, ("element", is::<i64>)]),
+    ("read", &[], &[MSG_ID]),
+    ("write", &[], &[MSG_ID]),
+    (
+        "insert",
+        &[],
+        &[MSG_ID, ("after", is::<Option<(i64, String)>>)],
+    ),
+    ("delete", &[], &[MSG_ID, ("id", is::<(i64, String)>)]),
+    ("send", &[], &[MSG_ID, ("key", is::<String>)]),
+    ("poll", &[], &[MSG_ID, ("offsets", is::<Offsets>)]),
+    ("commit_offsets", &[], &[MSG_ID, ("offsets", is::<Offsets>)]),
+    (
+        "list_committed_offsets",
+        &[],
+        &[MSG_ID, ("keys", is::<Vec<String>>)],
+    ),
+    ("txn", &[], &[MSG_ID, ("txn", is::<Vec<Value>>)]),
+    ("replicate", &["txn-rw-register"], &[MSG_ID]),
+];
+
+const IN_REPLY_TO: Field = ("in_reply_to", is::<i64>);
+
+enum Output {
+    Line(String),
+    // Answered once everything queued before it is flushed.
+    Flush(oneshot::Sender<()>),
+}
+
+// Logs go to stderr, which Maelstrom keeps per node; stdout is the protocol.
+pub fn init_logging() {
+    let filter = EnvFilter::try_from_env(LOG_VAR).unwrap_or_else(|_| EnvFilter::new("info"));
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .with_ansi(false)
+        .init();
+}
+
+// Covers the handling of one incoming message, so everything logged meanwhile
+// is tagged with our node id and the message's msg_id.
+pub fn request_span(message: &Value) -> Span {
+    info_span!(
+        "request",
+        node = message["dest"].as_str().unwrap_or_default(),
+        src = message["src"].as_str().unwrap_or_default(),
+        msg_id = message["body"]["msg_id"].as_i64(),
+    )
+}
+
+fn trace_file() -> &'static Option<Mutex<File>> {
+    static TRACE: OnceLock<Option<Mutex<File>>> = OnceLock::new();
+    TRACE.get_or_init(|| {
+        let path = env::var(TRACE_VAR).ok()?;
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .unwrap_or_else(|error| panic!("cannot open trace {}: {}", path, error));
+        Some(Mutex::new(file))
+    })
+}
+
+fn record(direction: &str, line: &str) {
+    let file = match trace_file() {
+        Some(file) => file,
+        None => return,
+    };
+    let message: Value = serde_json::from_str(line).unwrap_or_else(|_| Value::from(line));
+    let node = match direction {
+        "recv" => &message["dest"],
+        _ => &message["src"],
+    };
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_micros() as i64;
+    let entry = json!({
+        "timestamp": timestamp,
+        "direction": direction,
+        "node": node,
+        "message": message,
+    });
+    // One write per entry, so nodes sharing the trace never split each
+    // other's lines.
+    let mut file = file.lock().unwrap();
+    file.write_all(format!("{}\n", entry).as_bytes()).unwrap();
+}
+
+fn received(line: &str) {
+    debug!(message = line.trim_end(), "received");
+    record("recv", line.trim_end());
+}
+
+// Stamps `message` with our clocks if it goes to another node, then
+// serializes it once, for the wire; the log and trace reuse that line.
+pub fn send<T: Serialize>(message: &T) {
+    let mut message = serde_json::to_value(message).unwrap();
+    clock::stamp(&mut message);
+    let line = message.to_string();
+    debug!(message = line.as_str(), "sending");
+    // Before the line goes out, so a fast reply always finds its request.
+    metrics::sent(&message);
+    failure_detector::sent(message["dest"].as_str().unwrap_or_default());
+    record("send", &line);
+    enqueue(Output::Line(line));
+}
+
+// All output goes through a single writer, so tasks sending concurrently
+// never block on stdout or interleave their lines. When the queue is full
+// `send` waits for room, off the runtime's worker so other tasks keep going.
+fn enqueue(output: Output) {
+    let queue = output_queue();
+    match queue.try_send(output) {
+        Ok(()) => metrics::queued(QUEUE_CAPACITY - queue.capacity()),
+        Err(mpsc::error::TrySendError::Full(output)) => {
+            metrics::queued(QUEUE_CAPACITY);
+            let started = Instant::now();
+            tokio::task::block_in_place(|| queue.blocking_send(output)).ok();
+            metrics::waited(started.elapsed());
+        }
+        Err(mpsc::error::TrySendError::Closed(_)) => {}
+    }
+}
+
+fn output_queue() -> &'static mpsc::Sender<Output> {
+    static QUEUE: OnceLock<mpsc::Sender<Output>> = OnceLock::new();
+    QUEUE.get_or_init(|| {
+        let linger = env::var(LINGER_VAR)
+            .ok()
+            .and_then(|ms| ms.parse().ok())
+            .map_or(Duration::ZERO, Duration::from_millis);
+        let (tx, rx) = mpsc::channel(QUEUE_CAPACITY);
+        thread::spawn(move || write(rx, linger));
+        tx
+    })
+}
+
+// The writer runs on a thread of its own rather than as a task: handlers here
+// block their worker while they wait for an RPC reply, and a writer task woken
+// on that worker could not send the request until the wait timed out.
+enum Sink {
+    Stdout(io::BufWriter<io::StdoutLock<'static>>),
+    Network(Router),
+}
+
+impl Sink {
+    fn line(&mut self, line: &str) {
+        match self {
+            Sink::Stdout(stdout) => {
+                stdout.write_all(line.as_bytes()).unwrap();
+                stdout.write_all(b"\n").unwrap();
+            }
+            Sink::Network(router) => router.send(line),
+        }
+    }
+
+    fn flush(&mut self) {
+        match self {
+            Sink::Stdout(stdout) => stdout.flush().unwrap(),
+            Sink::Network(router) => router.flush(),
+        }
+    }
+}
+
+fn write(mut queue: mpsc::Receiver<Output>, linger: Duration) {
+    let mut sink = match network::network() {
+        Some(network) => Sink::Network(Router::new(network)),
+        None => Sink::Stdout(io::BufWriter::new(io::stdout().lock())),
+    };
+    while let Some(first) = queue.blocking_recv() {
+        thread::sleep(linger);
+        let mut batch = vec![first];
+        while batch.len() < MAX_BATCH {
+            match queue.try_recv() {
+                Ok(output) => batch.push(output),
+                Err(_) => break,
+            }
+        }
+        let (mut lines, mut bytes) = (0, 0u64);
+        let mut flushed = Vec::new();
+        for output in batch {
+            match output {
+                Output::Line(line) => {
+                    sink.line(&line);
+                    lines += 1;
+                    bytes += line.len() as u64 + 1;
+                }
+                Output::Flush(done) => flushed.push(done),
+            }
+        }
+        sink.flush();
+        metrics::flushed(lines, bytes);
+        for done in flushed {
+            done.send(()).ok();
+        }
+    }
+}
+
+// Checks a line holds a message every handler can rely on: a source, a
+// destination and a body with a type, arriving after `init` unless it is the
+// `init`, and carrying the fields REQUESTS lists for its type.
+fn parse(line: &str, initialized: bool) -> Result<Value, String> {
+    let message: Value = serde_json::from_str(line).map_err(|e| e.to_string())?;
+    if !message["src"].is_string() || !message["dest"].is_string() {
+        return Err("message has no src or dest".to_string());
+    }
+    let body = &message["body"];
+    let r#type = body["type"].as_str().ok_or("body has no type")?;
+    if !initialized && r#type != "init" {
+        return Err(format!("{} before init", r#type));
+    }
+    let workload = config::workload();
+    let fields = REQUESTS
+        .iter()
+        .find(|(t, workloads, _)| {
+            *t == r#type && (workloads.is_empty() || workloads.contains(&workload))
+        })
+        .map_or(&[][..], |(_, _, fields)| fields);
+    let fields = match r#type == "error" || r#type.ends_with("_ok") {
+        true => &[IN_REPLY_TO][..],
+        false => fields,
+    };
+    match fields.iter().find(|(name, check)| !check(&body[*name])) {
+        Some((name, _)) => Err(format!("{} has a missing or bad {}", r#type, name)),
+        None => Ok(message),
+    }
+}
+
+// Answers a malformed message when we can tell who sent it and which msg_id
+// to answer; otherwise there is no one to tell.
+fn reject(line: &str, reason: String) {
+    warn!("Malformed message ({}): {}", reason, line.trim_end());
+    let message: Value = serde_json::from_str(line).unwrap_or_default();
+    if let (Some(src), Some(dest), Some(msg_id)) = (
+        message["dest"].as_str(),
+        message["src"].as_str(),
+        message["body"]["msg_id"].as_i64(),
+    ) {
+        send(&json!({
+            "src": src,
+            "dest": dest,
+            "body": {
+                "type": "error",
+                "in_reply_to": msg_id,
+                "code": MALFORMED_REQUEST,
+                "text": reason,
+            },
+        }));
+    }
+}
+
+// Sets up our clocks once `init` names this node and its peers.
+fn init(body: &Value) {
+    let node_ids: Vec<String> = serde_json::from_value(body["node_ids"].clone()).unwrap();
+    clock::init(body["node_id"].as_str().unwrap(), &node_ids);
+    INITIALIZED.store(true, Ordering::SeqCst);
+}
+
+enum Input {
+    Stdin(BufReader<Stdin>),
+    Network(tokio::sync::mpsc::UnboundedReceiver<String>),
+}
+
+impl Input {
+    async fn line(&mut self) -> Option<String> {
+        match self {
+            Input::Stdin(stdin) => {
+                let mut line = Vec::new();
+                match stdin.read_until(b'\n', &mut line).await {
+                    Ok(0) => None,
+                    Ok(_) => Some(String::from_utf8_lossy(&line).into_owned()),
+                    Err(e) => {
+                        error!("Cannot read stdin: {}", e);
+                        None
+                    }
+                }
+            }
+            Input::Network(incoming) => incoming.recv().await,
+        }
+    }
+}
+
+// The next message on stdin, or from the network when network.rs is set up,
+// skipping blank and malformed lines. `metrics` messages are answered here
+// rather than handed to the workload. `None` once stdin is closed or can no
+// longer be read, at which point the node should `shutdown`.
+pub async fn receive() -> Option<Value> {
+    static INPUT: OnceLock<tokio::sync::Mutex<Input>> = OnceLock::new();
+    let mut input = INPUT
+        .get_or_init(|| {
+            tokio::sync::Mutex::new(match network::network() {
+                Some(network) => Input::Network(network.incoming()),
+                None => Input::Stdin(BufReader::new(tokio::io::stdin())),
+            })
+        })
+        .lock()
+        .await;
+    loop {
+        let line = input.line().await?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        received(&line);
+        match parse(&line, INITIALIZED.load(Ordering::SeqCst)) {
+            Ok(message) => {
+                metrics::received(&message);
+                if message["body"]["type"] == "init" {
+                    init(&message["body"]);
+                }
+                clock::observe(&message);
+                if message["body"]["type"] == "metrics" {
+                    metrics::report(&message);
+                    continue;
+                }
+                return Some(message);
+            }
+            Err(reason) => reject(&line, reason),
+        }
+    }
+}
+
+struct Finished;
+
+impl Drop for Finished {
+    fn drop(&mut self) {
+        IN_FLIGHT.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Handles a request on its own task, which `shutdown` waits for. Loops that
+// run for the node's lifetime, such as gossip and retries, are spawned
+// directly instead.
+pub fn spawn<F>(task: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    IN_FLIGHT.fetch_add(1, Ordering::SeqCst);
+    tokio::spawn(async move {
+        let _finished = Finished;
+        task.await;
+    });
+}
+
+// Waits for in-flight requests, up to a little longer than an RPC timeout,
+// and for everything they sent to reach stdout, then logs the metrics.
+pub async fn shutdown() {
+    let deadline = Instant::now() + config::rpc_timeout(RPC_TIMEOUT) + DRAIN_GRACE;
+    while IN_FLIGHT.load(Ordering::SeqCst) > 0 && Instant::now() < deadline {
+        sleep(DRAIN_POLL).await;
+    }
+    let count = IN_FLIGHT.load(Ordering::SeqCst);
+    if count > 0 {
+        warn!("Shutting down with {} requests in flight", count);
+    }
+    let (done, flushed) = oneshot::channel();
+    enqueue(Output::Flush(done));
+    flushed.await.ok();
+    metrics::dump();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_without_what_handlers_unwrap_are_rejected() {
+        let echo = r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":2}}"#;
+        assert!(parse(echo, true).is_err());
+        let echo = r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":2,"echo":"hi"}}"#;
+        assert!(parse(echo, true).is_ok());
+
+        let add = r#"{"src":"c1","dest":"n1","body":{"type":"add","msg_id":3,"delta":"x"}}"#;
+        assert!(parse(add, true).is_err());
+        let add = r#"{"src":"c1","dest":"n1","body":{"type":"add","msg_id":3,"delta":-2}}"#;
+        assert!(parse(add, true).is_ok());
+
+        let reply = r#"{"src":"lin-kv","dest":"n1","body":{"type":"read_ok","value":1}}"#;
+        assert!(parse(reply, true).is_err());
+    }
+
+    #[test]
+    fn requests_before_init_are_rejected() {
+        let echo = r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":1,"echo":"hi"}}"#;
+        assert!(parse(echo, false).is_err());
+        let init = r#"{"src":"c0","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1"]}}"#;
+        assert!(parse(init, false).is_ok());
+    }
+}
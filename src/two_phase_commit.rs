@@ -0,0 +1,415 @@
+use crate::config;
+use crate::failure_detector::ThreadDetector;
+use crate::micro_ops::{changes, keys, run_transactions, TxnError, Values};
+use crate::txn::{next_id, send, MessageCounter, MessageHash};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::time::{sleep, Duration};
+
+// Keys are hash-partitioned across nodes. Whichever node a client asks
+// coordinates the transaction with two-phase commit: every partition it
+// touches takes a write intent on the keys and returns their values, the
+// coordinator runs the micro-ops against them, then tells each partition to
+// install its share of the writes or to drop its intents. Intents are held
+// from prepare to decision, so transactions on overlapping keys are
+// serialized and the rest run in parallel. A partition that finds a key
+// already held votes no instead of waiting, so there are no deadlocks, only
+// aborts.
+
+pub const TXN_CONFLICT: i64 = 30;
+const PREPARE_TIMEOUT: Duration = Duration::from_millis(1000);
+const RETRY_MS: u64 = 1000;
+// How often a coordinator waiting on votes checks whether a participant it is
+// waiting on has come under suspicion.
+const POLL: Duration = Duration::from_millis(100);
+// A partition holding intents this long asks the coordinator what became of
+// the transaction, in case the decision was lost.
+const INTENT_TIMEOUT: Duration = Duration::from_millis(2000);
+
+struct Intent {
+    coordinator: String,
+    keys: Vec<i64>,
+    since: Instant,
+}
+
+enum Decision {
+    Pending,
+    // The writes each participant installs, by node.
+    Commit(HashMap<String, Values>),
+    Abort,
+}
+
+#[derive(Default)]
+pub struct Partition {
+    values: Values,
+    // The transaction holding an intent on each key.
+    locks: HashMap<i64, String>,
+    intents: HashMap<String, Intent>,
+    // Outcomes of the transactions this node coordinated, kept until every
+    // participant has acknowledged them.
+    decisions: HashMap<String, Decision>,
+    // Commit and abort messages not yet acknowledged, and their transactions.
+    unacked: HashMap<i64, String>,
+}
+
+pub type ThreadPartition = Arc<Mutex<Partition>>;
+
+impl Partition {
+    fn prepare(
+        &mut self,
+        txn_id: &str,
+        coordinator: &str,
+        keys: Vec<i64>,
+    ) -> Result<Values, TxnError> {
+        if !self.intents.contains_key(txn_id) {
+            for k in &keys {
+                if let Some(holder) = self.locks.get(k) {
+                    return Err(TxnError::new(
+                        TXN_CONFLICT,
+                        format!("Key {} is held by {}", k, holder),
+                    ));
+                }
+            }
+            for k in &keys {
+                self.locks.insert(*k, txn_id.to_string());
+            }
+            self.intents.insert(
+                txn_id.to_string(),
+                Intent {
+                    coordinator: coordinator.to_string(),
+                    keys,
+                    since: Instant::now(),
+                },
+            );
+        }
+        Ok(self.intents[txn_id]
+            .keys
+            .iter()
+            .filter_map(|k| self.values.get(k).map(|v| (*k, v.clone())))
+            .collect())
+    }
+
+    // Drops the intents of `txn_id`, installing `writes` if it committed.
+    fn resolve(&mut self, txn_id: &str, writes: Option<Values>) {
+        if let Some(intent) = self.intents.remove(txn_id) {
+            for k in intent.keys {
+                self.locks.remove(&k);
+            }
+            self.values.extend(writes.unwrap_or_default());
+        }
+    }
+
+    // Forgets the outcome of `txn_id` once no participant still has to hear it.
+    // Anyone asking about it later is told to abort, which is safe because
+    // every participant has already resolved its intents.
+    fn forget(&mut self, txn_id: &str) {
+        if !self.unacked.values().any(|t| t == txn_id) {
+            self.decisions.remove(txn_id);
+        }
+    }
+
+    // What `participant` should do about `txn_id`, as its coordinator.
+    // Transactions we know nothing about are presumed aborted.
+    fn outcome(&self, txn_id: &str, participant: &str) -> (&'static str, Values) {
+        match self.decisions.get(txn_id) {
+            Some(Decision::Pending) => ("pending", Values::new()),
+            Some(Decision::Commit(writes)) => (
+                "commit",
+                writes.get(participant).cloned().unwrap_or_default(),
+            ),
+            _ => ("abort", Values::new()),
+        }
+    }
+}
+
+// FNV-1a, so every node agrees on which node owns a key.
+fn owner(key: i64, node_ids: &[String]) -> &str {
+    let hash = key
+        .to_be_bytes()
+        .iter()
+        .fold(0xcbf29ce484222325u64, |h, b| {
+            (h ^ *b as u64).wrapping_mul(0x100000001b3)
+        });
+    &node_ids[(hash % node_ids.len() as u64) as usize]
+}
+
+#[derive(Clone)]
+pub struct Context {
+    pub id: String,
+    pub node_ids: Vec<String>,
+    pub next_msg_id: MessageCounter,
+    pub detector: ThreadDetector,
+    pub senders: MessageHash,
+    pub partition: ThreadPartition,
+}
+
+impl Context {
+    fn send(&self, dest: &str, body: Value) {
+        send(&self.id, dest, body);
+    }
+
+    fn reply(&self, dest: &str, incoming_id: i64, mut body: Value) {
+        body["msg_id"] = Value::from(next_id(&self.next_msg_id));
+        body["in_reply_to"] = Value::from(incoming_id);
+        self.send(dest, body);
+    }
+
+    // The first participant the failure detector suspects is down, if any.
+    fn suspect<'a>(&self, participants: impl Iterator<Item = &'a &'a str>) -> Option<&'a str> {
+        let detector = self.detector.lock().unwrap();
+        participants.copied().find(|n| detector.suspected(n))
+    }
+
+    // Phase one: takes intents on every shard's keys and gathers their values.
+    // Fails if any partition votes no or does not answer in time, and without
+    // waiting at all if one of them is suspected to be down.
+    fn prepare(&self, txn_id: &str, shards: &BTreeMap<&str, Vec<i64>>) -> Result<Values, TxnError> {
+        if let Some(node) = self.suspect(shards.keys()) {
+            return Err(TxnError::new(
+                TXN_CONFLICT,
+                format!("Participant {} of {} is suspected down", node, txn_id),
+            ));
+        }
+        let mut snapshot = match shards.get(self.id.as_str()) {
+            Some(keys) => self
+                .partition
+                .lock()
+                .unwrap()
+                .prepare(txn_id, &self.id, keys.clone())?,
+            None => Values::new(),
+        };
+        let (tx, rx) = mpsc::channel();
+        let mut waiting = Vec::new();
+        for (node, keys) in shards.iter().filter(|(node, _)| **node != self.id) {
+            let msg_id = next_id(&self.next_msg_id);
+            self.senders.lock().unwrap().insert(msg_id, tx.clone());
+            waiting.push(msg_id);
+            self.send(
+                node,
+                json!({"type": "prepare", "msg_id": msg_id, "txn_id": txn_id, "keys": keys}),
+            );
+        }
+        let deadline = Instant::now() + config::prepare_timeout(PREPARE_TIMEOUT);
+        let mut result = Ok(());
+        let mut votes = 0;
+        while votes < waiting.len() && result.is_ok() {
+            let wait = deadline.saturating_duration_since(Instant::now()).min(POLL);
+            match rx.recv_timeout(wait) {
+                Ok(reply) if reply["type"] == "error" => {
+                    result = Err(TxnError::new(
+                        TXN_CONFLICT,
+                        format!("A participant voted to abort {}", txn_id),
+                    ))
+                }
+                Ok(reply) => {
+                    snapshot.extend(
+                        serde_json::from_value::<Values>(reply["values"].to_owned()).unwrap(),
+                    );
+                    votes += 1;
+                }
+                Err(_) if Instant::now() >= deadline => {
+                    result = Err(TxnError::new(
+                        TXN_CONFLICT,
+                        format!("Timed out preparing {}", txn_id),
+                    ))
+                }
+                Err(_) => {
+                    if let Some(node) = self.suspect(shards.keys()) {
+                        result = Err(TxnError::new(
+                            TXN_CONFLICT,
+                            format!("Participant {} of {} is suspected down", node, txn_id),
+                        ))
+                    }
+                }
+            }
+        }
+        let mut lookup = self.senders.lock().unwrap();
+        for msg_id in waiting {
+            lookup.remove(&msg_id);
+        }
+        result.map(|_| snapshot)
+    }
+
+    // Phase two: records the decision, then delivers it to every participant,
+    // retrying until each one acknowledges.
+    fn decide(&self, txn_id: &str, participants: Vec<&str>, decision: Decision) {
+        let mut partition = self.partition.lock().unwrap();
+        partition.decisions.insert(txn_id.to_string(), decision);
+        for node in participants {
+            let (outcome, writes) = partition.outcome(txn_id, node);
+            let committed = outcome == "commit";
+            if node == self.id {
+                partition.resolve(txn_id, Some(writes).filter(|_| committed));
+                continue;
+            }
+            let msg_id = next_id(&self.next_msg_id);
+            partition.unacked.insert(msg_id, txn_id.to_string());
+            let body = match committed {
+                true => {
+                    json!({"type": "commit", "msg_id": msg_id, "txn_id": txn_id, "writes": writes})
+                }
+                _ => json!({"type": "abort", "msg_id": msg_id, "txn_id": txn_id}),
+            };
+            tokio::spawn(deliver(self.clone(), node.to_string(), body, msg_id));
+        }
+        partition.forget(txn_id);
+    }
+}
+
+async fn deliver(ctx: Context, dest: String, body: Value, msg_id: i64) {
+    loop {
+        if !ctx.partition.lock().unwrap().unacked.contains_key(&msg_id) {
+            break;
+        }
+        ctx.send(&dest, body.clone());
+        sleep(config::retry_interval(Duration::from_millis(RETRY_MS))).await;
+    }
+}
+
+pub async fn transact(ctx: Context, txn: Value, incoming_id: i64, dest: String) {
+    let txn_id = format!("{}-{}", ctx.id, next_id(&ctx.next_msg_id));
+    ctx.partition
+        .lock()
+        .unwrap()
+        .decisions
+        .insert(txn_id.clone(), Decision::Pending);
+    let mut shards: BTreeMap<&str, Vec<i64>> = BTreeMap::new();
+    for k in keys(&txn) {
+        let shard = shards.entry(owner(k, &ctx.node_ids)).or_default();
+        if !shard.contains(&k) {
+            shard.push(k);
+        }
+    }
+    let result = ctx.prepare(&txn_id, &shards).and_then(|before| {
+        let mut values = before.clone();
+        run_transactions(&mut values, &txn).map(|txs_json| (txs_json, changes(&before, &values)))
+    });
+    let decision = match &result {
+        Ok((_, writes)) => {
+            let mut by_node: HashMap<String, Values> = HashMap::new();
+            for (k, v) in writes {
+                by_node
+                    .entry(owner(*k, &ctx.node_ids).to_string())
+                    .or_default()
+                    .insert(*k, v.clone());
+            }
+            Decision::Commit(by_node)
+        }
+        Err(_) => Decision::Abort,
+    };
+    ctx.decide(&txn_id, shards.keys().copied().collect(), decision);
+    let body = match result {
+        Ok((txs_json, _)) => json!({"type": "txn_ok", "txn": txs_json}),
+        Err(error) => json!({"type": "error", "code": error.code, "text": error.text}),
+    };
+    ctx.reply(&dest, incoming_id, body);
+}
+
+// Serves prepare, commit, abort and status requests from other nodes.
+pub fn handle(ctx: &Context, src: &str, body: &Value) {
+    let txn_id = body["txn_id"].as_str().unwrap();
+    let mut partition = ctx.partition.lock().unwrap();
+    let reply = match body["type"].as_str().unwrap() {
+        "prepare" => {
+            let keys = serde_json::from_value(body["keys"].clone()).unwrap();
+            match partition.prepare(txn_id, src, keys) {
+                Ok(values) => json!({"type": "prepare_ok", "values": values}),
+                Err(error) => json!({"type": "error", "code": error.code, "text": error.text}),
+            }
+        }
+        "commit" => {
+            let writes = serde_json::from_value(body["writes"].clone()).unwrap();
+            partition.resolve(txn_id, Some(writes));
+            json!({"type": "commit_ok"})
+        }
+        "abort" => {
+            partition.resolve(txn_id, None);
+            json!({"type": "abort_ok"})
+        }
+        _ => {
+            let (outcome, writes) = partition.outcome(txn_id, src);
+            json!({"type": "status_ok", "txn_id": txn_id, "outcome": outcome, "writes": writes})
+        }
+    };
+    drop(partition);
+    ctx.reply(src, body["msg_id"].as_i64().unwrap(), reply);
+}
+
+pub fn acknowledged(ctx: &Context, body: &Value) {
+    let mut partition = ctx.partition.lock().unwrap();
+    if let Some(txn_id) = partition
+        .unacked
+        .remove(&body["in_reply_to"].as_i64().unwrap())
+    {
+        partition.forget(&txn_id);
+    }
+}
+
+// Applies the outcome a coordinator reported for one of our stale intents.
+pub fn recovered(ctx: &Context, body: &Value) {
+    let txn_id = body["txn_id"].as_str().unwrap();
+    let mut partition = ctx.partition.lock().unwrap();
+    match body["outcome"].as_str().unwrap() {
+        "commit" => {
+            let writes = serde_json::from_value(body["writes"].clone()).unwrap();
+            partition.resolve(txn_id, Some(writes));
+        }
+        "abort" => partition.resolve(txn_id, None),
+        _ => {}
+    }
+}
+
+// Periodically asks the coordinator of every intent held past the timeout how
+// its transaction ended. Intents we coordinate ourselves are resolved by
+// `transact`, so they never need asking.
+pub async fn recover(ctx: Context) {
+    let timeout = config::intent_timeout(INTENT_TIMEOUT);
+    loop {
+        sleep(timeout).await;
+        let stale: Vec<(String, String)> = {
+            let partition = ctx.partition.lock().unwrap();
+            partition
+                .intents
+                .iter()
+                .filter(|(_, intent)| {
+                    intent.coordinator != ctx.id && intent.since.elapsed() > timeout
+                })
+                .map(|(txn_id, intent)| (txn_id.clone(), intent.coordinator.clone()))
+                .collect()
+        };
+        for (txn_id, coordinator) in stale {
+            let msg_id = next_id(&ctx.next_msg_id);
+            ctx.send(
+                &coordinator,
+                json!({"type": "status", "msg_id": msg_id, "txn_id": txn_id}),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A coordinator keeps a decision while any participant may still ask for
+    // it, and drops it once the last acknowledgement is in.
+    #[test]
+    fn decisions_are_forgotten_once_acknowledged() {
+        let mut partition = Partition::default();
+        partition
+            .decisions
+            .insert("n0-1".to_string(), Decision::Abort);
+        partition.unacked.insert(2, "n0-1".to_string());
+        partition.unacked.insert(3, "n0-1".to_string());
+        for msg_id in [2, 3] {
+            assert!(partition.decisions.contains_key("n0-1"));
+            partition.unacked.remove(&msg_id);
+            partition.forget("n0-1");
+        }
+        assert!(partition.decisions.is_empty());
+        assert_eq!(partition.outcome("n0-1", "n1").0, "abort");
+    }
+}
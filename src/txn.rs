@@ -0,0 +1,494 @@
+use crate::failure_detector::{self, FailureDetector, ThreadDetector};
+use crate::micro_ops::{run_transactions, TxnError, TxnType, Values, PRECONDITION_FAILED};
+use crate::{config, sequencer, transport, two_phase_commit};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::mpsc;
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::time::{sleep, Duration};
+use tracing::{debug, info, Instrument};
+
+struct Node {
+    id: Id,
+    next_msg_id: MessageCounter,
+    senders: MessageHash,
+    cluster: two_phase_commit::Context,
+    sequencer: sequencer::Context,
+    detector: ThreadDetector,
+    // msg_id of the latest probe read, whose reply counts as lin-kv's
+    // heartbeat.
+    probe: MessageCounter,
+}
+pub type MessageCounter = Arc<Mutex<i64>>;
+pub type MessageHash = Arc<Mutex<HashMap<i64, Sender<Value>>>>;
+type Id = Arc<RwLock<String>>;
+const KV: &str = "lin-kv";
+const ROOT: &str = "root";
+// Read by the probe. It never exists, so the reply stays small however large
+// the store grows, and the read is told apart from a transaction's.
+const PROBE: &str = "probe";
+const TIMEOUT: i64 = 0;
+const TEMPORARILY_UNAVAILABLE: i64 = 11;
+const CRASH: i64 = 13;
+const CAS_CONFLICT: i64 = 30;
+
+// Fails straight away while the failure detector suspects lin-kv is down,
+// rather than waiting out an RPC timeout on every request.
+async fn transact(
+    next_msg_id: MessageCounter,
+    txns: Value,
+    sender_hash: MessageHash,
+    detector: ThreadDetector,
+    node_id: Id,
+    incoming_id: i64,
+    dest: String,
+) {
+    if detector.lock().unwrap().suspected(KV) {
+        let error = TxnError::new(TEMPORARILY_UNAVAILABLE, format!("{} is suspected down", KV));
+        return reply_error(node_id, next_msg_id, dest, incoming_id, error);
+    }
+    let (tx, rx): (Sender<Value>, Receiver<Value>) = mpsc::channel();
+    let result =
+        send_read(node_id.clone(), next_msg_id.clone(), &sender_hash, &tx, &rx).and_then(|val| {
+            debug!("Inside channel {}", val);
+            let from: Store = serde_json::from_value(val).map_err(|error| {
+                TxnError::new(CRASH, format!("Unreadable {} value: {}", ROOT, error))
+            })?;
+            let mut hash = Store(from.0.clone());
+            let txs_json = run_transactions(&mut hash.0, &txns)?;
+            send_cas(
+                &next_msg_id,
+                node_id.clone(),
+                from,
+                hash,
+                sender_hash,
+                tx,
+                rx,
+            )?;
+            Ok(txs_json)
+        });
+    match result {
+        Ok(txs_json) => reply_to_transaction(node_id, next_msg_id, dest, incoming_id, txs_json),
+        Err(error) => reply_error(node_id, next_msg_id, dest, incoming_id, error),
+    }
+}
+
+fn reply_error(
+    node_id: Arc<RwLock<String>>,
+    next_msg_id: Arc<Mutex<i64>>,
+    dest: String,
+    incoming_id: i64,
+    error: TxnError,
+) {
+    let src = node_id.read().unwrap();
+    let mut msg_id = next_msg_id.lock().unwrap();
+    *msg_id += 1;
+    let wait_key = *msg_id;
+    drop(msg_id);
+    let reply = Reply {
+        dest: &dest,
+        src: &src,
+        body: ResponseBody::Error {
+            msg_id: wait_key,
+            r#type: "error",
+            in_reply_to: incoming_id,
+            text: &error.text,
+            code: error.code,
+        },
+    };
+    transport::send(&reply);
+}
+
+fn reply_to_transaction(
+    node_id: Arc<RwLock<String>>,
+    next_msg_id: Arc<Mutex<i64>>,
+    dest: String,
+    incoming_id: i64,
+    txs_json: Vec<TxnType>,
+) {
+    let src = node_id.read().unwrap();
+    let mut msg_id = next_msg_id.lock().unwrap();
+    *msg_id += 1;
+    let wait_key = *msg_id;
+    drop(msg_id);
+    let reply = Reply {
+        dest: &dest,
+        src: &src,
+        body: ResponseBody::Txn {
+            msg_id: wait_key,
+            r#type: "txn_ok",
+            in_reply_to: incoming_id,
+            txn: txs_json,
+        },
+    };
+    transport::send(&reply);
+}
+
+// Waits for lin-kv's reply to `wait_key`. An error reply fails with its own
+// code, and no reply at all with `lost`: definite for a request that changes
+// nothing, indefinite for one that may have taken effect unseen.
+fn await_reply(
+    sender_hash: &MessageHash,
+    wait_key: i64,
+    rx: &Receiver<Value>,
+    lost: i64,
+) -> Result<Value, TxnError> {
+    let message = rx.recv_timeout(config::rpc_timeout(Duration::from_secs(5)));
+    sender_hash.lock().unwrap().remove(&wait_key);
+    match message {
+        Ok(body) if body["type"] == "error" => Err(TxnError::new(
+            body["code"].as_i64().unwrap_or(CRASH),
+            body["text"].as_str().unwrap_or_default().to_string(),
+        )),
+        Ok(body) => Ok(body),
+        Err(_) => Err(TxnError::new(lost, format!("Timed out waiting for {}", KV))),
+    }
+}
+
+fn send_cas(
+    next_msg_id: &Arc<Mutex<i64>>,
+    node_id: Arc<RwLock<String>>,
+    from: Store,
+    hash: Store,
+    sender_hash: Arc<Mutex<HashMap<i64, Sender<Value>>>>,
+    tx: Sender<Value>,
+    rx: Receiver<Value>,
+) -> Result<(), TxnError> {
+    let mut msg_id = next_msg_id.lock().unwrap();
+    *msg_id += 1;
+    let wait_key = *msg_id;
+    drop(msg_id);
+    let src = node_id.read().unwrap();
+    let reply = Reply {
+        dest: KV,
+        src: &src,
+        body: ResponseBody::Cas {
+            r#type: "cas",
+            key: ROOT,
+            from,
+            to: hash,
+            msg_id: wait_key,
+            create_if_not_exists: false,
+        },
+    };
+    let mut lookup = sender_hash.lock().unwrap();
+    lookup.insert(wait_key, tx);
+    drop(lookup);
+    transport::send(&reply);
+    // The commit may have landed even though its reply was lost.
+    match await_reply(&sender_hash, wait_key, &rx, TIMEOUT) {
+        // Another transaction changed the root since we read it.
+        Err(error) if error.code == PRECONDITION_FAILED => {
+            Err(TxnError::new(CAS_CONFLICT, "Cas Conflict".to_string()))
+        }
+        result => result.map(|_| ()),
+    }
+}
+
+fn send_read(
+    node_id: Arc<RwLock<String>>,
+    next_msg_id: Arc<Mutex<i64>>,
+    sender_hash: &Arc<Mutex<HashMap<i64, Sender<Value>>>>,
+    tx: &Sender<Value>,
+    rx: &Receiver<Value>,
+) -> Result<Value, TxnError> {
+    let src = node_id.read().unwrap();
+    let mut msg_id = next_msg_id.lock().unwrap();
+    *msg_id += 1;
+    let wait_key = *msg_id;
+    drop(msg_id);
+    let reply = Reply {
+        dest: KV,
+        src: &src,
+        body: ResponseBody::Read {
+            msg_id: wait_key,
+            r#type: "read",
+            key: ROOT,
+        },
+    };
+    let mut lookup = sender_hash.lock().unwrap();
+    lookup.insert(wait_key, tx.clone());
+    drop(lookup);
+    transport::send(&reply);
+    await_reply(sender_hash, wait_key, rx, TEMPORARILY_UNAVAILABLE)
+        .map(|body| body["value"].to_owned())
+}
+
+// Reads from lin-kv every heartbeat interval, so the failure detector keeps
+// hearing from it while no transactions are running.
+async fn probe(src: String, next_msg_id: MessageCounter, probe: MessageCounter) {
+    loop {
+        let msg_id = next_id(&next_msg_id);
+        *probe.lock().unwrap() = msg_id;
+        let message = Reply {
+            dest: KV,
+            src: &src,
+            body: ResponseBody::Read {
+                r#type: "read",
+                msg_id,
+                key: PROBE,
+            },
+        };
+        transport::send(&message);
+        sleep(failure_detector::interval()).await;
+    }
+}
+
+pub fn next_id(next_msg_id: &MessageCounter) -> i64 {
+    let mut msg_id = next_msg_id.lock().unwrap();
+    *msg_id += 1;
+    *msg_id
+}
+
+// Sends a message whose body is built by the two-phase commit or sequencer
+// modules.
+pub fn send(src: &str, dest: &str, body: Value) {
+    let message = Reply {
+        dest,
+        src,
+        body: ResponseBody::Request(body),
+    };
+    transport::send(&message);
+}
+
+impl Node {
+    fn new(id: String, node_ids: Vec<String>) -> Node {
+        let next_msg_id = Arc::new(Mutex::new(0));
+        let senders = Arc::new(Mutex::new(HashMap::new()));
+        // With the lin-kv backend, lin-kv is watched like a peer.
+        let mut peers = node_ids.clone();
+        if config::backend() == KV {
+            peers.push(KV.to_string());
+        }
+        let detector = Arc::new(Mutex::new(FailureDetector::new(&id, &peers)));
+        Node {
+            id: Arc::new(RwLock::new(id.clone())),
+            next_msg_id: next_msg_id.clone(),
+            senders: senders.clone(),
+            cluster: two_phase_commit::Context {
+                id: id.clone(),
+                node_ids: node_ids.clone(),
+                next_msg_id: next_msg_id.clone(),
+                senders,
+                detector: detector.clone(),
+                partition: Arc::new(Mutex::new(two_phase_commit::Partition::default())),
+            },
+            sequencer: sequencer::Context {
+                id,
+                node_ids,
+                next_msg_id,
+                sequencer: Arc::new(Mutex::new(sequencer::Sequencer::default())),
+            },
+            detector,
+            probe: Arc::new(Mutex::new(0)),
+        }
+    }
+}
+
+#[derive(Serialize)]
+enum ResponseBody<'a> {
+    #[serde(rename = "body")]
+    Init {
+        msg_id: i64,
+        r#type: &'a str,
+        in_reply_to: i64,
+    },
+    #[serde(rename = "body")]
+    Txn {
+        r#type: &'a str,
+        in_reply_to: i64,
+        msg_id: i64,
+        txn: Vec<TxnType>,
+    },
+    #[serde(rename = "body")]
+    Read {
+        r#type: &'a str,
+        msg_id: i64,
+        key: &'a str,
+    },
+    #[serde(rename = "body")]
+    Cas {
+        r#type: &'a str,
+        key: &'a str,
+        from: Store,
+        to: Store,
+        msg_id: i64,
+        create_if_not_exists: bool,
+    },
+    #[serde(rename = "body")]
+    Error {
+        r#type: &'a str,
+        in_reply_to: i64,
+        code: i64,
+        text: &'a str,
+        msg_id: i64,
+    },
+    #[serde(rename = "body")]
+    Request(Value),
+}
+
+#[derive(Serialize)]
+struct Reply<'a> {
+    src: &'a str,
+    dest: &'a str,
+    #[serde(flatten)]
+    body: ResponseBody<'a>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Store(Values);
+
+pub async fn run() {
+    // `--backend 2pc` partitions keys across the nodes and commits with
+    // two-phase commit instead of compare-and-set on one lin-kv key.
+    let partitioned = config::backend() == "2pc";
+    // `--backend calvin` orders transactions into replicated epochs and runs
+    // them deterministically on every node instead.
+    let sequenced = config::backend() == "calvin";
+    let mut node: Option<Node> = None;
+    while let Some(parsed) = transport::receive().await {
+        let _span = transport::request_span(&parsed).entered();
+        let body = &parsed["body"];
+        if let Some(s) = node.as_ref() {
+            let src = parsed["src"].as_str().unwrap();
+            let mut detector = s.detector.lock().unwrap();
+            match body["type"] == "heartbeat" || body["in_reply_to"] == *s.probe.lock().unwrap() {
+                true => detector.heartbeat(src),
+                _ => detector.heard(src),
+            }
+        }
+        match body["type"].as_str().unwrap() {
+            "init" => {
+                node = Some(Node::new(
+                    body["node_id"].as_str().unwrap().to_string(),
+                    serde_json::from_value(body["node_ids"].clone()).unwrap(),
+                ));
+
+                info!("Initialized node {:?}", node.as_ref().map(|s| &s.id));
+                if let Some(s) = node.as_mut() {
+                    let mut msg_id = s.next_msg_id.lock().unwrap();
+                    *msg_id += 1;
+                    let reply = Reply {
+                        dest: parsed["src"].as_str().unwrap(),
+                        src: &s.id.read().unwrap(),
+                        body: ResponseBody::Init {
+                            msg_id: *msg_id,
+                            r#type: "init_ok",
+                            in_reply_to: body["msg_id"].as_i64().unwrap(),
+                        },
+                    };
+                    transport::send(&reply);
+                    if partitioned {
+                        tokio::spawn(two_phase_commit::recover(s.cluster.clone()));
+                        tokio::spawn(failure_detector::heartbeat(
+                            s.cluster.id.clone(),
+                            s.cluster.node_ids.clone(),
+                        ));
+                        continue;
+                    }
+                    if sequenced {
+                        tokio::spawn(sequencer::sequence(s.sequencer.clone()));
+                        continue;
+                    }
+                    *msg_id += 1;
+                    let reply = Reply {
+                        dest: KV,
+                        src: &s.id.read().unwrap(),
+                        body: ResponseBody::Cas {
+                            msg_id: *msg_id,
+                            r#type: "cas",
+                            key: ROOT,
+                            from: Store(BTreeMap::new()),
+                            to: Store(BTreeMap::new()),
+                            create_if_not_exists: true,
+                        },
+                    };
+                    transport::send(&reply);
+                    tokio::spawn(probe(
+                        s.cluster.id.clone(),
+                        s.next_msg_id.clone(),
+                        s.probe.clone(),
+                    ));
+                }
+            }
+            "txn" if sequenced => {
+                if let Some(s) = node.as_ref() {
+                    sequencer::submit(
+                        &s.sequencer,
+                        parsed["src"].as_str().unwrap().to_string(),
+                        body["msg_id"].as_i64().unwrap(),
+                        body["txn"].to_owned(),
+                    );
+                }
+            }
+            "batch" => {
+                if let Some(s) = node.as_ref() {
+                    sequencer::handle(&s.sequencer, parsed["src"].as_str().unwrap(), body);
+                }
+            }
+            "batch_ok" => {
+                if let Some(s) = node.as_ref() {
+                    sequencer::acknowledged(&s.sequencer, body);
+                }
+            }
+            "txn" if partitioned => {
+                if let Some(s) = node.as_ref() {
+                    transport::spawn(
+                        two_phase_commit::transact(
+                            s.cluster.clone(),
+                            body["txn"].to_owned(),
+                            body["msg_id"].as_i64().unwrap(),
+                            parsed["src"].as_str().unwrap().to_string(),
+                        )
+                        .in_current_span(),
+                    );
+                }
+            }
+            "prepare" | "commit" | "abort" | "status" => {
+                if let Some(s) = node.as_ref() {
+                    two_phase_commit::handle(&s.cluster, parsed["src"].as_str().unwrap(), body);
+                }
+            }
+            "commit_ok" | "abort_ok" => {
+                if let Some(s) = node.as_ref() {
+                    two_phase_commit::acknowledged(&s.cluster, body);
+                }
+            }
+            "status_ok" => {
+                if let Some(s) = node.as_ref() {
+                    two_phase_commit::recovered(&s.cluster, body);
+                }
+            }
+            "txn" => {
+                if let Some(s) = node.as_mut() {
+                    transport::spawn(
+                        transact(
+                            s.next_msg_id.clone(),
+                            body["txn"].to_owned(),
+                            s.senders.clone(),
+                            s.detector.clone(),
+                            s.id.clone(),
+                            body["msg_id"].as_i64().unwrap(),
+                            parsed["src"].as_str().unwrap().to_string(),
+                        )
+                        .in_current_span(),
+                    );
+                }
+            }
+            // Replies to our own requests, handed whole to whoever waits on them.
+            "cas_ok" | "prepare_ok" | "read_ok" | "error" => {
+                let msg_id = body["in_reply_to"].as_i64().unwrap();
+                if let Some(s) = node.as_ref() {
+                    let lookup = s.senders.lock().unwrap();
+                    if lookup.contains_key(&msg_id) {
+                        lookup[&msg_id].send(body.to_owned()).unwrap();
+                    }
+                }
+            }
+            _ => continue,
+        }
+    }
+    transport::shutdown().await;
+}
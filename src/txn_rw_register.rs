@@ -0,0 +1,253 @@
+use crate::clock::{self, Lamport};
+use crate::micro_ops::{changes, keys, run_transactions, TxnError, TxnType, Values};
+use crate::mvcc::Mvcc;
+use crate::{config, transport};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use tokio::time::{sleep, Duration};
+use tracing::info;
+
+struct Node {
+    id: String,
+    neighbours: Vec<String>,
+    next_msg_id: i64,
+    store: ThreadStore,
+    ack_messages: ThreadSet,
+}
+
+type ThreadStore = Arc<Mutex<Store>>;
+type ThreadSet = Arc<Mutex<HashSet<i64>>>;
+// Lamport timestamp of the committing transaction plus its node, used as the
+// version of every key it wrote.
+type Version = (i64, String);
+
+// Every transaction runs against this node alone, so the workload stays
+// available during partitions. Writes are buffered and installed in one step
+// at commit, so no transaction sees another's uncommitted or intermediate
+// state (read committed, and therefore read uncommitted). Committed writes are
+// then shipped to peers and applied last-writer-wins by version: every node
+// orders any two transactions the same way on every key, so there are no dirty
+// write cycles either. Versions live in an MVCC store, so a transaction reads
+// one snapshot even if replicated writes land while it runs.
+#[derive(Default)]
+struct Store {
+    values: Mvcc<Version>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct Writes {
+    version: Version,
+    writes: Values,
+}
+
+impl Store {
+    fn transact(&mut self, id: &str, txn: &Value) -> Result<(Vec<TxnType>, Writes), TxnError> {
+        // Sorts after every version this node has seen so far.
+        let start = (clock::with(|clocks| clocks.lamport.0) + 1, String::new());
+        self.values.begin(&start);
+        let before = self.values.snapshot(keys(txn), &start);
+        let mut values = before.clone();
+        let result = run_transactions(&mut values, txn).and_then(|txs_json| {
+            let version = (clock::with(|clocks| clocks.lamport.tick()), id.to_string());
+            let writes = changes(&before, &values);
+            self.values
+                .commit(&start, version.clone(), writes.clone())
+                .map(|_| (txs_json, Writes { version, writes }))
+        });
+        self.values.finish(&start);
+        result
+    }
+
+    fn apply(&mut self, replicated: Writes) {
+        clock::with(|clocks| clocks.lamport.observe(Lamport(replicated.version.0)));
+        self.values.install(replicated.version, replicated.writes);
+    }
+}
+
+async fn replicate(dest: String, src: String, writes: Writes, msg_id: i64, seen: ThreadSet) {
+    loop {
+        {
+            let set = seen.lock().unwrap();
+            if !set.contains(&msg_id) {
+                break;
+            }
+            let message = Reply {
+                dest: &dest,
+                src: &src,
+                body: ResponseBody::Replicate {
+                    r#type: "replicate",
+                    msg: &writes,
+                    msg_id,
+                },
+            };
+            transport::send(&message);
+        }
+        sleep(config::gossip_interval(Duration::from_millis(2000))).await;
+    }
+}
+
+impl Node {
+    fn new(id: String, neighbours: Vec<String>) -> Node {
+        Node {
+            id,
+            neighbours,
+            next_msg_id: 0,
+            store: Arc::new(Mutex::new(Store::default())),
+            ack_messages: Arc::new(Mutex::new(HashSet::new())),
+        }
+    }
+    fn replicate_neighbours(&mut self, writes: Writes) {
+        for n in &self.neighbours {
+            if *n != self.id {
+                self.next_msg_id += 1;
+                let mut db = self.ack_messages.lock().unwrap();
+                db.insert(self.next_msg_id);
+                tokio::spawn(replicate(
+                    n.clone(),
+                    self.id.clone(),
+                    writes.clone(),
+                    self.next_msg_id,
+                    self.ack_messages.clone(),
+                ));
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+enum ResponseBody<'a> {
+    #[serde(rename = "body")]
+    Init {
+        msg_id: i64,
+        r#type: &'a str,
+        in_reply_to: i64,
+    },
+    #[serde(rename = "body")]
+    Txn {
+        r#type: &'a str,
+        in_reply_to: i64,
+        msg_id: i64,
+        txn: &'a [TxnType],
+    },
+    #[serde(rename = "body")]
+    Error {
+        r#type: &'a str,
+        in_reply_to: i64,
+        msg_id: i64,
+        code: i64,
+        text: &'a str,
+    },
+    #[serde(rename = "body")]
+    Replicate {
+        r#type: &'a str,
+        msg: &'a Writes,
+        msg_id: i64,
+    },
+    #[serde(rename = "body")]
+    ReplicateOk {
+        r#type: &'a str,
+        in_reply_to: i64,
+        msg_id: i64,
+    },
+}
+
+#[derive(Serialize)]
+struct Reply<'a> {
+    src: &'a str,
+    dest: &'a str,
+    #[serde(flatten)]
+    body: ResponseBody<'a>,
+}
+
+pub async fn run() {
+    let mut node: Option<Node> = None;
+    while let Some(parsed) = transport::receive().await {
+        let _span = transport::request_span(&parsed).entered();
+        let body = &parsed["body"];
+        match body["type"].as_str().unwrap() {
+            "init" => {
+                node = Some(Node::new(
+                    body["node_id"].as_str().unwrap().to_string(),
+                    serde_json::from_value(body["node_ids"].clone()).unwrap(),
+                ));
+
+                info!("Initialized node {:?}", node.as_ref().map(|s| &s.id));
+                if let Some(s) = node.as_mut() {
+                    s.next_msg_id += 1;
+                }
+                let reply = Reply {
+                    dest: parsed["src"].as_str().unwrap(),
+                    src: node.as_ref().map(|s| &s.id).unwrap(),
+                    body: ResponseBody::Init {
+                        msg_id: node.as_ref().map(|s| s.next_msg_id).unwrap(),
+                        r#type: "init_ok",
+                        in_reply_to: body["msg_id"].as_i64().unwrap(),
+                    },
+                };
+                transport::send(&reply);
+            }
+            "txn" => {
+                if let Some(s) = node.as_mut() {
+                    let result = s.store.lock().unwrap().transact(&s.id, &body["txn"]);
+                    s.next_msg_id += 1;
+                    let reply = match &result {
+                        Ok((txs_json, _)) => Reply {
+                            dest: parsed["src"].as_str().unwrap(),
+                            src: &s.id,
+                            body: ResponseBody::Txn {
+                                msg_id: s.next_msg_id,
+                                r#type: "txn_ok",
+                                in_reply_to: body["msg_id"].as_i64().unwrap(),
+                                txn: txs_json,
+                            },
+                        },
+                        Err(error) => Reply {
+                            dest: parsed["src"].as_str().unwrap(),
+                            src: &s.id,
+                            body: ResponseBody::Error {
+                                msg_id: s.next_msg_id,
+                                r#type: "error",
+                                in_reply_to: body["msg_id"].as_i64().unwrap(),
+                                text: &error.text,
+                                code: error.code,
+                            },
+                        },
+                    };
+                    transport::send(&reply);
+                    if let Ok((_, committed)) = result {
+                        if !committed.writes.is_empty() {
+                            s.replicate_neighbours(committed);
+                        }
+                    }
+                }
+            }
+            "replicate" => {
+                if let Some(s) = node.as_mut() {
+                    let r: Writes = serde_json::from_value(body["msg"].clone()).unwrap();
+                    s.store.lock().unwrap().apply(r);
+                    s.next_msg_id += 1;
+                    let reply = Reply {
+                        dest: parsed["src"].as_str().unwrap(),
+                        src: &s.id,
+                        body: ResponseBody::ReplicateOk {
+                            msg_id: s.next_msg_id,
+                            r#type: "replicate_ok",
+                            in_reply_to: body["msg_id"].as_i64().unwrap(),
+                        },
+                    };
+                    transport::send(&reply);
+                }
+            }
+            "replicate_ok" => {
+                if let Some(s) = node.as_mut() {
+                    let mut set = s.ack_messages.lock().unwrap();
+                    set.remove(&body["in_reply_to"].as_i64().unwrap());
+                }
+            }
+            _ => continue,
+        }
+    }
+    transport::shutdown().await;
+}
@@ -0,0 +1,212 @@
+use crate::{config, transport};
+use serde::Serialize;
+use serde_json::Value;
+use std::cmp::max;
+use std::collections::hash_map::DefaultHasher;
+use std::env;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::PathBuf;
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{error, info, warn};
+
+struct Node {
+    id: String,
+    next_msg_id: i64,
+    generator: Generator,
+}
+
+// 2020-01-01T00:00:00Z, so 41 bits of milliseconds last until 2089.
+const EPOCH: i64 = 1_577_836_800_000;
+const NODE_BITS: i64 = 10;
+const SEQUENCE_BITS: i64 = 12;
+const MAX_SEQUENCE: i64 = (1 << SEQUENCE_BITS) - 1;
+// How far ahead of the clock we persist our high-water mark. A restarted node
+// resumes after the lease, so it never reissues a timestamp it may have used.
+const LEASE_MS: i64 = 1000;
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as i64
+}
+
+// Snowflake-style generator: timestamp | node index | per-millisecond sequence.
+// The timestamp never moves backwards: if the wall clock regresses we keep
+// issuing from the last timestamp, borrowing the next millisecond when the
+// sequence runs out.
+struct Generator {
+    node: i64,
+    last: i64,
+    sequence: i64,
+    lease: i64,
+    state: PathBuf,
+    entropy: u64,
+}
+
+impl Generator {
+    // Fails if the node's index does not fit in NODE_BITS, as it would then
+    // share its ids with another node. The lease is kept in `--lease-dir`, by
+    // default the temp dir; clusters sharing a host should each get their own.
+    fn new(id: &str, node_ids: &[String]) -> Result<Generator, String> {
+        let node = match id.trim_start_matches('n').parse::<i64>() {
+            Ok(n) => n,
+            Err(_) => node_ids.iter().position(|n| n == id).unwrap_or(0) as i64,
+        };
+        if !(0..1 << NODE_BITS).contains(&node) {
+            return Err(format!(
+                "Node index {} of {} does not fit in {} bits",
+                node, id, NODE_BITS
+            ));
+        }
+        let dir = config::lease_dir().map_or_else(env::temp_dir, PathBuf::from);
+        let state = dir.join(format!("unique-ids-{}", id));
+        let lease = fs::read_to_string(&state)
+            .ok()
+            .and_then(|s| s.trim().parse::<i64>().ok())
+            .unwrap_or(0);
+        let mut hasher = DefaultHasher::new();
+        (id, process::id(), SystemTime::now()).hash(&mut hasher);
+        Ok(Generator {
+            node,
+            last: lease,
+            sequence: 0,
+            lease,
+            state,
+            entropy: hasher.finish(),
+        })
+    }
+
+    // Returns the next (millisecond, sequence) pair, strictly increasing.
+    fn tick(&mut self) -> (i64, i64) {
+        let wall = now();
+        if wall > self.last {
+            self.last = wall;
+            self.sequence = 0;
+        } else if self.sequence < MAX_SEQUENCE {
+            self.sequence += 1;
+        } else {
+            self.last += 1;
+            self.sequence = 0;
+        }
+        if self.last >= self.lease {
+            self.lease = max(self.last, wall) + LEASE_MS;
+            // Ids stay unique while we run without it; only a restart onto a
+            // clock that went backwards could then reissue one.
+            if let Err(e) = fs::write(&self.state, self.lease.to_string()) {
+                warn!("Could not persist the lease to {:?}: {}", self.state, e);
+            }
+        }
+        (self.last, self.sequence)
+    }
+
+    fn snowflake(&mut self) -> i64 {
+        let (ms, sequence) = self.tick();
+        ((ms - EPOCH) << (NODE_BITS + SEQUENCE_BITS)) | (self.node << SEQUENCE_BITS) | sequence
+    }
+
+    // RFC 9562 UUIDv7: 48-bit unix millis, then the sequence in rand_a and the
+    // node index plus per-process entropy in rand_b.
+    fn uuid(&mut self) -> String {
+        let (ms, sequence) = self.tick();
+        let hi = ((ms as u64) << 16) | (0x7 << 12) | sequence as u64;
+        let lo = (0b10 << 62) | ((self.node as u64) << 52) | (self.entropy >> 12);
+        format!(
+            "{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+            hi >> 32,
+            (hi >> 16) & 0xffff,
+            hi & 0xffff,
+            lo >> 48,
+            lo & 0xffff_ffff_ffff
+        )
+    }
+}
+
+#[derive(Serialize)]
+enum ResponseBody<'a> {
+    #[serde(rename = "body")]
+    Init {
+        msg_id: i64,
+        r#type: &'a str,
+        in_reply_to: i64,
+    },
+    #[serde(rename = "body")]
+    Generate {
+        msg_id: i64,
+        r#type: &'a str,
+        in_reply_to: i64,
+        id: Value,
+    },
+}
+
+#[derive(Serialize)]
+struct Reply<'a> {
+    src: &'a str,
+    dest: &'a str,
+    #[serde(flatten)]
+    body: ResponseBody<'a>,
+}
+
+pub async fn run() {
+    // `--format uuid` hands out UUIDv7 strings instead of 64-bit integers.
+    let uuid = config::format() == "uuid";
+    let mut node: Option<Node> = None;
+    while let Some(parsed) = transport::receive().await {
+        let _span = transport::request_span(&parsed).entered();
+        let body = &parsed["body"];
+        match body["type"].as_str().unwrap() {
+            "init" => {
+                let id = body["node_id"].as_str().unwrap().to_string();
+                let node_ids: Vec<String> =
+                    serde_json::from_value(body["node_ids"].clone()).unwrap();
+                let generator = Generator::new(&id, &node_ids).unwrap_or_else(|e| {
+                    error!("{}", e);
+                    process::exit(1);
+                });
+                node = Some(Node {
+                    generator,
+                    id,
+                    next_msg_id: 0,
+                });
+                info!("Initialized node {:?}", node.as_ref().map(|s| &s.id));
+                if let Some(s) = node.as_mut() {
+                    s.next_msg_id += 1;
+                }
+                let reply = Reply {
+                    dest: parsed["src"].as_str().unwrap(),
+                    src: node.as_ref().map(|s| &s.id).unwrap(),
+                    body: ResponseBody::Init {
+                        msg_id: node.as_ref().map(|s| s.next_msg_id).unwrap(),
+                        r#type: "init_ok",
+                        in_reply_to: body["msg_id"].as_i64().unwrap(),
+                    },
+                };
+                transport::send(&reply);
+            }
+            "generate" => {
+                if let Some(s) = node.as_mut() {
+                    let id = match uuid {
+                        true => Value::from(s.generator.uuid()),
+                        _ => Value::from(s.generator.snowflake()),
+                    };
+                    s.next_msg_id += 1;
+                    let reply = Reply {
+                        dest: parsed["src"].as_str().unwrap(),
+                        src: &s.id,
+                        body: ResponseBody::Generate {
+                            msg_id: s.next_msg_id,
+                            r#type: "generate_ok",
+                            in_reply_to: body["msg_id"].as_i64().unwrap(),
+                            id,
+                        },
+                    };
+                    transport::send(&reply);
+                }
+            }
+            _ => continue,
+        }
+    }
+    transport::shutdown().await;
+}